@@ -0,0 +1,184 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::LazyLock;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::net::lookup_host;
+
+use crate::config::load_config;
+use crate::prelude::*;
+
+/// `deny_private` 拒绝的地址段: 私有, 回环, 链路本地, 运营商 NAT, 基准测试, 组播, 保留和未指定地址.
+/// NAT64 和 IPv4 兼容地址内嵌的可能是任意 IPv4 地址, 整段拒绝
+static PRIVATE_RANGES: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/96",
+        "64:ff9b::/96",
+        "64:ff9b:1::/48",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|net| net.parse().expect("valid CIDR"))
+    .collect()
+});
+
+/// 访问控制配置文件: YAML 或 TOML
+///
+/// ```yaml
+/// clients:
+///   allow: [10.0.0.0/8, 127.0.0.1/32]
+///   deny: [10.0.5.0/24]
+/// destinations:
+///   deny_private: true
+///   allow_hosts: ["*.example.com"]
+///   deny_hosts: ["*.internal"]
+///   allow_ports: [80, 443]
+/// ```
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct AclConfig {
+    #[serde(default)]
+    pub clients: ClientAcl,
+    #[serde(default)]
+    pub destinations: DestinationAcl,
+}
+
+/// 客户端地址: 先看 `deny`, `allow` 为空时允许其余所有地址
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct ClientAcl {
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+/// 目标地址: 主机通配符和端口, 为空的列表不限制
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct DestinationAcl {
+    /// 拒绝解析到内网地址的目标, 防止通过代理访问内部网络 (SSRF)
+    #[serde(default)]
+    pub deny_private: bool,
+    #[serde(default)]
+    pub allow_hosts: Vec<String>,
+    #[serde(default)]
+    pub deny_hosts: Vec<String>,
+    #[serde(default)]
+    pub allow_ports: Vec<u16>,
+}
+
+/// 访问控制, 拒绝时返回原因, 代理以 403 回复
+#[derive(Debug,Clone,Default)]
+pub struct Acl {
+    config: AclConfig,
+}
+
+impl Acl {
+    pub fn new(config: AclConfig) -> Self {
+        Acl { config }
+    }
+
+    /// 按扩展名读取 `.yaml`/`.yml` 或 `.toml` 配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        Ok(Acl::new(load_config(path, "ACL")?))
+    }
+
+    pub fn config(&self) -> &AclConfig {
+        &self.config
+    }
+
+    pub fn check_client(&self, ip: IpAddr) -> Result<(), String> {
+        let ip = canonical(ip);
+        let clients = &self.config.clients;
+        if clients.deny.iter().any(|net| net.contains(&ip)) {
+            return Err(format!("client {} is denied", ip));
+        }
+        if !clients.allow.is_empty() && !clients.allow.iter().any(|net| net.contains(&ip)) {
+            return Err(format!("client {} is not allowed", ip));
+        }
+        Ok(())
+    }
+
+    /// `deny_private` 时解析主机名检查所有地址, 返回检查过的地址. 上游只能连接这些地址,
+    /// 不再重新解析, 防止 DNS rebinding. 不检查地址时返回 None
+    pub async fn check_destination(&self, host: &str, port: u16) -> Result<Option<Vec<SocketAddr>>, String> {
+        let dest = &self.config.destinations;
+        if dest.deny_hosts.iter().any(|p| wildcard_match(p, host)) {
+            return Err(format!("destination {} is denied", host));
+        }
+        if !dest.allow_hosts.is_empty() && !dest.allow_hosts.iter().any(|p| wildcard_match(p, host)) {
+            return Err(format!("destination {} is not allowed", host));
+        }
+        if !dest.allow_ports.is_empty() && !dest.allow_ports.contains(&port) {
+            return Err(format!("port {} is not allowed", port));
+        }
+        if !dest.deny_private {
+            return Ok(None);
+        }
+        let addrs: Vec<SocketAddr> = match host.trim_matches(['[', ']']).parse() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => lookup_host((host, port)).await.map(|addrs| addrs.collect()).unwrap_or_default(),
+        };
+        if addrs.is_empty() {
+            return Err(format!("destination {} could not be resolved", host));
+        }
+        if let Some(ip) = addrs.iter().map(|a| canonical(a.ip())).find(|ip| PRIVATE_RANGES.iter().any(|net| net.contains(ip))) {
+            return Err(format!("destination {} resolves to private address {}", host, ip));
+        }
+        Ok(Some(addrs))
+    }
+}
+
+/// IPv4 映射的 IPv6 地址 (`::ffff:a.b.c.d`) 按 IPv4 处理
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// 拒绝时回复给客户端的响应
+pub fn forbidden(reason: &str) -> Response {
+    Response::new(403).with_header("Content-Type", "text/plain; charset=utf-8").with_body(format!("{}\n", reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn clients_and_destinations() {
+        let config: AclConfig = serde_yaml::from_str(
+            "clients:\n  allow: [10.0.0.0/8, 127.0.0.1/32]\n  deny: [10.0.5.0/24]\n\
+             destinations:\n  deny_private: true\n  deny_hosts: ['*.internal']\n  allow_ports: [80, 443]\n",
+        )
+        .unwrap();
+        let acl = Acl::new(config);
+        assert!(acl.check_client("10.1.2.3".parse().unwrap()).is_ok());
+        assert!(acl.check_client("::ffff:127.0.0.1".parse().unwrap()).is_ok());
+        assert_eq!(acl.check_client("10.0.5.9".parse().unwrap()).unwrap_err(), "client 10.0.5.9 is denied");
+        assert!(acl.check_client("192.0.2.1".parse().unwrap()).is_err());
+
+        let pinned: SocketAddr = "93.184.216.34:443".parse().unwrap();
+        assert_eq!(acl.check_destination("93.184.216.34", 443).await, Ok(Some(vec![pinned])));
+        assert!(acl.check_destination("no-such-host.invalid", 443).await.is_err());
+        assert!(acl.check_destination("db.internal", 443).await.is_err());
+        assert_eq!(acl.check_destination("93.184.216.34", 22).await.unwrap_err(), "port 22 is not allowed");
+        for private in ["127.0.0.1", "169.254.169.254", "[::1]", "[::ffff:10.0.0.1]", "[64:ff9b::a00:1]", "198.18.0.1", "239.1.1.1", "[ff02::1]", "localhost"] {
+            assert!(acl.check_destination(private, 80).await.is_err(), "{}", private);
+        }
+        assert_eq!(Acl::default().check_destination("localhost", 22).await, Ok(None));
+    }
+}
@@ -0,0 +1,272 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use crate::breakpoint::Breakpoints;
+use crate::capture::FlowLog;
+use crate::filter::Filter;
+use crate::metrics::metrics;
+use crate::prelude::*;
+use crate::rules::{RuleConfig, RuleEngine};
+use crate::store::Store;
+use crate::web::{self, bearer_authorized, error_response, json_response, percent_decode, query_param, Reply};
+
+/// `generate_ca_certificate` 保存 CA 证书的位置
+const CA_CERT_PATH: &str = "ca.crt";
+
+/// 运行中代理的管理接口, 在单独的端口上监听.
+/// 除 `GET /health` 外都需要 `Authorization: Bearer <token>`.
+///
+/// - `GET /health`, `GET /stats`, `GET /metrics` (Prometheus 文本格式)
+/// - `GET /flows?filter=&limit=`, `DELETE /flows`, `GET|DELETE /flows/{id}`
+/// - `GET /intercept`, `PUT /intercept` (`{"enabled": true}`), `POST /intercept/{id}/release|abort`
+/// - `GET /rules`, `POST /rules` (`RuleConfig` JSON), `DELETE /rules/{name}`
+/// - `POST /logs/rotate` 把会话数据库归档到带时间戳的文件并清空
+/// - `GET /ca.crt`
+#[derive(Clone)]
+pub struct AdminApi {
+    token: Arc<str>,
+    flows: FlowLog,
+    breakpoints: Option<Breakpoints>,
+    rules: Option<RuleEngine>,
+    store: Option<Store>,
+    started_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct InterceptState {
+    enabled: bool,
+}
+
+impl AdminApi {
+    pub fn new(token: impl Into<String>, flows: FlowLog) -> Self {
+        AdminApi {
+            token: token.into().into(),
+            flows,
+            breakpoints: None,
+            rules: None,
+            store: None,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn with_breakpoints(mut self, breakpoints: Breakpoints) -> Self {
+        self.breakpoints = Some(breakpoints);
+        self
+    }
+
+    pub fn with_rules(mut self, rules: RuleEngine) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+
+    pub fn with_store(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub async fn run(self, addr: &str) -> Result<(), anyhow::Error> {
+        let listener = tokio::net::TcpListener::bind(addr).await.with_context(|| format!("[-] Failed to bind admin API on {}", addr))?;
+        info!("[+] Admin API on http://{}/", addr);
+        web::serve(listener, move |req| {
+            let this = self.clone();
+            async move { Reply::Full(this.handle(req).await) }
+        })
+        .await
+    }
+
+    pub async fn handle(&self, req: Request) -> Response {
+        let segments: Vec<String> = req.uri.path.trim_matches('/').split('/').map(percent_decode).collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        if let (Method::GET, ["health"]) = (&req.method, segments.as_slice()) {
+            return json_response(200, &json!({"status": "ok", "uptime_secs": self.started_at.elapsed().as_secs()}));
+        }
+        if !bearer_authorized(&req, &self.token) {
+            return error_response(401, "missing or invalid token").with_header("WWW-Authenticate", "Bearer");
+        }
+        match self.route(&req, &segments).await {
+            Ok(resp) => resp,
+            Err(e) => error_response(500, format!("{:#}", e)),
+        }
+    }
+
+    async fn route(&self, req: &Request, segments: &[&str]) -> Result<Response, anyhow::Error> {
+        Ok(match (&req.method, segments) {
+            (Method::GET, ["stats"]) => json_response(200, &self.stats().await?),
+            (Method::GET, ["metrics"]) => Response::new(200)
+                .with_header("Content-Type", prometheus::TEXT_FORMAT)
+                .with_body(metrics().render()),
+            (Method::GET, ["flows"]) => {
+                let filter = match query_param(req, "filter").filter(|f| !f.trim().is_empty()).map(|f| f.parse::<Filter>()).transpose() {
+                    Ok(filter) => filter,
+                    Err(e) => return Ok(error_response(400, e)),
+                };
+                let limit = query_param(req, "limit").and_then(|l| l.parse().ok()).unwrap_or(usize::MAX);
+                let mut list: Vec<_> = self
+                    .flows
+                    .flows()
+                    .iter()
+                    .rev()
+                    .filter(|f| filter.as_ref().is_none_or(|flt| flt.matches(&f.request, Some(&f.response))))
+                    .take(limit)
+                    .map(|f| f.summary())
+                    .collect();
+                list.reverse();
+                json_response(200, &list)
+            }
+            (Method::DELETE, ["flows"]) => {
+                let removed = self.flows.len();
+                self.flows.clear();
+                json_response(200, &json!({"removed": removed}))
+            }
+            (Method::GET, ["flows", id]) => match id.parse().ok().and_then(|id| self.flows.get(id)) {
+                Some(flow) => json_response(200, flow.as_ref()),
+                None => error_response(404, "no such flow"),
+            },
+            (Method::DELETE, ["flows", id]) => match id.parse().map(|id| self.flows.remove(id)) {
+                Ok(true) => Response::new(204),
+                _ => error_response(404, "no such flow"),
+            },
+            (_, ["intercept", ..]) if self.breakpoints.is_none() => error_response(409, "interception is not enabled"),
+            (Method::GET, ["intercept"]) => {
+                let bp = self.breakpoints.as_ref().unwrap();
+                json_response(200, &json!({"enabled": bp.intercept_all(), "paused": bp.paused()}))
+            }
+            (Method::PUT, ["intercept"]) => {
+                let state: InterceptState = match serde_json::from_slice(&req.body) {
+                    Ok(state) => state,
+                    Err(e) => return Ok(error_response(400, e)),
+                };
+                self.breakpoints.as_ref().unwrap().set_intercept_all(state.enabled);
+                info!(enabled = state.enabled, "[+] Admin API: interception toggled");
+                json_response(200, &json!({"enabled": state.enabled}))
+            }
+            (Method::POST, ["intercept", id, action @ ("release" | "abort")]) => {
+                let bp = self.breakpoints.as_ref().unwrap();
+                let done = id.parse().is_ok_and(|id| if *action == "release" { bp.release(id) } else { bp.abort(id) });
+                if done { Response::new(204) } else { error_response(404, "no such paused flow") }
+            }
+            (_, ["rules", ..]) if self.rules.is_none() => error_response(409, "rule engine is not enabled"),
+            (Method::GET, ["rules"]) => {
+                let configs: Vec<_> = self.rules.as_ref().unwrap().rules().iter().map(|r| r.config.clone()).collect();
+                json_response(200, &configs)
+            }
+            (Method::POST, ["rules"]) => {
+                let config: RuleConfig = match serde_json::from_slice(&req.body) {
+                    Ok(config) => config,
+                    Err(e) => return Ok(error_response(400, e)),
+                };
+                match self.rules.as_ref().unwrap().add_rule(config.clone()) {
+                    Ok(()) => json_response(201, &config),
+                    Err(e) => error_response(400, format!("{:#}", e)),
+                }
+            }
+            (Method::DELETE, ["rules", name]) => match self.rules.as_ref().unwrap().remove_rule(name) {
+                0 => error_response(404, "no such rule"),
+                removed => json_response(200, &json!({"removed": removed})),
+            },
+            (Method::POST, ["logs", "rotate"]) => self.rotate().await?,
+            (Method::GET, ["ca.crt"]) => match tokio::fs::read(CA_CERT_PATH).await {
+                Ok(pem) => Response::new(200)
+                    .with_header("Content-Type", "application/x-pem-file")
+                    .with_header("Content-Disposition", "attachment; filename=\"ca.crt\"")
+                    .with_body(pem),
+                Err(_) => error_response(404, "CA certificate has not been generated"),
+            },
+            _ => error_response(404, "not found"),
+        })
+    }
+
+    async fn stats(&self) -> Result<serde_json::Value, anyhow::Error> {
+        let mut stats = json!({
+            "uptime_secs": self.started_at.elapsed().as_secs(),
+            "flows": self.flows.len(),
+        });
+        if let Some(bp) = &self.breakpoints {
+            stats["intercept"] = json!({"enabled": bp.intercept_all(), "paused": bp.paused().len()});
+        }
+        if let Some(rules) = &self.rules {
+            stats["rules"] = json!(rules.rules().len());
+        }
+        if let Some(store) = self.store.clone() {
+            let (count, size) = tokio::task::spawn_blocking(move || Ok::<_, anyhow::Error>((store.count()?, store.total_size()?))).await??;
+            stats["store"] = json!({"exchanges": count, "size": size});
+        }
+        Ok(stats)
+    }
+
+    /// 归档到数据库旁边的 `<名称>-<时间戳>.db`
+    async fn rotate(&self) -> Result<Response, anyhow::Error> {
+        let Some(path) = self.store.as_ref().and_then(|s| s.path()) else {
+            return Ok(error_response(409, "no on-disk session store"));
+        };
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let archive = path.with_file_name(format!("{}-{}.db", stem, secs));
+        let store = self.store.clone().unwrap();
+        let target = archive.clone();
+        let archived = tokio::task::spawn_blocking(move || store.rotate(target)).await??;
+        info!(archived, archive = %archive.display(), "[+] Admin API: session store rotated");
+        Ok(json_response(200, &json!({"archive": archive, "archived": archived})))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+    use crate::intercept::InterceptContext;
+    use crate::store::StoreOptions;
+
+    #[tokio::test]
+    async fn token_flows_rules_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("admin-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = Store::open(dir.join("sessions.db"), StoreOptions::default()).unwrap();
+        let flows = FlowLog::default();
+        let api = AdminApi::new("secret", flows.clone())
+            .with_breakpoints(Breakpoints::default())
+            .with_rules(RuleEngine::default())
+            .with_store(store.clone());
+
+        let ctx = InterceptContext::default();
+        let req = Request::from_string("GET http://a.test/x HTTP/1.1\r\n\r\n").unwrap();
+        let resp = Response::new(200).with_body("ok");
+        flows.record(&ctx, &req, &resp);
+        store.insert(&ctx, &req, &resp).unwrap();
+
+        let call = |method: &str, path: &str, token: &str, body: &str| {
+            let raw = format!("{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}", method, path, token, body.len(), body);
+            api.handle(Request::from_string(&raw).unwrap())
+        };
+        assert_eq!(call("GET", "/health", "", "").await.status, 200);
+        assert_eq!(call("GET", "/stats", "wrong", "").await.status, 401);
+
+        let stats = call("GET", "/stats", "secret", "").await.json().unwrap();
+        assert_eq!((stats["flows"].as_u64(), stats["store"]["exchanges"].as_u64()), (Some(1), Some(1)));
+        assert!(call("GET", "/metrics", "secret", "").await.text().contains("proxy_active_sessions"));
+        assert_eq!(call("GET", "/flows?filter=host+%3D%3D+a.test", "secret", "").await.json().unwrap()[0]["id"], 1);
+
+        assert_eq!(call("PUT", "/intercept", "secret", r#"{"enabled": true}"#).await.status, 200);
+        assert_eq!(call("GET", "/intercept", "secret", "").await.json().unwrap()["enabled"], true);
+
+        let rule = r#"{"name": "block ads", "match": {"host": "ads.*"}, "actions": [{"block": {}}]}"#;
+        assert_eq!(call("POST", "/rules", "secret", rule).await.status, 201);
+        assert_eq!(call("POST", "/rules", "secret", r#"{"match": {"path": "("}}"#).await.status, 400);
+        assert_eq!(call("GET", "/rules", "secret", "").await.json().unwrap()[0]["name"], "block ads");
+        assert_eq!(call("DELETE", "/rules/block%20ads", "secret", "").await.status, 200);
+
+        let rotated = call("POST", "/logs/rotate", "secret", "").await.json().unwrap();
+        assert_eq!(rotated["archived"], 1);
+        assert!(Path::new(rotated["archive"].as_str().unwrap()).exists());
+        assert_eq!(store.count().unwrap(), 0);
+
+        assert_eq!(call("DELETE", "/flows/1", "secret", "").await.status, 204);
+        assert_eq!(call("GET", "/flows/1", "secret", "").await.status, 404);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
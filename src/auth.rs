@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use tracing::warn;
+
+use crate::prelude::*;
+use crate::web::constant_eq;
+
+/// Digest 质询中的 nonce 有效期, 过期后要求客户端用新 nonce 重试 (stale=true)
+pub const NONCE_TTL: Duration = Duration::from_secs(300);
+
+/// 用户校验后端
+#[async_trait::async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Basic 认证: 用户名和密码是否正确
+    async fn verify(&self, user: &str, password: &str) -> bool;
+
+    /// Digest 认证需要的 HA1 = MD5(user:realm:password), 十六进制小写.
+    /// 只保存了单向哈希的用户返回 None, 只能使用 Basic
+    async fn digest_ha1(&self, user: &str, realm: &str) -> Option<String> {
+        let _ = (user, realm);
+        None
+    }
+}
+
+/// 代理认证: 校验 `Proxy-Authorization`, 失败时给出 Basic 和 Digest 两种质询
+#[derive(Clone)]
+pub struct ProxyAuth {
+    realm: String,
+    backend: Arc<dyn Authenticator>,
+    /// 给 nonce 签名, 进程重启后旧 nonce 失效
+    secret: [u8; 16],
+    /// 每个 nonce 用过的最大 `nc` 和首次使用的时间
+    nonce_counts: Arc<Mutex<HashMap<String, (u64, Instant)>>>,
+}
+
+impl std::fmt::Debug for ProxyAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAuth").field("realm", &self.realm).finish()
+    }
+}
+
+impl ProxyAuth {
+    pub fn new(realm: impl Into<String>, backend: impl Authenticator + 'static) -> Self {
+        ProxyAuth {
+            realm: realm.into(),
+            backend: Arc::new(backend),
+            secret: *uuid::Uuid::new_v4().as_bytes(),
+            nonce_counts: Arc::default(),
+        }
+    }
+
+    /// 通过时删除 `Proxy-Authorization` 并返回用户名, 否则返回应当回复给客户端的 407
+    pub async fn authenticate(&self, req: &mut Request) -> Result<String, Response> {
+        let Some(credentials) = req.header("proxy-authorization") else {
+            return Err(self.challenge(false));
+        };
+        let (scheme, rest) = credentials.split_once(' ').unwrap_or((credentials, ""));
+        let outcome = if scheme.eq_ignore_ascii_case("basic") {
+            self.basic(rest.trim()).await
+        } else if scheme.eq_ignore_ascii_case("digest") {
+            self.digest(req, rest).await
+        } else {
+            Outcome::Rejected(None)
+        };
+        match outcome {
+            Outcome::Accepted(user) => {
+                req.headers.remove("proxy-authorization");
+                Ok(user)
+            }
+            Outcome::Stale => Err(self.challenge(true)),
+            Outcome::Rejected(user) => {
+                warn!(scheme, user = user.as_deref().unwrap_or_default(), "[-] Proxy authentication failed");
+                Err(self.challenge(false))
+            }
+        }
+    }
+
+    async fn basic(&self, encoded: &str) -> Outcome {
+        let Some(decoded) = STANDARD.decode(encoded).ok().and_then(|d| String::from_utf8(d).ok()) else {
+            return Outcome::Rejected(None);
+        };
+        let Some((user, password)) = decoded.split_once(':') else {
+            return Outcome::Rejected(None);
+        };
+        if self.backend.verify(user, password).await {
+            Outcome::Accepted(user.to_string())
+        } else {
+            Outcome::Rejected(Some(user.to_string()))
+        }
+    }
+
+    /// RFC 7616 的 MD5 算法, 支持 `qop=auth` 和不带 qop 的旧格式.
+    /// `uri` 必须与请求目标一致, 同一个 nonce 的 `nc` 必须递增, 旧格式的 nonce 只能用一次
+    async fn digest(&self, req: &Request, params: &str) -> Outcome {
+        let params = parse_params(params);
+        let param = |name: &str| params.get(name).map(String::as_str);
+        let Some(user) = param("username") else {
+            return Outcome::Rejected(None);
+        };
+        let rejected = || Outcome::Rejected(Some(user.to_string()));
+        if param("realm") != Some(self.realm.as_str()) || !param("algorithm").is_none_or(|a| a.eq_ignore_ascii_case("md5")) {
+            return rejected();
+        }
+        let (Some(nonce), Some(uri), Some(response)) = (param("nonce"), param("uri"), param("response")) else {
+            return rejected();
+        };
+        if uri != req.url {
+            return rejected();
+        }
+        let Some(ha1) = self.backend.digest_ha1(user, &self.realm).await else {
+            return rejected();
+        };
+        let ha2 = md5_hex(&format!("{}:{}", req.method.as_str(), uri));
+        let (expected, count) = match (param("qop"), param("nc"), param("cnonce")) {
+            (Some("auth"), Some(nc), Some(cnonce)) => {
+                let Ok(count) = u64::from_str_radix(nc, 16) else {
+                    return rejected();
+                };
+                (md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2)), count)
+            }
+            (None, _, _) => (md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2)), 1),
+            _ => return rejected(),
+        };
+        if !constant_eq(expected.as_bytes(), response.to_ascii_lowercase().as_bytes()) {
+            return rejected();
+        }
+        match self.check_nonce(nonce) {
+            Some(true) if self.advance_nc(nonce, count) => Outcome::Accepted(user.to_string()),
+            // 密码正确但 nonce 过期或 nc 没有递增, 客户端会自动用新 nonce 重试
+            Some(_) => Outcome::Stale,
+            None => rejected(),
+        }
+    }
+
+    /// `nc` 大于该 nonce 之前用过的值时记录下来并返回true. 顺便清理超过有效期的记录
+    fn advance_nc(&self, nonce: &str, count: u64) -> bool {
+        let mut counts = self.nonce_counts.lock().unwrap();
+        counts.retain(|_, (_, first_used)| first_used.elapsed() <= NONCE_TTL);
+        match counts.get_mut(nonce) {
+            Some((last, _)) if *last >= count => false,
+            Some((last, _)) => {
+                *last = count;
+                true
+            }
+            None => {
+                counts.insert(nonce.to_string(), (count, Instant::now()));
+                true
+            }
+        }
+    }
+
+    fn challenge(&self, stale: bool) -> Response {
+        let mut resp = Response::new(407).with_header("Content-Length", "0");
+        resp.headers.append("Proxy-Authenticate", format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm));
+        resp.headers.append(
+            "Proxy-Authenticate",
+            format!(
+                "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+                self.realm,
+                self.nonce(SystemTime::now()),
+                if stale { ", stale=true" } else { "" }
+            ),
+        );
+        resp
+    }
+
+    /// `<秒>:<MD5(秒:secret)>` 的 base64, 不需要在服务端保存
+    fn nonce(&self, now: SystemTime) -> String {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        STANDARD.encode(format!("{}:{}", secs, self.sign(secs)))
+    }
+
+    fn sign(&self, secs: u64) -> String {
+        let mut hasher = Md5::new();
+        hasher.update(secs.to_string());
+        hasher.update(b":");
+        hasher.update(self.secret);
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// 签名正确时返回是否仍在有效期内, 伪造的 nonce 返回 None
+    fn check_nonce(&self, nonce: &str) -> Option<bool> {
+        let decoded = String::from_utf8(STANDARD.decode(nonce).ok()?).ok()?;
+        let (secs, signature) = decoded.split_once(':')?;
+        let secs: u64 = secs.parse().ok()?;
+        if !constant_eq(self.sign(secs).as_bytes(), signature.as_bytes()) {
+            return None;
+        }
+        let issued = UNIX_EPOCH + Duration::from_secs(secs);
+        Some(issued.elapsed().is_ok_and(|age| age <= NONCE_TTL))
+    }
+}
+
+enum Outcome {
+    Accepted(String),
+    /// Digest 的 nonce 已过期
+    Stale,
+    /// 用户名(如果能解析出来)只用于日志
+    Rejected(Option<String>),
+}
+
+/// htpasswd 格式的用户文件, 每行一个用户:
+///
+/// - `user:password` 明文
+/// - `user:{SHA}base64` (`htpasswd -s`)
+/// - `user:$2y$...` bcrypt (`htpasswd -B`)
+/// - `user:realm:ha1` htdigest 格式, 可用于 Digest
+///
+/// 明文和 htdigest 格式的用户可以使用 Digest 认证, 其余只能用 Basic.
+/// `#` 开头的行和空行忽略
+#[derive(Debug,Clone,Default)]
+pub struct HtpasswdFile {
+    users: HashMap<String, Secret>,
+}
+
+#[derive(Debug,Clone)]
+enum Secret {
+    Plain(String),
+    Sha1(String),
+    Bcrypt(String),
+    Digest { realm: String, ha1: String },
+}
+
+impl HtpasswdFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("[-] Failed to read {}", path.display()))?;
+        text.parse().with_context(|| format!("[-] Failed to parse {}", path.display()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl FromStr for HtpasswdFile {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut users = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, secret) = line.split_once(':').ok_or_else(|| anyhow!("[-] line {}: expected user:password", n + 1))?;
+            let secret = match secret.rsplit_once(':') {
+                Some((realm, ha1)) if ha1.len() == 32 && ha1.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    Secret::Digest { realm: realm.to_string(), ha1: ha1.to_ascii_lowercase() }
+                }
+                _ if secret.starts_with("{SHA}") => Secret::Sha1(secret["{SHA}".len()..].to_string()),
+                _ if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|p| secret.starts_with(p)) => Secret::Bcrypt(secret.to_string()),
+                _ if secret.starts_with('$') => {
+                    return Err(anyhow!("[-] line {}: unsupported hash for {}, use htpasswd -B or -s", n + 1, user));
+                }
+                _ => Secret::Plain(secret.to_string()),
+            };
+            users.insert(user.to_string(), secret);
+        }
+        Ok(HtpasswdFile { users })
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticator for HtpasswdFile {
+    async fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(Secret::Plain(expected)) => constant_eq(expected.as_bytes(), password.as_bytes()),
+            Some(Secret::Sha1(expected)) => constant_eq(expected.as_bytes(), STANDARD.encode(Sha1::digest(password)).as_bytes()),
+            Some(Secret::Bcrypt(hash)) => {
+                // bcrypt 故意很慢, 不占用异步线程
+                let (hash, password) = (hash.clone(), password.to_string());
+                tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false)).await.unwrap_or(false)
+            }
+            Some(Secret::Digest { realm, ha1 }) => constant_eq(ha1.as_bytes(), md5_hex(&format!("{}:{}:{}", user, realm, password)).as_bytes()),
+            None => false,
+        }
+    }
+
+    async fn digest_ha1(&self, user: &str, realm: &str) -> Option<String> {
+        match self.users.get(user)? {
+            Secret::Plain(password) => Some(md5_hex(&format!("{}:{}:{}", user, realm, password))),
+            Secret::Digest { realm: r, ha1 } if r == realm => Some(ha1.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn md5_hex(s: &str) -> String {
+    format!("{:x}", Md5::digest(s))
+}
+
+/// `key=value, key="quoted, value"` 形式的参数, 键转为小写
+fn parse_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = s.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let after = after.trim_start();
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        params.insert(key, value);
+        rest = next.trim_start().trim_start_matches(',');
+    }
+    params
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn basic_and_digest() {
+        let users: HtpasswdFile = "# users\nalice:secret\nbob:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n".parse().unwrap();
+        assert_eq!(users.len(), 2);
+        assert!("carol:$apr1$x$y".parse::<HtpasswdFile>().is_err());
+        let auth = ProxyAuth::new("proxy", users);
+        let connect = |credentials: &str| {
+            Request::from_string(&format!("CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n", credentials)).unwrap()
+        };
+
+        let challenge = auth.authenticate(&mut Request::from_string("CONNECT example.com:443 HTTP/1.1\r\n\r\n").unwrap()).await.unwrap_err();
+        assert_eq!(challenge.status, 407);
+        let offered: Vec<_> = challenge.headers.get_all("proxy-authenticate").map(|v| String::from_utf8_lossy(v).to_string()).collect();
+        assert!(offered[0].starts_with("Basic realm=\"proxy\""));
+
+        // bob 的密码是 "password"
+        let mut req = connect(&format!("Basic {}", STANDARD.encode("bob:password")));
+        assert_eq!(auth.authenticate(&mut req).await.unwrap(), "bob");
+        assert_eq!(req.header("proxy-authorization"), None);
+        assert!(auth.authenticate(&mut connect(&format!("Basic {}", STANDARD.encode("bob:wrong")))).await.is_err());
+        let hashed: HtpasswdFile = format!("dave:{}", bcrypt::hash("pw", 4).unwrap()).parse().unwrap();
+        assert!(hashed.verify("dave", "pw").await && !hashed.verify("dave", "px").await);
+
+        // 按质询计算 Digest 响应
+        let nonce = parse_params(offered[1].strip_prefix("Digest ").unwrap())["nonce"].clone();
+        let ha1 = md5_hex("alice:proxy:secret");
+        let ha2 = md5_hex("CONNECT:example.com:443");
+        let response = md5_hex(&format!("{}:{}:00000001:abc:auth:{}", ha1, nonce, ha2));
+        let digest = |nonce: &str, response: &str| {
+            format!(
+                "Digest username=\"alice\", realm=\"proxy\", nonce=\"{}\", uri=\"example.com:443\", qop=auth, nc=00000001, cnonce=\"abc\", response=\"{}\"",
+                nonce, response
+            )
+        };
+        assert_eq!(auth.authenticate(&mut connect(&digest(&nonce, &response))).await.unwrap(), "alice");
+        assert!(auth.authenticate(&mut connect(&digest(&nonce, &md5_hex("forged")))).await.is_err());
+
+        // 重放同一个 nc 要求换新 nonce, 递增后可以继续使用; uri 必须是请求目标
+        let replayed = auth.authenticate(&mut connect(&digest(&nonce, &response))).await.unwrap_err();
+        assert!(replayed.headers.get_all("proxy-authenticate").any(|v| v.ends_with(b"stale=true")));
+        let next = md5_hex(&format!("{}:{}:00000002:abc:auth:{}", ha1, nonce, ha2));
+        let next = digest(&nonce, &next).replace("nc=00000001", "nc=00000002");
+        assert_eq!(auth.authenticate(&mut connect(&next)).await.unwrap(), "alice");
+        let other = Request::from_string(&format!("CONNECT other.test:443 HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n", next.replace("nc=00000002", "nc=00000003")));
+        assert!(auth.authenticate(&mut other.unwrap()).await.is_err());
+
+        // 过期的 nonce 要求客户端重试
+        let old = auth.nonce(SystemTime::now() - NONCE_TTL - Duration::from_secs(1));
+        let response = md5_hex(&format!("{}:{}:00000001:abc:auth:{}", ha1, old, ha2));
+        let stale = auth.authenticate(&mut connect(&digest(&old, &response))).await.unwrap_err();
+        assert!(stale.headers.get_all("proxy-authenticate").any(|v| v.ends_with(b"stale=true")));
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::intercept::{InterceptContext, Interceptor, Verdict};
+use crate::prelude::*;
+use crate::rules::Phase;
+
+/// 暂停后没有人处理时自动继续的默认时间
+pub const DEFAULT_BREAKPOINT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// 拦截开关使用的断点规则
+const INTERCEPT_ALL: &str = "*";
+
+/// 断点条件
+#[derive(Debug,Clone,Deserialize,serde::Serialize)]
+pub struct BreakpointRule {
+    /// 完整 URL(含查询串)的通配符
+    pub url: String,
+    #[serde(default)]
+    pub phase: Phase,
+    /// 只在这个方法上暂停, 不设置时匹配所有方法
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default = "crate::config::default_true")]
+    pub enabled: bool,
+}
+
+impl BreakpointRule {
+    pub fn new(url: impl Into<String>, phase: Phase) -> Self {
+        BreakpointRule { url: url.into(), phase, method: None, enabled: true }
+    }
+
+    fn is_intercept_all(&self) -> bool {
+        self.url == INTERCEPT_ALL && self.phase == Phase::Request && self.method.is_none()
+    }
+
+    fn matches(&self, phase: Phase, req: &Request) -> bool {
+        self.enabled
+            && self.phase == phase
+            && self.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(req.method.as_str()))
+            && wildcard_match(&self.url, &req.uri.to_string())
+    }
+}
+
+/// 一个被暂停的事务. 响应阶段的断点同时带有请求和响应
+#[derive(Debug,Clone,serde::Serialize)]
+pub struct Paused {
+    pub id: u64,
+    pub session_id: u32,
+    pub phase: Phase,
+    pub request: Request,
+    pub response: Option<Response>,
+    pub paused_at: SystemTime,
+}
+
+enum Resume {
+    Continue(Box<Paused>),
+    Abort,
+}
+
+struct Entry {
+    paused: Paused,
+    resume: oneshot::Sender<Resume>,
+}
+
+/// 等待中的 `pause` 被丢弃(客户端断开, 会话中止)时移除它的事务
+struct PauseGuard {
+    inner: Arc<Mutex<Inner>>,
+    id: u64,
+}
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.paused.remove(&self.id);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    rules: Vec<BreakpointRule>,
+    paused: BTreeMap<u64, Entry>,
+}
+
+/// 断点注册表, 在所有会话任务之间共享. 命中的事务在这里等待编辑, 放行或中止,
+/// 超时后按当前(可能已编辑的)内容自动继续
+#[derive(Clone)]
+pub struct Breakpoints {
+    inner: Arc<Mutex<Inner>>,
+    next_id: Arc<AtomicU64>,
+    timeout: Duration,
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Self::new(DEFAULT_BREAKPOINT_TIMEOUT)
+    }
+}
+
+impl std::fmt::Debug for Breakpoints {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        write!(f, "Breakpoints({} rules, {} paused)", inner.rules.len(), inner.paused.len())
+    }
+}
+
+impl Breakpoints {
+    pub fn new(timeout: Duration) -> Self {
+        Breakpoints { inner: Arc::default(), next_id: Arc::new(AtomicU64::new(1)), timeout }
+    }
+
+    pub fn add_rule(&self, rule: BreakpointRule) {
+        self.inner.lock().unwrap().rules.push(rule);
+    }
+
+    pub fn rules(&self) -> Vec<BreakpointRule> {
+        self.inner.lock().unwrap().rules.clone()
+    }
+
+    /// 删除 URL 通配符等于 `url` 的规则, 返回删除的数量
+    pub fn remove_rules(&self, url: &str) -> usize {
+        let rules = &mut self.inner.lock().unwrap().rules;
+        let before = rules.len();
+        rules.retain(|r| r.url != url);
+        before - rules.len()
+    }
+
+    /// 拦截开关: 在所有请求上暂停
+    pub fn set_intercept_all(&self, enabled: bool) {
+        let rules = &mut self.inner.lock().unwrap().rules;
+        rules.retain(|r| !r.is_intercept_all());
+        if enabled {
+            rules.push(BreakpointRule::new(INTERCEPT_ALL, Phase::Request));
+        }
+    }
+
+    pub fn intercept_all(&self) -> bool {
+        self.inner.lock().unwrap().rules.iter().any(BreakpointRule::is_intercept_all)
+    }
+
+    pub fn clear_rules(&self) {
+        self.inner.lock().unwrap().rules.clear();
+    }
+
+    /// 当前暂停的所有事务, 按暂停顺序
+    pub fn paused(&self) -> Vec<Paused> {
+        self.inner.lock().unwrap().paused.values().map(|e| e.paused.clone()).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Paused> {
+        self.inner.lock().unwrap().paused.get(&id).map(|e| e.paused.clone())
+    }
+
+    /// 替换暂停中的请求, 放行后生效. 事务不存在时返回false
+    pub fn edit_request(&self, id: u64, request: Request) -> bool {
+        match self.inner.lock().unwrap().paused.get_mut(&id) {
+            Some(entry) => {
+                entry.paused.request = request;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 替换暂停中的响应, 只对响应阶段的断点有效
+    pub fn edit_response(&self, id: u64, response: Response) -> bool {
+        match self.inner.lock().unwrap().paused.get_mut(&id) {
+            Some(Entry { paused: Paused { response: Some(resp), .. }, .. }) => {
+                *resp = response;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 按当前内容放行
+    pub fn release(&self, id: u64) -> bool {
+        self.finish(id, |paused| Resume::Continue(Box::new(paused)))
+    }
+
+    /// 中止事务, 客户端连接随之关闭
+    pub fn abort(&self, id: u64) -> bool {
+        self.finish(id, |_| Resume::Abort)
+    }
+
+    fn finish(&self, id: u64, resume: impl FnOnce(Paused) -> Resume) -> bool {
+        let entry = self.inner.lock().unwrap().paused.remove(&id);
+        match entry {
+            Some(entry) => entry.resume.send(resume(entry.paused)).is_ok(),
+            None => false,
+        }
+    }
+
+    fn should_pause(&self, phase: Phase, req: &Request) -> bool {
+        self.inner.lock().unwrap().rules.iter().any(|r| r.matches(phase, req))
+    }
+
+    /// 暂停事务直到被放行, 中止或超时; 返回None表示中止
+    async fn pause(&self, ctx: &InterceptContext, phase: Phase, request: Request, response: Option<Response>) -> Option<Paused> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, mut rx) = oneshot::channel();
+        info!(breakpoint = id, ?phase, method = request.method.as_str(), url = %request.uri, "[+] Breakpoint hit");
+        let paused = Paused { id, session_id: ctx.session_id, phase, request, response, paused_at: SystemTime::now() };
+        self.inner.lock().unwrap().paused.insert(id, Entry { paused, resume: tx });
+        let _guard = PauseGuard { inner: self.inner.clone(), id };
+        let resume = match tokio::time::timeout(self.timeout, &mut rx).await {
+            Ok(resume) => resume.ok(),
+            Err(_) => {
+                let entry = self.inner.lock().unwrap().paused.remove(&id);
+                match entry {
+                    Some(entry) => {
+                        warn!(breakpoint = id, "[-] Breakpoint timed out, continuing");
+                        Some(Resume::Continue(Box::new(entry.paused)))
+                    }
+                    // 超时的同时已经被放行或中止, 以发出的结果为准
+                    None => rx.try_recv().ok(),
+                }
+            }
+        };
+        match resume {
+            Some(Resume::Continue(paused)) => Some(*paused),
+            Some(Resume::Abort) | None => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Interceptor for Breakpoints {
+    async fn on_request(&self, ctx: &InterceptContext, req: &mut Request) -> Verdict {
+        if !self.should_pause(Phase::Request, req) {
+            return Verdict::Continue;
+        }
+        match self.pause(ctx, Phase::Request, req.clone(), None).await {
+            Some(paused) => {
+                *req = paused.request;
+                Verdict::Continue
+            }
+            None => Verdict::Drop,
+        }
+    }
+
+    async fn on_response(&self, ctx: &InterceptContext, req: &Request, resp: &mut Response) -> Verdict {
+        if !self.should_pause(Phase::Response, req) {
+            return Verdict::Continue;
+        }
+        match self.pause(ctx, Phase::Response, req.clone(), Some(resp.clone())).await {
+            Some(Paused { response: Some(edited), .. }) => {
+                *resp = edited;
+                Verdict::Continue
+            }
+            Some(_) => Verdict::Continue,
+            None => Verdict::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn wait_paused(bps: &Breakpoints) -> Paused {
+        loop {
+            if let Some(paused) = bps.paused().into_iter().next() {
+                return paused;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn edit_release_abort_and_timeout() {
+        let bps = Breakpoints::new(Duration::from_millis(200));
+        bps.add_rule(BreakpointRule::new("http://api.test/*", Phase::Request));
+        bps.add_rule(BreakpointRule::new("http://api.test/*", Phase::Response));
+        let ctx = InterceptContext::default();
+
+        // 编辑后放行
+        let task = tokio::spawn({
+            let bps = bps.clone();
+            let ctx = ctx.clone();
+            async move {
+                let mut req = Request::from_string("GET http://api.test/a HTTP/1.1\r\n\r\n").unwrap();
+                let verdict = bps.on_request(&ctx, &mut req).await;
+                (verdict, req)
+            }
+        });
+        let paused = wait_paused(&bps).await;
+        let mut req = paused.request.clone();
+        req.headers.insert("X-Edited", "1");
+        assert!(bps.edit_request(paused.id, req));
+        assert!(bps.release(paused.id));
+        let (verdict, req) = task.await.unwrap();
+        assert!(matches!(verdict, Verdict::Continue));
+        assert_eq!(req.header("x-edited"), Some("1"));
+        assert!(bps.paused().is_empty());
+
+        // 中止
+        let task = tokio::spawn({
+            let bps = bps.clone();
+            let ctx = ctx.clone();
+            async move {
+                let req = Request::from_string("GET http://api.test/b HTTP/1.1\r\n\r\n").unwrap();
+                bps.on_response(&ctx, &req, &mut Response::new(200)).await
+            }
+        });
+        let paused = wait_paused(&bps).await;
+        assert_eq!(paused.phase, Phase::Response);
+        assert!(bps.abort(paused.id));
+        assert!(matches!(task.await.unwrap(), Verdict::Drop));
+
+        // 超时后带着编辑过的响应继续
+        let task = tokio::spawn({
+            let bps = bps.clone();
+            async move {
+                let req = Request::from_string("GET http://api.test/c HTTP/1.1\r\n\r\n").unwrap();
+                let mut resp = Response::new(200);
+                let verdict = bps.on_response(&ctx, &req, &mut resp).await;
+                (verdict, resp)
+            }
+        });
+        let paused = wait_paused(&bps).await;
+        assert!(bps.edit_response(paused.id, Response::new(418)));
+        let (verdict, resp) = task.await.unwrap();
+        assert!(matches!(verdict, Verdict::Continue));
+        assert_eq!(resp.status, 418);
+
+        // 等待中的会话被中止时不留下暂停的事务
+        let task = tokio::spawn({
+            let bps = bps.clone();
+            async move {
+                let mut req = Request::from_string("GET http://api.test/d HTTP/1.1\r\n\r\n").unwrap();
+                bps.on_request(&InterceptContext::default(), &mut req).await
+            }
+        });
+        wait_paused(&bps).await;
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert!(bps.paused().is_empty());
+
+        let mut other = Request::from_string("GET http://other.test/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(matches!(bps.on_request(&InterceptContext::default(), &mut other).await, Verdict::Continue));
+    }
+}
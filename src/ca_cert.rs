@@ -69,4 +69,43 @@ pub  async fn generate_signed_cert(ca_cert: &Certificate, ca_key: &KeyPair, host
     params.use_authority_key_identifier_extension = true; 
     let cert = params.signed_by(&server_key_pair, ca_cert, ca_key)?;
     Ok(CertifiedKey { cert, key_pair: server_key_pair })
-}
\ No newline at end of file
+}
+/// 缓存的服务器证书数量上限, 超出时整体清空
+const CERT_CACHE_CAPACITY: usize = 1024;
+
+/// 按主机缓存签发的服务器证书, 避免每个 CONNECT 都重新签发
+pub struct CertCache {
+    ca: CertifiedKey,
+    configs: std::sync::Mutex<std::collections::HashMap<String, Arc<rustls::ServerConfig>>>,
+}
+
+impl CertCache {
+    pub fn new(ca: CertifiedKey) -> Self {
+        CertCache { ca, configs: Default::default() }
+    }
+
+    pub fn ca(&self) -> &CertifiedKey {
+        &self.ca
+    }
+
+    /// 给 `host` 的 TLS 服务端配置, 没有缓存时用 CA 签发
+    pub async fn server_config(&self, host: &str) -> Result<Arc<rustls::ServerConfig>, anyhow::Error> {
+        if let Some(config) = self.configs.lock().unwrap().get(host) {
+            crate::metrics::metrics().cert_cache_hits.inc();
+            return Ok(config.clone());
+        }
+        crate::metrics::metrics().cert_cache_misses.inc();
+        let server_cert = generate_signed_cert(&self.ca.cert, &self.ca.key_pair, host.to_string()).await?;
+        let config = Arc::new(
+            rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![server_cert.cert.into()], PrivateKeyDer::Pkcs8(server_cert.key_pair.serialize_der().into()))?,
+        );
+        let mut configs = self.configs.lock().unwrap();
+        if configs.len() >= CERT_CACHE_CAPACITY {
+            configs.clear();
+        }
+        configs.insert(host.to_string(), config.clone());
+        Ok(config)
+    }
+}
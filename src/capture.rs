@@ -0,0 +1,287 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use tokio::sync::broadcast;
+
+use crate::intercept::{InterceptContext, Interceptor};
+use crate::prelude::*;
+
+/// 内存中默认保留的事务数量
+pub const DEFAULT_FLOW_CAPACITY: usize = 1000;
+
+/// 一个完成的事务 (请求 + 响应)
+#[derive(Debug,Clone,serde::Serialize)]
+pub struct Flow {
+    pub id: u64,
+    pub session_id: u32,
+    pub client_addr: Option<String>,
+    pub tls: bool,
+    pub request: Request,
+    pub response: Response,
+    pub started_at: SystemTime,
+    pub duration_ms: Option<u64>,
+}
+
+/// 列表中显示的摘要, 不含消息体
+#[derive(Debug,Clone,serde::Serialize)]
+pub struct FlowSummary {
+    pub id: u64,
+    pub session_id: u32,
+    pub method: String,
+    pub url: String,
+    pub host: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub size: usize,
+    pub duration_ms: Option<u64>,
+    pub started_at: u64,
+    pub tls: bool,
+}
+
+impl Flow {
+    pub fn summary(&self) -> FlowSummary {
+        FlowSummary {
+            id: self.id,
+            session_id: self.session_id,
+            method: self.request.method.as_str().to_string(),
+            url: self.request.uri.to_string(),
+            host: self.request.target().map(|(h, _)| h).unwrap_or_default(),
+            status: self.response.status,
+            content_type: self.response.content_type().map(str::to_string),
+            size: self.response.body.len(),
+            duration_ms: self.duration_ms,
+            started_at: self.started_at.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default(),
+            tls: self.tls,
+        }
+    }
+
+    /// HAR 1.2 中的一个 entry, 消息体按解码后的文本导出
+    pub fn to_har_entry(&self) -> serde_json::Value {
+        let headers = |map: &HeaderMap| {
+            let mut map = map.clone();
+            map.redact_credentials();
+            map.iter().map(|(k, v)| json!({"name": k, "value": String::from_utf8_lossy(v)})).collect::<Vec<_>>()
+        };
+        let query = self
+            .request
+            .uri
+            .query
+            .as_deref()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let (k, v) = p.split_once('=').unwrap_or((p, ""));
+                json!({"name": k, "value": v})
+            })
+            .collect::<Vec<_>>();
+        let mut request = json!({
+            "method": self.request.method.as_str(),
+            "url": self.request.uri.to_string(),
+            "httpVersion": self.request.http_version,
+            "headers": headers(&self.request.headers),
+            "queryString": query,
+            "cookies": [],
+            "headersSize": -1,
+            "bodySize": self.request.body.len(),
+        });
+        if !self.request.body.is_empty() {
+            request["postData"] = json!({
+                "mimeType": self.request.header("content-type").unwrap_or_default(),
+                "text": String::from_utf8_lossy(&self.request.body),
+            });
+        }
+        let body = self.response.decoded_body().unwrap_or_else(|_| self.response.body.clone());
+        json!({
+            "startedDateTime": iso8601(self.started_at),
+            "time": self.response.timings.total.unwrap_or(self.duration_ms.unwrap_or_default() as f64),
+            "request": request,
+            "response": {
+                "status": self.response.status,
+                "statusText": self.response.reason,
+                "httpVersion": self.response.http_version,
+                "headers": headers(&self.response.headers),
+                "cookies": [],
+                "content": {
+                    "size": body.len(),
+                    "mimeType": self.response.content_type().unwrap_or_default(),
+                    "text": String::from_utf8_lossy(&body),
+                },
+                "redirectURL": self.response.headers.get_str("location").unwrap_or_default(),
+                "headersSize": -1,
+                "bodySize": self.response.body.len(),
+            },
+            "cache": {},
+            "timings": self.response.timings.to_har(self.duration_ms),
+        })
+    }
+}
+
+/// 导出为 HAR 1.2 文档
+pub fn to_har<'a>(flows: impl IntoIterator<Item = &'a Flow>) -> serde_json::Value {
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+            "entries": flows.into_iter().map(Flow::to_har_entry).collect::<Vec<_>>(),
+        }
+    })
+}
+
+/// 最近完成的事务: 保存在内存中并广播给订阅者 (控制台, 终端界面, 管理接口等).
+/// 作为拦截器注册到代理上, 克隆后共享同一份数据
+#[derive(Clone)]
+pub struct FlowLog {
+    flows: Arc<Mutex<VecDeque<Arc<Flow>>>>,
+    capacity: usize,
+    next_id: Arc<AtomicU64>,
+    sender: broadcast::Sender<Arc<Flow>>,
+}
+
+impl Default for FlowLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_FLOW_CAPACITY)
+    }
+}
+
+impl std::fmt::Debug for FlowLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FlowLog({}/{} flows)", self.len(), self.capacity)
+    }
+}
+
+impl FlowLog {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(256);
+        FlowLog { flows: Arc::default(), capacity, next_id: Arc::new(AtomicU64::new(1)), sender }
+    }
+
+    /// 记录一个事务并通知订阅者, 超过容量时丢弃最旧的
+    pub fn record(&self, ctx: &InterceptContext, req: &Request, resp: &Response) -> Arc<Flow> {
+        let started_at = req.received_at.or(resp.received_at).unwrap_or_else(SystemTime::now);
+        let flow = Arc::new(Flow {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            session_id: ctx.session_id,
+            client_addr: ctx.client_addr.map(|a| a.to_string()),
+            tls: ctx.tls || req.uri.scheme.as_deref() == Some("https"),
+            request: {
+                let mut req = req.clone();
+                req.headers.redact_credentials();
+                req
+            },
+            response: resp.clone(),
+            started_at,
+            duration_ms: resp.completed_at.and_then(|end| end.duration_since(started_at).ok()).map(|d| d.as_millis() as u64),
+        });
+        {
+            let mut flows = self.flows.lock().unwrap();
+            flows.push_back(flow.clone());
+            while flows.len() > self.capacity {
+                flows.pop_front();
+            }
+        }
+        // 没有订阅者时发送失败, 忽略即可
+        let _ = self.sender.send(flow.clone());
+        flow
+    }
+
+    /// 订阅之后完成的事务. 接收太慢时会收到 `Lagged` 并丢失部分事务
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Flow>> {
+        self.sender.subscribe()
+    }
+
+    /// 按完成顺序返回当前保存的事务
+    pub fn flows(&self) -> Vec<Arc<Flow>> {
+        self.flows.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Flow>> {
+        self.flows.lock().unwrap().iter().find(|f| f.id == id).cloned()
+    }
+
+    pub fn remove(&self, id: u64) -> bool {
+        let mut flows = self.flows.lock().unwrap();
+        let before = flows.len();
+        flows.retain(|f| f.id != id);
+        flows.len() != before
+    }
+
+    pub fn clear(&self) {
+        self.flows.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait::async_trait]
+impl Interceptor for FlowLog {
+    async fn on_complete(&self, ctx: &InterceptContext, req: &Request, resp: &Response) {
+        self.record(ctx, req, resp);
+    }
+}
+
+/// 把 `SystemTime` 格式化为 HAR 需要的 ISO 8601 UTC 时间
+fn iso8601(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // 天数换算为公历日期 (Howard Hinnant 的 civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_broadcasts_and_exports() {
+        let log = FlowLog::new(2);
+        let mut rx = log.subscribe();
+        let ctx = InterceptContext { session_id: 1, ..Default::default() };
+        for path in ["/a", "/b?x=1", "/c"] {
+            let req = Request::from_string(&format!("GET http://h.test{} HTTP/1.1\r\nProxy-Authorization: Basic YTpi\r\n\r\n", path)).unwrap();
+            log.on_complete(&ctx, &req, &Response::new(200).with_body("ok")).await;
+        }
+        assert_eq!(rx.recv().await.unwrap().request.uri.path, "/a");
+        let ids: Vec<u64> = log.flows().iter().map(|f| f.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(log.get(2).unwrap().summary().url, "http://h.test/b?x=1");
+
+        let har = to_har(log.flows().iter().map(|f| f.as_ref()));
+        let entry = &har["log"]["entries"][0];
+        assert_eq!(entry["request"]["queryString"][0], json!({"name": "x", "value": "1"}));
+        assert_eq!(entry["response"]["content"]["text"], "ok");
+        assert_eq!(entry["request"]["headers"][0]["value"], "[redacted]");
+        assert_eq!(iso8601(UNIX_EPOCH + std::time::Duration::from_millis(951_782_400_123)), "2000-02-29T00:00:00.123Z");
+
+        assert!(log.remove(2) && !log.remove(2));
+        assert_eq!(log.len(), 1);
+    }
+}
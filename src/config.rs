@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use serde::de::DeserializeOwned;
+
+/// 按扩展名读取 `.yaml`/`.yml` 或 `.toml` 配置文件, `what` 用在错误信息里.
+/// YAML 中的枚举写成 `- set_header: {...}` 形式的单键映射, 而不是 YAML tag
+pub fn load_config<T: DeserializeOwned>(path: impl AsRef<Path>, what: &str) -> Result<T, anyhow::Error> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).with_context(|| format!("[-] Failed to read {} file {}", what, path.display()))?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(&text))
+            .with_context(|| format!("[-] Failed to parse {} YAML", what)),
+        Some("toml") => toml::from_str(&text).with_context(|| format!("[-] Failed to parse {} TOML", what)),
+        other => Err(anyhow!("[-] unsupported {} file type: {:?}", what, other)),
+    }
+}
+
+/// 用于 `#[serde(default = "crate::config::default_true")]`
+pub fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug,PartialEq,Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Action {
+        Status(u16),
+        Fault {
+            #[serde(default = "default_true")]
+            enabled: bool,
+        },
+    }
+
+    #[derive(Debug,Deserialize)]
+    struct File {
+        actions: Vec<Action>,
+    }
+
+    #[test]
+    fn yaml_and_toml() {
+        let dir = std::env::temp_dir();
+        let yaml = dir.join(format!("config_{}.yaml", std::process::id()));
+        std::fs::write(&yaml, "actions:\n  - status: 503\n  - fault: {}\n").unwrap();
+        let file: File = load_config(&yaml, "test").unwrap();
+        assert_eq!(file.actions, vec![Action::Status(503), Action::Fault { enabled: true }]);
+
+        let toml = dir.join(format!("config_{}.toml", std::process::id()));
+        std::fs::write(&toml, "actions = [{ fault = { enabled = false } }]\n").unwrap();
+        let file: File = load_config(&toml, "test").unwrap();
+        assert_eq!(file.actions, vec![Action::Fault { enabled: false }]);
+
+        let json = dir.join(format!("config_{}.json", std::process::id()));
+        let err = load_config::<File>(&json, "test").unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to read test file"));
+        std::fs::write(&json, "{}").unwrap();
+        assert!(load_config::<File>(&json, "test").unwrap_err().to_string().contains("unsupported test file type"));
+        for path in [yaml, toml, json] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
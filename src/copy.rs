@@ -3,20 +3,34 @@ use std::io;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 
+use std::time::SystemTime;
+
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-#[derive(Debug)]
+use crate::fault::{FaultAction, StreamFault};
+use crate::throttle::Pacer;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,serde::Serialize)]
 pub enum Direction {
     Request,  // 请求体
     Response, // 响应体
 }
 
 
+/// 一个方向上转发的统计, 数据本身只经过 `Tap`
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    pub amt: u64,
+    pub first_byte_at: Option<SystemTime>,
+}
+
+/// 转发路径上观察数据的回调, 每写出一段数据调用一次
+pub type Tap = std::sync::Arc<dyn Fn(Direction, &[u8]) + Send + Sync>;
+
 pub(crate) fn trace_leaf(_: &mut std::task::Context<'_>) -> std::task::Poll<()> {
     std::task::Poll::Ready(())
 }
 
-#[derive(Debug)]
 pub(super) struct CopyBuffer {
     pub read_done: bool,
     pub need_flush: bool,
@@ -25,6 +39,12 @@ pub(super) struct CopyBuffer {
     pub amt: u64,
     pub buf: Box<[u8]>,
     pub direction: Direction, // 新增字段，表示当前数据流的方向
+    pub first_byte_at: Option<SystemTime>,
+    pub tap: Option<Tap>,
+    /// 限速时控制每次写出的时机和大小
+    pub pacer: Option<Pacer>,
+    /// 注入的停顿或截断
+    pub fault: Option<StreamFault>,
 }
 
 impl CopyBuffer {
@@ -37,6 +57,18 @@ impl CopyBuffer {
             amt: 0,
             buf: vec![0; buf_size].into_boxed_slice(),
             direction,
+            first_byte_at: None,
+            tap: None,
+            pacer: None,
+            fault: None,
+        }
+    }
+
+    /// 取出这个方向的统计
+    pub(super) fn take_capture(&mut self) -> Capture {
+        Capture {
+            amt: self.amt,
+            first_byte_at: self.first_byte_at,
         }
     }
 
@@ -56,6 +88,9 @@ impl CopyBuffer {
         if let Poll::Ready(Ok(())) = res {
             let filled_len = buf.filled().len();
             me.read_done = me.cap == filled_len;
+            if !me.read_done && me.first_byte_at.is_none() {
+                me.first_byte_at = Some(SystemTime::now());
+            }
             me.cap = filled_len;
         }
         res
@@ -66,13 +101,18 @@ impl CopyBuffer {
         cx: &mut Context<'_>,
         mut reader: Pin<&mut R>,
         mut writer: Pin<&mut W>,
+        end: usize,
     ) -> Poll<io::Result<usize>>
     where
         R: AsyncRead + ?Sized,
         W: AsyncWrite + ?Sized,
     {
         let me = &mut *self;
-        match writer.as_mut().poll_write(cx, &me.buf[me.pos..me.cap]) {
+        let end = match &mut me.pacer {
+            Some(pacer) => me.pos + ready!(pacer.poll_reserve(cx, end - me.pos)),
+            None => end,
+        };
+        match writer.as_mut().poll_write(cx, &me.buf[me.pos..end]) {
             Poll::Pending => {
                 // Top up the buffer towards full if we can read a bit more
                 // data - this should improve the chances of a large write
@@ -81,7 +121,12 @@ impl CopyBuffer {
                 }
                 Poll::Pending
             }
-            res => res,
+            res => {
+                if let (Some(pacer), Poll::Ready(Ok(n))) = (&mut me.pacer, &res) {
+                    pacer.consume(*n);
+                }
+                res
+            }
         }
     }
 
@@ -170,7 +215,19 @@ impl CopyBuffer {
 
             // If our buffer has some data, let's write it out!
             while self.pos < self.cap {
-                let i = ready!(self.poll_write_buf(cx, reader.as_mut(), writer.as_mut()))?;
+                let mut end = self.cap;
+                if let Some(fault) = &mut self.fault {
+                    match ready!(fault.poll_limit(cx, self.cap - self.pos)) {
+                        FaultAction::Write(n) => end = self.pos + n,
+                        // 截断: 丢弃剩余数据, 结束这个方向
+                        FaultAction::Discard => {
+                            self.read_done = true;
+                            self.cap = self.pos;
+                            break;
+                        }
+                    }
+                }
+                let i = ready!(self.poll_write_buf(cx, reader.as_mut(), writer.as_mut(), end))?;
                 #[cfg(any(
                     feature = "fs",
                     feature = "io-std",
@@ -188,6 +245,12 @@ impl CopyBuffer {
                         "write zero byte into writer",
                     )));
                 } else {
+                    if let Some(tap) = &self.tap {
+                        tap(self.direction, &self.buf[self.pos..self.pos + i]);
+                    }
+                    if let Some(fault) = &mut self.fault {
+                        fault.consume(i);
+                    }
                     self.pos += i;
                     self.amt += i as u64;
                     self.need_flush = true;
@@ -0,0 +1,256 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::sync::{broadcast, mpsc};
+use tracing::info;
+
+use crate::acl::Acl;
+use crate::capture::{to_har, Flow, FlowLog};
+use crate::filter::Filter;
+use crate::intercept::InterceptContext;
+use crate::prelude::*;
+use crate::replay::{repeat, RequestEdit};
+use crate::web::{self, bearer_authorized, error_response, json_response, query_param, Reply};
+
+const INDEX_HTML: &str = include_str!("dashboard.html");
+
+/// SSE 连接空闲时发送注释行的间隔, 用来发现已经断开的浏览器
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// 管理端口上的网页控制台, 数据来自 `FlowLog`.
+///
+/// - `GET /` 页面
+/// - `GET /api/flows?filter=` 事务摘要列表
+/// - `GET /api/flows/{id}` 完整事务
+/// - `GET /api/flows/{id}/request/body`, `/response/body` 解码后的原始消息体
+/// - `DELETE /api/flows` 清空
+/// - `POST /api/flows/{id}/replay` 重放, 请求体可以是 `RequestEdit` JSON
+/// - `GET /api/events?filter=` 新事务的 SSE 流
+/// - `GET /api/export.har?filter=` 导出 HAR
+///
+/// `Host` 和 `Origin` 必须是看板自己的地址, 防止其他网页借用户的浏览器访问.
+/// 清空和重放还需要 `Authorization: Bearer <token>`, 页面从 `#token=` 读取或提示输入
+#[derive(Clone,Debug)]
+pub struct Dashboard {
+    token: Arc<str>,
+    flows: FlowLog,
+    /// 重放时检查目标, 与代理使用同一份 ACL
+    acl: Option<Arc<Acl>>,
+    /// 监听的地址, `run` 之后才有
+    addr: Option<SocketAddr>,
+}
+
+impl Dashboard {
+    pub fn new(token: impl Into<String>, flows: FlowLog) -> Self {
+        Dashboard { token: token.into().into(), flows, acl: None, addr: None }
+    }
+
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    pub async fn run(mut self, addr: &str) -> Result<(), anyhow::Error> {
+        let listener = tokio::net::TcpListener::bind(addr).await.with_context(|| format!("[-] Failed to bind dashboard on {}", addr))?;
+        let addr = listener.local_addr()?;
+        self.addr = Some(addr);
+        info!("[+] Dashboard on http://{}/", addr);
+        web::serve(listener, move |req| {
+            let this = self.clone();
+            async move { this.handle(req).await }
+        })
+        .await
+    }
+
+    pub async fn handle(&self, req: Request) -> Reply {
+        if !self.same_origin(&req) {
+            return error_response(403, "cross-origin request").into();
+        }
+        let filter = match query_param(&req, "filter").filter(|f| !f.trim().is_empty()).map(|f| f.parse::<Filter>()).transpose() {
+            Ok(filter) => filter,
+            Err(e) => return error_response(400, e).into(),
+        };
+        let segments: Vec<&str> = req.uri.path.trim_matches('/').split('/').collect();
+        match (&req.method, segments.as_slice()) {
+            (Method::GET, [""]) => Response::new(200).with_header("Content-Type", "text/html; charset=utf-8").with_body(INDEX_HTML).into(),
+            (Method::GET, ["api", "flows"]) => {
+                let list: Vec<_> = self.matching(filter.as_ref()).iter().map(|f| f.summary()).collect();
+                json_response(200, &list).into()
+            }
+            (Method::DELETE | Method::POST, _) if !bearer_authorized(&req, &self.token) => {
+                error_response(401, "missing or invalid token").with_header("WWW-Authenticate", "Bearer").into()
+            }
+            (Method::DELETE, ["api", "flows"]) => {
+                self.flows.clear();
+                Response::new(204).into()
+            }
+            (Method::GET, ["api", "flows", id]) => match self.flow(id) {
+                Some(flow) => json_response(200, flow.as_ref()).into(),
+                None => error_response(404, "no such flow").into(),
+            },
+            (Method::GET, ["api", "flows", id, part @ ("request" | "response"), "body"]) => match self.flow(id) {
+                Some(flow) => body_response(&flow, *part == "request").into(),
+                None => error_response(404, "no such flow").into(),
+            },
+            (Method::POST, ["api", "flows", id, "replay"]) => match req.header("content-type") {
+                Some(ct) if ct.split(';').next().is_some_and(|t| t.trim().eq_ignore_ascii_case("application/json")) => {
+                    self.replay(id, &req.body).await.into()
+                }
+                _ => error_response(415, "expected application/json").into(),
+            },
+            (Method::GET, ["api", "events"]) => self.events(filter),
+            (Method::GET, ["api", "export.har"]) => {
+                let flows = self.matching(filter.as_ref());
+                json_response(200, &to_har(flows.iter().map(|f| f.as_ref())))
+                    .with_header("Content-Disposition", "attachment; filename=\"flows.har\"")
+                    .into()
+            }
+            _ => error_response(404, "not found").into(),
+        }
+    }
+
+    /// `Host` 是看板监听的地址 (回环地址也接受 `localhost`), `Origin` 如果有则与之一致
+    fn same_origin(&self, req: &Request) -> bool {
+        let Some(addr) = self.addr else {
+            return true;
+        };
+        let Some(host) = req.header("host") else {
+            return false;
+        };
+        let own = host == addr.to_string() || (addr.ip().is_loopback() && host == format!("localhost:{}", addr.port()));
+        own && req.header("origin").is_none_or(|origin| origin == format!("http://{}", host))
+    }
+
+    fn flow(&self, id: &str) -> Option<Arc<Flow>> {
+        self.flows.get(id.parse().ok()?)
+    }
+
+    fn matching(&self, filter: Option<&Filter>) -> Vec<Arc<Flow>> {
+        self.flows.flows().into_iter().filter(|f| filter.is_none_or(|flt| flt.matches(&f.request, Some(&f.response)))).collect()
+    }
+
+    /// 重放并把结果作为新的事务记录下来
+    async fn replay(&self, id: &str, body: &[u8]) -> Response {
+        let Some(flow) = self.flow(id) else {
+            return error_response(404, "no such flow");
+        };
+        let edit = if body.iter().all(u8::is_ascii_whitespace) {
+            RequestEdit::default()
+        } else {
+            match serde_json::from_slice::<RequestEdit>(body) {
+                Ok(edit) => edit,
+                Err(e) => return error_response(400, e),
+            }
+        };
+        let result = repeat(&flow.request, &edit, Some(flow.response.clone()), self.acl.as_deref()).await;
+        match (&result.response, &result.error) {
+            (Some(resp), _) => {
+                let ctx = InterceptContext { session_id: flow.session_id, tls: flow.tls, ..Default::default() };
+                let new = self.flows.record(&ctx, &result.request, resp);
+                json_response(200, &new.summary())
+            }
+            (None, error) => error_response(502, error.as_deref().unwrap_or("replay failed")),
+        }
+    }
+
+    fn events(&self, filter: Option<Filter>) -> Reply {
+        let mut rx = self.flows.subscribe();
+        let (tx, out) = mpsc::channel::<Bytes>(64);
+        tokio::spawn(async move {
+            let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+            loop {
+                let chunk = tokio::select! {
+                    flow = rx.recv() => match flow {
+                        Ok(flow) if filter.as_ref().is_none_or(|f| f.matches(&flow.request, Some(&flow.response))) => {
+                            let data = serde_json::to_string(&flow.summary()).unwrap_or_default();
+                            format!("event: flow\ndata: {}\n\n", data)
+                        }
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(n)) => format!("event: lagged\ndata: {}\n\n", n),
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+                };
+                if tx.send(Bytes::from(chunk)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let resp = Response::new(200).with_header("Content-Type", "text/event-stream").with_header("Cache-Control", "no-cache");
+        Reply::Stream(resp, out)
+    }
+}
+
+/// 解码后的消息体, 带原来的 Content-Type, 供页面预览图片和显示十六进制
+fn body_response(flow: &Flow, request: bool) -> Response {
+    let (body, content_type) = if request {
+        (flow.request.body.clone(), flow.request.header("content-type"))
+    } else {
+        (flow.response.decoded_body().unwrap_or_else(|_| flow.response.body.clone()), flow.response.content_type())
+    };
+    Response::new(200)
+        .with_header("Content-Type", content_type.unwrap_or("application/octet-stream"))
+        .with_header("X-Content-Type-Options", "nosniff")
+        .with_header("Content-Security-Policy", "sandbox")
+        .with_body(body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn routes() {
+        let log = FlowLog::default();
+        let mut dashboard = Dashboard::new("secret", log.clone());
+        dashboard.addr = Some("127.0.0.1:9991".parse().unwrap());
+        let ctx = InterceptContext::default();
+        let req = Request::from_string("GET http://img.test/a.png HTTP/1.1\r\n\r\n").unwrap();
+        log.record(&ctx, &req, &Response::new(200).with_header("Content-Type", "image/png").with_body(&b"\x89PNG"[..]));
+        let req = Request::from_string("GET http://api.test/x HTTP/1.1\r\n\r\n").unwrap();
+        log.record(&ctx, &req, &Response::new(500).with_body("{}"));
+
+        let get = |path: &str| Request::from_string(&format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1:9991\r\n\r\n", path)).unwrap();
+        let full = |reply: Reply| match reply {
+            Reply::Full(resp) => resp,
+            Reply::Stream(..) => panic!("unexpected stream"),
+        };
+
+        let list = full(dashboard.handle(get("/api/flows?filter=status+%3E%3D+500")).await).json().unwrap();
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        assert_eq!(list[0]["host"], "api.test");
+
+        let body = full(dashboard.handle(get("/api/flows/1/response/body")).await);
+        assert_eq!(body.content_type(), Some("image/png"));
+        assert_eq!(&body.body[..], b"\x89PNG");
+
+        let har = full(dashboard.handle(get("/api/export.har")).await).json().unwrap();
+        assert_eq!(har["log"]["entries"].as_array().unwrap().len(), 2);
+        assert_eq!(full(dashboard.handle(get("/api/flows/9")).await).status, 404);
+        assert_eq!(full(dashboard.handle(get("/api/flows?filter=status+%3E%3E")).await).status, 400);
+
+        let Reply::Stream(resp, mut events) = dashboard.handle(get("/api/events?filter=host+%3D%3D+live.test")).await else {
+            panic!("expected stream");
+        };
+        assert_eq!(resp.content_type(), Some("text/event-stream"));
+        // 第一个块是立即触发的 keepalive
+        assert_eq!(&events.recv().await.unwrap()[..], b": keepalive\n\n");
+        log.record(&ctx, &get("/skip"), &Response::new(200));
+        log.record(&ctx, &Request::from_string("GET http://live.test/ HTTP/1.1\r\n\r\n").unwrap(), &Response::new(204));
+        let chunk = events.recv().await.unwrap();
+        assert!(chunk.starts_with(b"event: flow\ndata: {\"id\":4,"));
+
+        // 其他网页发起的请求和没有令牌的写操作
+        let send = |head: &str| Request::from_string(&format!("{}\r\n\r\n", head)).unwrap();
+        assert_eq!(full(dashboard.handle(send("GET /api/flows HTTP/1.1\r\nHost: evil.test:9991")).await).status, 403);
+        let cross = send("DELETE /api/flows HTTP/1.1\r\nHost: localhost:9991\r\nOrigin: http://evil.test\r\nAuthorization: Bearer secret");
+        assert_eq!(full(dashboard.handle(cross).await).status, 403);
+        assert_eq!(full(dashboard.handle(send("DELETE /api/flows HTTP/1.1\r\nHost: 127.0.0.1:9991")).await).status, 401);
+        let replay = send("POST /api/flows/1/replay HTTP/1.1\r\nHost: 127.0.0.1:9991\r\nAuthorization: Bearer secret\r\nContent-Type: text/plain");
+        assert_eq!(full(dashboard.handle(replay).await).status, 415);
+        let clear = send("DELETE /api/flows HTTP/1.1\r\nHost: 127.0.0.1:9991\r\nOrigin: http://127.0.0.1:9991\r\nAuthorization: Bearer secret");
+        assert_eq!(full(dashboard.handle(clear).await).status, 204);
+        assert!(log.flows().is_empty());
+    }
+}
@@ -6,13 +6,14 @@ use std::task::{ready, Context, Poll};
 
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::copy::{CopyBuffer, Direction};
-use crate::{Request, Response};
+use crate::copy::{Capture, CopyBuffer, Direction, Tap};
+use crate::fault::StreamFault;
+use crate::throttle::Shaper;
 
 enum TransferState {
     Running(CopyBuffer),
-    ShuttingDown(u64,String),
-    Done(u64,String),
+    ShuttingDown(Capture),
+    Done(Capture),
 }
 
 fn transfer_one_direction<A, B>(
@@ -20,7 +21,7 @@ fn transfer_one_direction<A, B>(
     state: &mut TransferState,
     r: &mut A,
     w: &mut B,
-) -> Poll<io::Result<(u64,String)>>
+) -> Poll<io::Result<Capture>>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
@@ -31,37 +32,20 @@ where
     loop {
         match state {
             TransferState::Running(buf) => {
-
-                let count = ready!(buf.poll_copy(cx, r.as_mut(), w.as_mut()))?;
-                // 打印每次拷贝的字节数
-                //println!("[INFO] Copied {} bytes", count);
-                let raw_data = String::from_utf8_lossy(&buf.buf[..count as usize]).to_string();
-                match buf.direction {
-                    Direction::Request => {
-                        let raw_data = String::from_utf8_lossy(&buf.buf[..count as usize]).to_string();
-                        //let data  = Request::from_string(&raw_data).unwrap();
-                        
-                    }
-                    Direction::Response => {
-                        let raw_data = String::from_utf8_lossy(&buf.buf[..count as usize]).to_string();
-                        //let resp  = Response::from_string(&raw_data).unwrap();
-                        //println!("[INFO] Response Data Copied:\n {:?}", resp);
-                    }
-                }
-                *state = TransferState::ShuttingDown(count,raw_data);
-
+                ready!(buf.poll_copy(cx, r.as_mut(), w.as_mut()))?;
+                *state = TransferState::ShuttingDown(buf.take_capture());
             }
-            TransferState::ShuttingDown(count,raw_data) => {
+            TransferState::ShuttingDown(capture) => {
                 ready!(w.as_mut().poll_shutdown(cx))?;
 
-                *state = TransferState::Done(*count,raw_data.to_string());
+                *state = TransferState::Done(std::mem::take(capture));
             }
-            TransferState::Done(count,raw_data) => return Poll::Ready(Ok((*count, raw_data.clone()))),
+            TransferState::Done(capture) => return Poll::Ready(Ok(std::mem::take(capture))),
         }
     }
 }
 
-pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> io::Result<((u64,String), (u64,String))>
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> io::Result<(Capture, Capture)>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
@@ -75,6 +59,32 @@ where
     .await
 }
 
+/// 与 [`copy_bidirectional()`] 相同, 每段转发的数据都会交给 `tap` 观察
+pub async fn copy_bidirectional_with_tap<A, B>(a: &mut A, b: &mut B, tap: Tap) -> io::Result<(Capture, Capture)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    copy_bidirectional_shaped(a, b, tap, None, None).await
+}
+
+/// 与 [`copy_bidirectional_with_tap()`] 相同, `a` 为客户端一侧, 两个方向按 `shaper` 限速,
+/// `fault` 作用于 `b` 写往 `a` 的数据
+pub async fn copy_bidirectional_shaped<A, B>(a: &mut A, b: &mut B, tap: Tap, shaper: Option<&Shaper>, fault: Option<StreamFault>) -> io::Result<(Capture, Capture)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut a_to_b = CopyBuffer::new(super::DEFAULT_BUF_SIZE, Direction::Request);
+    let mut b_to_a = CopyBuffer::new(super::DEFAULT_BUF_SIZE, Direction::Response);
+    a_to_b.tap = Some(tap.clone());
+    b_to_a.tap = Some(tap);
+    a_to_b.pacer = shaper.map(|s| s.pacer(Direction::Request));
+    b_to_a.pacer = shaper.map(|s| s.pacer(Direction::Response));
+    b_to_a.fault = fault;
+    copy_bidirectional_impl(a, b, a_to_b, b_to_a).await
+}
+
 /// Copies data in both directions between `a` and `b` using buffers of the specified size.
 ///
 /// This method is the same as the [`copy_bidirectional()`], except that it allows you to set the
@@ -86,7 +96,7 @@ pub async fn copy_bidirectional_with_sizes<A, B>(
     b: &mut B,
     a_to_b_buf_size: usize,
     b_to_a_buf_size: usize,
-) -> io::Result<((u64,String), (u64,String))>
+) -> io::Result<(Capture, Capture)>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
@@ -105,25 +115,32 @@ async fn copy_bidirectional_impl<A, B>(
     b: &mut B,
     a_to_b_buffer: CopyBuffer,
     b_to_a_buffer: CopyBuffer,
-) -> io::Result<((u64,String), (u64,String))>
+) -> io::Result<(Capture, Capture)>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
     let mut a_to_b = TransferState::Running(a_to_b_buffer);
     let mut b_to_a = TransferState::Running(b_to_a_buffer);
+    // Done 状态只交出一次数据, 先结束的方向在这里保存结果
+    let mut a_to_b_result = None;
+    let mut b_to_a_result = None;
     poll_fn(|cx| {
-        let (a_to_b) = transfer_one_direction(cx, &mut a_to_b, a, b)?;
-        let b_to_a = transfer_one_direction(cx, &mut b_to_a, b, a)?;
-
-        // It is not a problem if ready! returns early because transfer_one_direction for the
-        // other direction will keep returning TransferState::Done(count) in future calls to poll
-        let a_to_b = ready!(a_to_b);
-        let b_to_a = ready!(b_to_a);
+        if a_to_b_result.is_none() {
+            if let Poll::Ready(capture) = transfer_one_direction(cx, &mut a_to_b, a, b)? {
+                a_to_b_result = Some(capture);
+            }
+        }
+        if b_to_a_result.is_none() {
+            if let Poll::Ready(capture) = transfer_one_direction(cx, &mut b_to_a, b, a)? {
+                b_to_a_result = Some(capture);
+            }
+        }
 
-        Poll::Ready(Ok((a_to_b, b_to_a)))
+        match (&mut a_to_b_result, &mut b_to_a_result) {
+            (Some(a_to_b), Some(b_to_a)) => Poll::Ready(Ok((std::mem::take(a_to_b), std::mem::take(b_to_a)))),
+            _ => Poll::Pending,
+        }
     })
     .await
 }
-
-
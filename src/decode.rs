@@ -0,0 +1,76 @@
+use std::io::Read;
+
+use anyhow::anyhow;
+
+use crate::header::HeaderMap;
+
+/// 解压后允许的最大字节数, 防止解压炸弹
+pub const MAX_DECODED_SIZE: usize = 64 * 1024 * 1024;
+
+/// 按 `Content-Encoding` 解码消息体, 多层编码按相反顺序逐层解开.
+/// 没有编码时原样返回; 未知编码或解压后超过 `limit` 时返回错误
+pub fn decode_body(headers: &HeaderMap, body: &[u8], limit: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let encodings = headers.tokens("content-encoding");
+    let mut data = body.to_vec();
+    for encoding in encodings.iter().rev() {
+        data = decode_one(encoding, &data, limit)?;
+    }
+    Ok(data)
+}
+
+fn decode_one(encoding: &str, data: &[u8], limit: usize) -> Result<Vec<u8>, anyhow::Error> {
+    match encoding {
+        "identity" => Ok(data.to_vec()),
+        "gzip" | "x-gzip" => read_limited(flate2::read::MultiGzDecoder::new(data), limit),
+        // 规范要求 zlib 格式, 但不少服务器发送的是裸 deflate 流
+        "deflate" => read_limited(flate2::read::ZlibDecoder::new(data), limit)
+            .or_else(|_| read_limited(flate2::read::DeflateDecoder::new(data), limit)),
+        "br" => read_limited(brotli::Decompressor::new(data, 4096), limit),
+        "zstd" => read_limited(zstd::stream::read::Decoder::new(data)?, limit),
+        other => Err(anyhow!("unsupported content-encoding: {}", other)),
+    }
+}
+
+fn read_limited(reader: impl Read, limit: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut out = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut out)?;
+    if out.len() > limit {
+        return Err(anyhow!("decoded body exceeds {} bytes", limit));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    #[test]
+    fn stacked_encodings() {
+        let zstd_data = zstd::encode_all(&b"hello proxy"[..], 0).unwrap();
+        let body = gzip(&zstd_data);
+        let headers = HeaderMap::parse(b"Content-Encoding: zstd\r\nContent-Encoding: gzip\r\n");
+        assert_eq!(decode_body(&headers, &body, MAX_DECODED_SIZE).unwrap(), b"hello proxy");
+
+        let mut br = Vec::new();
+        brotli::CompressorWriter::new(&mut br, 4096, 5, 22).write_all(b"brotli body").unwrap();
+        let headers = HeaderMap::parse(b"Content-Encoding: br\r\n");
+        assert_eq!(decode_body(&headers, &br, MAX_DECODED_SIZE).unwrap(), b"brotli body");
+    }
+
+    #[test]
+    fn rejects_bombs_and_unknown() {
+        let body = gzip(&vec![0u8; 1024 * 1024]);
+        let headers = HeaderMap::parse(b"Content-Encoding: gzip\r\n");
+        assert!(decode_body(&headers, &body, 1024).is_err());
+        let headers = HeaderMap::parse(b"Content-Encoding: compress\r\n");
+        assert!(decode_body(&headers, b"x", MAX_DECODED_SIZE).is_err());
+    }
+}
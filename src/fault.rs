@@ -0,0 +1,322 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tokio::io::AsyncWrite;
+use tokio::time::{sleep, Sleep};
+
+use crate::config::load_config;
+use crate::prelude::*;
+
+/// TLS 告警记录: fatal handshake_failure
+pub const TLS_HANDSHAKE_FAILURE: &[u8] = &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+
+/// 故障注入配置文件: YAML 或 TOML, 顶层是 `rules` 列表
+///
+/// ```yaml
+/// rules:
+///   - host: "api.example.com"
+///     path: "/v1/*"
+///     probability: 0.2
+///     fault: { type: status, status: 503 }
+///   - host: "*.cdn.example"
+///     fault: { type: stall, ms: 5000, after: 1024 }
+/// ```
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct FaultFile {
+    #[serde(default)]
+    pub rules: Vec<FaultRule>,
+}
+
+/// 按主机和路径匹配的故障, 以 `probability` 的概率注入
+#[derive(Debug,Clone,Deserialize)]
+pub struct FaultRule {
+    /// 主机通配符
+    #[serde(default = "any")]
+    pub host: String,
+    /// 路径(不含查询串)通配符, `tls_handshake` 不看路径
+    #[serde(default = "any")]
+    pub path: String,
+    #[serde(default = "always")]
+    pub probability: f64,
+    pub fault: Fault,
+    #[serde(default = "crate::config::default_true")]
+    pub enabled: bool,
+}
+
+fn any() -> String {
+    "*".to_string()
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+/// 注入的故障, 都作用在上游一侧的流量上
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fault {
+    /// 不连接上游, 直接重置客户端连接
+    Reset,
+    /// 收到上游响应后延迟转发
+    Delay { ms: u64 },
+    /// 响应体只转发前 `bytes` 字节, 之后关闭连接
+    Truncate { bytes: u64 },
+    /// 不连接上游, 直接回复该状态码
+    Status {
+        #[serde(default = "default_status")]
+        status: u16,
+    },
+    /// 响应体转发 `after` 字节后停顿 `ms`
+    Stall {
+        ms: u64,
+        #[serde(default)]
+        after: u64,
+    },
+    /// CONNECT 后以 TLS 告警中断与客户端的握手
+    TlsHandshake,
+}
+
+fn default_status() -> u16 {
+    503
+}
+
+impl Fault {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fault::Reset => "reset",
+            Fault::Delay { .. } => "delay",
+            Fault::Truncate { .. } => "truncate",
+            Fault::Status { .. } => "status",
+            Fault::Stall { .. } => "stall",
+            Fault::TlsHandshake => "tls_handshake",
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Delay { ms } => write!(f, "delay {}ms", ms),
+            Fault::Truncate { bytes } => write!(f, "truncate after {} bytes", bytes),
+            Fault::Status { status } => write!(f, "status {}", status),
+            Fault::Stall { ms, after } => write!(f, "stall {}ms after {} bytes", ms, after),
+            other => f.write_str(other.as_str()),
+        }
+    }
+}
+
+/// 按规则随机注入故障, 第一条匹配且命中概率的规则生效
+#[derive(Debug,Clone,Default)]
+pub struct FaultInjector {
+    rules: Vec<FaultRule>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `probability` 必须在 0 到 1 之间, `status` 只能是 400-599 的错误状态码
+    pub fn add(&mut self, rule: FaultRule) -> Result<&mut Self, anyhow::Error> {
+        if !(0.0..=1.0).contains(&rule.probability) {
+            return Err(anyhow!("[-] fault probability must be between 0 and 1: {}", rule.probability));
+        }
+        if let Fault::Status { status } = rule.fault {
+            if !(400..=599).contains(&status) {
+                return Err(anyhow!("[-] fault status must be between 400 and 599: {}", status));
+            }
+        }
+        self.rules.push(rule);
+        Ok(self)
+    }
+
+    pub fn rules(&self) -> &[FaultRule] {
+        &self.rules
+    }
+
+    /// 按扩展名读取 `.yaml`/`.yml` 或 `.toml` 配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let file: FaultFile = load_config(path, "fault")?;
+        let mut injector = FaultInjector::new();
+        for rule in file.rules {
+            injector.add(rule)?;
+        }
+        Ok(injector)
+    }
+
+    /// 一个请求要注入的故障, 不含 `tls_handshake`
+    pub fn pick(&self, host: &str, path: &str) -> Option<Fault> {
+        self.pick_where(host, |rule| rule.fault != Fault::TlsHandshake && wildcard_match(&rule.path, path))
+    }
+
+    /// CONNECT 到 `host` 时是否中断 TLS 握手
+    pub fn pick_connect(&self, host: &str) -> Option<Fault> {
+        self.pick_where(host, |rule| rule.fault == Fault::TlsHandshake)
+    }
+
+    fn pick_where(&self, host: &str, matches: impl Fn(&FaultRule) -> bool) -> Option<Fault> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.enabled && wildcard_match(&rule.host, host) && matches(rule))
+            .find(|rule| roll(rule.probability))
+            .map(|rule| rule.fault)
+    }
+}
+
+/// 以概率 `p` 返回true
+fn roll(p: f64) -> bool {
+    if p >= 1.0 {
+        return true;
+    }
+    let unit = (uuid::Uuid::new_v4().as_u64_pair().0 >> 11) as f64 / (1u64 << 53) as f64;
+    unit < p
+}
+
+/// `status` 故障回复给客户端的响应
+pub fn fault_response(status: u16) -> Response {
+    Response::new(status).with_header("Content-Type", "text/plain; charset=utf-8").with_body("fault injected\n")
+}
+
+/// 数据流上的停顿或截断, 按已经转发的字节数触发
+pub struct StreamFault {
+    after: u64,
+    /// None 表示截断
+    stall: Option<Duration>,
+    passed: u64,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+/// 见 [`StreamFault::poll_limit`]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FaultAction {
+    /// 最多写出这么多字节
+    Write(usize),
+    /// 已截断, 丢弃剩余数据
+    Discard,
+}
+
+impl StreamFault {
+    /// 只有 `stall` 和 `truncate` 作用在数据流上
+    pub fn new(fault: &Fault) -> Option<Self> {
+        let (after, stall) = match *fault {
+            Fault::Stall { ms, after } => (after, Some(Duration::from_millis(ms))),
+            Fault::Truncate { bytes } => (bytes, None),
+            _ => return None,
+        };
+        Some(StreamFault { after, stall, passed: 0, sleep: None })
+    }
+
+    /// 写出 `want` 字节之前调用, 停顿时返回 Pending. 写完后用 [`StreamFault::consume`] 计数
+    pub fn poll_limit(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<FaultAction> {
+        if self.passed < self.after {
+            return Poll::Ready(FaultAction::Write(want.min((self.after - self.passed) as usize)));
+        }
+        let Some(stall) = self.stall else {
+            return Poll::Ready(FaultAction::Discard);
+        };
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(sleep(stall)));
+        ready!(sleep.as_mut().poll(cx));
+        // 只停顿一次
+        self.after = u64::MAX;
+        Poll::Ready(FaultAction::Write(want))
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        self.passed += n as u64;
+    }
+}
+
+/// 按 [`StreamFault`] 写出消息体, 截断后的数据直接丢弃
+pub struct Faulty<W> {
+    inner: W,
+    fault: Option<StreamFault>,
+}
+
+impl<W> Faulty<W> {
+    /// `fault` 为 None 时原样转发
+    pub fn new(inner: W, fault: Option<StreamFault>) -> Self {
+        Faulty { inner, fault }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Faulty<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        let Some(fault) = &mut me.fault else {
+            return Pin::new(&mut me.inner).poll_write(cx, buf);
+        };
+        match ready!(fault.poll_limit(cx, buf.len())) {
+            FaultAction::Discard => Poll::Ready(Ok(buf.len())),
+            FaultAction::Write(n) => {
+                let written = ready!(Pin::new(&mut me.inner).poll_write(cx, &buf[..n]))?;
+                fault.consume(written);
+                Poll::Ready(Ok(written))
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rules_and_stream_faults() {
+        let file: FaultFile = serde_yaml::from_str(
+            "rules:\n\
+             - host: '*.example.com'\n  path: /api/*\n  fault: { type: status }\n\
+             - host: '*.example.com'\n  probability: 0\n  fault: { type: reset }\n\
+             - host: secure.test\n  fault: { type: tls_handshake }\n\
+             - fault: { type: truncate, bytes: 4 }\n",
+        )
+        .unwrap();
+        let mut faults = FaultInjector::new();
+        for rule in file.rules {
+            faults.add(rule).unwrap();
+        }
+        assert_eq!(faults.pick("a.example.com", "/api/x"), Some(Fault::Status { status: 503 }));
+        assert_eq!(faults.pick("a.example.com", "/index.html"), Some(Fault::Truncate { bytes: 4 }));
+        assert_eq!(faults.pick("secure.test", "/"), Some(Fault::Truncate { bytes: 4 }));
+        assert_eq!(faults.pick_connect("secure.test"), Some(Fault::TlsHandshake));
+        assert_eq!(faults.pick_connect("a.example.com"), None);
+        assert!(serde_yaml::from_str::<FaultRule>("probability: 2\nfault: { type: reset }\n").map(|r| faults.add(r).is_err()).unwrap());
+        for status in [101, 204, 304, 600] {
+            let rule = serde_yaml::from_str::<FaultRule>(&format!("fault: {{ type: status, status: {} }}\n", status)).unwrap();
+            assert!(faults.add(rule).is_err(), "{}", status);
+        }
+
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut body = Faulty::new(a, StreamFault::new(&Fault::Truncate { bytes: 4 }));
+        body.write_all(b"hello world").await.unwrap();
+        drop(body);
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hell");
+
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut body = Faulty::new(a, StreamFault::new(&Fault::Stall { ms: 100, after: 2 }));
+        let started = std::time::Instant::now();
+        body.write_all(b"hello").await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let mut received = [0u8; 5];
+        b.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    }
+}
@@ -0,0 +1,430 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use regex::Regex;
+
+use crate::prelude::*;
+
+/// 括号和 `!` 的最大嵌套层数, 防止递归解析耗尽栈
+const MAX_DEPTH: usize = 64;
+/// 表达式的最大 token 数, 过长的 `&&`/`||` 链同样会在求值时深度递归
+const MAX_TOKENS: usize = 1024;
+
+/// 流量过滤表达式, 例如
+/// `host ~ "api" && status >= 500 && method == POST && body contains "token"`.
+///
+/// 字段: `host`, `path`, `query`, `url`, `scheme`, `method`, `status`, `reason`,
+/// `content_type`, `size`(响应体字节数), `duration`(毫秒), `req.body`, `resp.body`,
+/// `body`(请求或响应), `req.header.NAME`, `resp.header.NAME`, `header.NAME`(请求或响应).
+/// 运算符: `==`, `!=`, `~`(正则), `!~`, `contains`, `<`, `<=`, `>`, `>=`,
+/// 用 `&&`/`and`, `||`/`or`, `!`/`not` 和括号组合. 单独写字段名表示该字段存在且非空.
+/// 字符串比较不区分大小写; 字段不存在(如还没有响应)时比较结果为假
+#[derive(Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug,Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Exists(Field),
+    Compare(Field, Op, Value),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Field {
+    Host,
+    Path,
+    Query,
+    Url,
+    Scheme,
+    Method,
+    Status,
+    Reason,
+    ContentType,
+    Size,
+    Duration,
+    ReqBody,
+    RespBody,
+    Body,
+    ReqHeader(String),
+    RespHeader(String),
+    Header(String),
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Match,
+    NotMatch,
+    Contains,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug,Clone)]
+enum Value {
+    Text(String),
+    Number(f64),
+    Regex(Regex),
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self, anyhow::Error> {
+        let tokens = tokenize(source)?;
+        if tokens.len() > MAX_TOKENS {
+            return Err(anyhow!("[-] filter is too long: more than {} tokens", MAX_TOKENS));
+        }
+        let mut parser = Parser { tokens, pos: 0, depth: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(anyhow!("[-] unexpected {:?} in filter", token));
+        }
+        Ok(Filter { source: source.to_string(), expr })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// 对一个事务求值, 没有响应时响应相关的条件为假
+    pub fn matches(&self, req: &Request, resp: Option<&Response>) -> bool {
+        eval(&self.expr, req, resp)
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Filter({:?})", self.source)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Filter::parse(s)
+    }
+}
+
+fn eval(expr: &Expr, req: &Request, resp: Option<&Response>) -> bool {
+    match expr {
+        Expr::And(a, b) => eval(a, req, resp) && eval(b, req, resp),
+        Expr::Or(a, b) => eval(a, req, resp) || eval(b, req, resp),
+        Expr::Not(e) => !eval(e, req, resp),
+        Expr::Exists(field) => values(field, req, resp).iter().any(|v| !v.is_empty()),
+        Expr::Compare(field, op, value) => values(field, req, resp).iter().any(|v| compare(v, *op, value)),
+    }
+}
+
+/// 字段的取值, `body`/`header.NAME` 这类字段可能有多个值, 任意一个满足即可
+fn values(field: &Field, req: &Request, resp: Option<&Response>) -> Vec<String> {
+    let host = || req.target().map(|(h, _)| h).unwrap_or_default();
+    match field {
+        Field::Host => vec![host()],
+        Field::Path => vec![req.uri.path.clone()],
+        Field::Query => req.uri.query.clone().into_iter().collect(),
+        Field::Url => vec![req.uri.to_string()],
+        Field::Scheme => req.uri.scheme.clone().into_iter().collect(),
+        Field::Method => vec![req.method.as_str().to_string()],
+        Field::Status => resp.map(|r| r.status.to_string()).into_iter().collect(),
+        Field::Reason => resp.map(|r| r.reason.clone()).into_iter().collect(),
+        Field::ContentType => resp.and_then(|r| r.content_type()).or(req.header("content-type")).map(str::to_string).into_iter().collect(),
+        Field::Size => resp.map(|r| r.body.len().to_string()).into_iter().collect(),
+        Field::Duration => resp
+            .and_then(|r| r.completed_at?.duration_since(req.received_at?).ok())
+            .map(|d| d.as_millis().to_string())
+            .into_iter()
+            .collect(),
+        Field::ReqBody => vec![String::from_utf8_lossy(&req.body).into_owned()],
+        Field::RespBody => resp.map(Response::text).into_iter().collect(),
+        Field::Body => std::iter::once(String::from_utf8_lossy(&req.body).into_owned()).chain(resp.map(Response::text)).collect(),
+        Field::ReqHeader(name) => header_values(&req.headers, name),
+        Field::RespHeader(name) => resp.map(|r| header_values(&r.headers, name)).unwrap_or_default(),
+        Field::Header(name) => {
+            let mut all = header_values(&req.headers, name);
+            all.extend(resp.map(|r| header_values(&r.headers, name)).unwrap_or_default());
+            all
+        }
+    }
+}
+
+fn header_values(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers.get_all(name).map(|v| String::from_utf8_lossy(v).into_owned()).collect()
+}
+
+fn compare(actual: &str, op: Op, expected: &Value) -> bool {
+    match (op, expected) {
+        (Op::Match, Value::Regex(re)) => re.is_match(actual),
+        (Op::NotMatch, Value::Regex(re)) => !re.is_match(actual),
+        (Op::Contains, Value::Text(t)) => actual.to_lowercase().contains(&t.to_lowercase()),
+        (Op::Contains, Value::Number(n)) => actual.contains(&n.to_string()),
+        (op, Value::Number(n)) => match actual.trim().parse::<f64>() {
+            Ok(a) => match op {
+                Op::Eq => a == *n,
+                Op::Ne => a != *n,
+                Op::Lt => a < *n,
+                Op::Le => a <= *n,
+                Op::Gt => a > *n,
+                Op::Ge => a >= *n,
+                _ => false,
+            },
+            Err(_) => op == Op::Ne,
+        },
+        (Op::Eq, Value::Text(t)) => actual.eq_ignore_ascii_case(t),
+        (Op::Ne, Value::Text(t)) => !actual.eq_ignore_ascii_case(t),
+        (op, Value::Text(t)) => {
+            let ordering = actual.to_lowercase().cmp(&t.to_lowercase());
+            match op {
+                Op::Lt => ordering.is_lt(),
+                Op::Le => ordering.is_le(),
+                Op::Gt => ordering.is_gt(),
+                Op::Ge => ordering.is_ge(),
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, anyhow::Error> {
+    const OPS: &[&str] = &["&&", "||", "==", "!=", "!~", "<=", ">=", "~", "<", ">", "!"];
+    let mut tokens = Vec::new();
+    let mut rest = src.trim_start();
+    while !rest.is_empty() {
+        if let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if let Some(r) = rest.strip_prefix('(') {
+            tokens.push(Token::LParen);
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix(')') {
+            tokens.push(Token::RParen);
+            rest = r;
+        } else if rest.starts_with('"') || rest.starts_with('\'') {
+            let quote = rest.chars().next().unwrap_or('"');
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, next)) = chars.next() {
+                            value.push(next);
+                        }
+                    }
+                    c if c == quote => {
+                        end = Some(i + 2);
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            let end = end.ok_or_else(|| anyhow!("[-] unterminated string in filter: {}", src))?;
+            tokens.push(Token::Str(value));
+            rest = &rest[end..];
+        } else {
+            let end = rest.find(|c: char| c.is_whitespace() || "()\"'=!~<>&|".contains(c)).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(anyhow!("[-] unexpected character '{}' in filter", &rest[..1]));
+            }
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// 当前的嵌套层数
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn keyword(&self, word: &str, op: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(w)) => w.eq_ignore_ascii_case(word),
+            Some(Token::Op(o)) => *o == op,
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut expr = self.and()?;
+        while self.keyword("or", "||") {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut expr = self.not()?;
+        while self.keyword("and", "&&") {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    /// 进入一层 `!` 或括号
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, anyhow::Error>) -> Result<T, anyhow::Error> {
+        if self.depth >= MAX_DEPTH {
+            return Err(anyhow!("[-] filter is nested more than {} levels", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn not(&mut self) -> Result<Expr, anyhow::Error> {
+        if self.keyword("not", "!") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.nested(Self::not)?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, anyhow::Error> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.nested(Self::or)?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(anyhow!("[-] missing ')' in filter")),
+                }
+            }
+            Some(Token::Word(word)) => {
+                let field = parse_field(&word)?;
+                let op = match self.peek() {
+                    Some(Token::Op(op)) => match *op {
+                        "==" => Op::Eq,
+                        "!=" => Op::Ne,
+                        "~" => Op::Match,
+                        "!~" => Op::NotMatch,
+                        "<" => Op::Lt,
+                        "<=" => Op::Le,
+                        ">" => Op::Gt,
+                        ">=" => Op::Ge,
+                        _ => return Ok(Expr::Exists(field)),
+                    },
+                    Some(Token::Word(w)) if w.eq_ignore_ascii_case("contains") => Op::Contains,
+                    _ => return Ok(Expr::Exists(field)),
+                };
+                self.pos += 1;
+                let value = match self.next() {
+                    Some(Token::Str(s)) => s,
+                    Some(Token::Word(w)) => w,
+                    other => return Err(anyhow!("[-] expected value after {:?}, got {:?}", op, other)),
+                };
+                let value = match op {
+                    Op::Match | Op::NotMatch => Value::Regex(Regex::new(&format!("(?i){}", value))?),
+                    _ => match value.parse::<f64>() {
+                        Ok(n) => Value::Number(n),
+                        Err(_) => Value::Text(value),
+                    },
+                };
+                Ok(Expr::Compare(field, op, value))
+            }
+            other => Err(anyhow!("[-] expected field in filter, got {:?}", other)),
+        }
+    }
+}
+
+fn parse_field(word: &str) -> Result<Field, anyhow::Error> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(name) = lower.strip_prefix("req.header.") {
+        return Ok(Field::ReqHeader(name.to_string()));
+    }
+    if let Some(name) = lower.strip_prefix("resp.header.") {
+        return Ok(Field::RespHeader(name.to_string()));
+    }
+    if let Some(name) = lower.strip_prefix("header.") {
+        return Ok(Field::Header(name.to_string()));
+    }
+    Ok(match lower.as_str() {
+        "host" => Field::Host,
+        "path" => Field::Path,
+        "query" => Field::Query,
+        "url" => Field::Url,
+        "scheme" => Field::Scheme,
+        "method" => Field::Method,
+        "status" => Field::Status,
+        "reason" => Field::Reason,
+        "content_type" => Field::ContentType,
+        "size" => Field::Size,
+        "duration" => Field::Duration,
+        "req.body" => Field::ReqBody,
+        "resp.body" => Field::RespBody,
+        "body" => Field::Body,
+        _ => return Err(anyhow!("[-] unknown filter field '{}'", word)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_and_evaluate() {
+        let req = Request::from_string("POST https://api.example.com/login?next=%2F HTTP/1.1\r\nX-Trace: abc\r\nContent-Length: 13\r\n\r\n{\"token\":\"t\"}").unwrap();
+        let resp = Response::from_string("HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/html\r\n\r\ndown").unwrap();
+        let hit = |src: &str| Filter::parse(src).unwrap().matches(&req, Some(&resp));
+
+        assert!(hit(r#"host ~ "api" && status >= 500 && method == POST && body contains "token""#));
+        assert!(hit("method == post and not status < 500"));
+        assert!(hit("(status == 200 || status == 503) && path == '/login'"));
+        assert!(hit("req.header.x-trace == abc && !resp.header.x-trace && query"));
+        assert!(hit("content_type contains HTML && resp.body !~ '^up'"));
+        assert!(!hit("host ~ \"^example\" || size > 4"));
+        assert!(!Filter::parse("status >= 500").unwrap().matches(&req, None));
+
+        assert!(Filter::parse("status >=").is_err());
+        assert!(Filter::parse("color == red").is_err());
+        assert!(Filter::parse("(host == a").is_err());
+        assert!(Filter::parse("host == \"a").is_err());
+
+        // 嵌套和长度有上限, 超出时是解析错误而不是栈溢出
+        assert!(hit(&format!("{}host{}", "(".repeat(64), ")".repeat(64))));
+        assert!(Filter::parse(&format!("{}host{}", "(".repeat(65_000), ")".repeat(65_000))).is_err());
+        assert!(Filter::parse(&format!("{}host", "!".repeat(65_000))).is_err());
+        assert!(Filter::parse(&format!("{}host{}", "(".repeat(65), ")".repeat(65))).unwrap_err().to_string().contains("nested"));
+        assert!(Filter::parse(&format!("{}host", "not ".repeat(65))).unwrap_err().to_string().contains("nested"));
+        assert!(Filter::parse(&vec!["host"; 600].join(" && ")).is_err());
+    }
+}
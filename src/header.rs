@@ -0,0 +1,204 @@
+use serde::ser::SerializeSeq;
+
+/// HTTP头部集合: 名称不区分大小写, 保持原始顺序和大小写, 同名头部可以出现多次.
+/// 值保存为原始字节, 不要求是合法的UTF-8
+#[derive(Clone,Default,PartialEq,Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 解析头部块(不含起始行), 遇到空行停止. 没有冒号的行被忽略,
+    /// 以空白开头的折叠行 (obs-fold) 合并到上一个头部
+    pub fn parse(raw: &[u8]) -> Self {
+        let mut map = HeaderMap::new();
+        for line in raw.split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                break;
+            }
+            if line[0] == b' ' || line[0] == b'\t' {
+                if let Some((_, value)) = map.entries.last_mut() {
+                    value.push(b' ');
+                    value.extend_from_slice(trim(line));
+                }
+                continue;
+            }
+            let Some(colon) = line.iter().position(|&b| b == b':') else {
+                continue;
+            };
+            let name = String::from_utf8_lossy(trim(&line[..colon])).to_string();
+            if name.is_empty() {
+                continue;
+            }
+            map.entries.push((name, trim(&line[colon + 1..]).to_vec()));
+        }
+        map
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.iter().any(|(k, _)| k.eq_ignore_ascii_case(name))
+    }
+
+    /// 第一个同名头部的原始值
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_slice())
+    }
+
+    /// 第一个同名头部的值, 不是UTF-8时返回None
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.entries.iter().filter(move |(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_slice())
+    }
+
+    /// 替换同名头部: 第一个的位置保留, 其余删除; 不存在时追加到末尾
+    pub fn insert(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        let value = value.into();
+        match self.entries.iter().position(|(k, _)| k.eq_ignore_ascii_case(name)) {
+            Some(pos) => {
+                self.entries[pos].1 = value;
+                let mut idx = 0;
+                self.entries.retain(|(k, _)| {
+                    idx += 1;
+                    idx - 1 == pos || !k.eq_ignore_ascii_case(name)
+                });
+            }
+            None => self.entries.push((name.to_string(), value)),
+        }
+    }
+
+    /// 追加一个头部, 不影响已有的同名头部
+    pub fn append(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        self.entries.push((name.to_string(), value.into()));
+    }
+
+    /// 删除所有同名头部, 返回被删除的值
+    pub fn remove(&mut self, name: &str) -> Vec<Vec<u8>> {
+        let mut removed = Vec::new();
+        self.entries.retain_mut(|(k, v)| {
+            if k.eq_ignore_ascii_case(name) {
+                removed.push(std::mem::take(v));
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    /// 把 `Proxy-Authorization` 的值换成 `[redacted]`, 记录和导出前使用
+    pub fn redact_credentials(&mut self) {
+        for (k, v) in &mut self.entries {
+            if k.eq_ignore_ascii_case("proxy-authorization") {
+                *v = b"[redacted]".to_vec();
+            }
+        }
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&str, &[u8]) -> bool) {
+        self.entries.retain(|(k, v)| f(k, v));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    /// 逗号分隔的列表头部 (如 `Connection`) 中的所有元素, 统一转成小写
+    pub fn tokens(&self, name: &str) -> Vec<String> {
+        self.get_all(name)
+            .flat_map(|v| String::from_utf8_lossy(v).split(',').map(|t| t.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+            .filter(|t| !t.is_empty())
+            .collect()
+    }
+
+    /// 按原始名称和顺序写出 `Name: value\r\n`, 不含结尾空行
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        for (name, value) in &self.entries {
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(value);
+            buf.extend_from_slice(b"\r\n");
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf);
+        buf
+    }
+}
+
+impl std::fmt::Debug for HeaderMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|(name, value)| (name, String::from_utf8_lossy(value))))
+            .finish()
+    }
+}
+
+impl serde::Serialize for HeaderMap {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.entries.len()))?;
+        for (name, value) in &self.entries {
+            seq.serialize_element(&(name, String::from_utf8_lossy(value)))?;
+        }
+        seq.end()
+    }
+}
+
+fn trim(mut s: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = s {
+        s = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = s {
+        s = rest;
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn case_insensitive_multi_value() {
+        let mut map = HeaderMap::parse(b"Host: a.com\r\nSet-Cookie: a=1\r\nX-Trace:  abc \r\nset-cookie: b=2\r\n\r\nignored: yes");
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get_str("HOST"), Some("a.com"));
+        assert_eq!(map.get("x-trace"), Some(&b"abc"[..]));
+        assert_eq!(map.get_all("set-cookie").collect::<Vec<_>>(), vec![&b"a=1"[..], &b"b=2"[..]]);
+
+        map.insert("SET-COOKIE", "c=3");
+        assert_eq!(map.get_all("set-cookie").count(), 1);
+        assert_eq!(map.to_bytes(), b"Host: a.com\r\nSet-Cookie: c=3\r\nX-Trace: abc\r\n".to_vec());
+
+        map.append("Via", "1.1 proxy");
+        assert_eq!(map.remove("host"), vec![b"a.com".to_vec()]);
+        assert!(!map.contains("Host"));
+        assert_eq!(map.iter().last(), Some(("Via", &b"1.1 proxy"[..])));
+    }
+
+    #[test]
+    fn raw_values_and_folding() {
+        let map = HeaderMap::parse(b"X-Bin: \xff\xfe\r\nX-Long: a\r\n  b\r\nConnection: keep-alive, X-Bin\r\n");
+        assert_eq!(map.get("x-bin"), Some(&b"\xff\xfe"[..]));
+        assert_eq!(map.get_str("x-bin"), None);
+        assert_eq!(map.get_str("x-long"), Some("a b"));
+        assert_eq!(map.tokens("connection"), vec!["keep-alive", "x-bin"]);
+    }
+}
@@ -0,0 +1,479 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::header::HeaderMap;
+use crate::prelude::{head_len, Bytes, Method, Request, Response};
+
+/// 请求行加头部允许的最大长度
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// 解码分块传输编码 (RFC 9112 §7.1), 返回解码后的数据和消耗的字节数.
+/// 数据不完整或格式错误时返回None
+pub fn decode_chunked(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+    loop {
+        let line_end = pos + data[pos..].windows(2).position(|w| w == b"\r\n")?;
+        let size_line = std::str::from_utf8(&data[pos..line_end]).ok()?;
+        // 忽略 chunk-ext
+        let size_str = size_line.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_str, 16).ok()?;
+        pos = line_end + 2;
+        if size == 0 {
+            // 跳过 trailer 直到空行
+            loop {
+                let end = pos + data[pos..].windows(2).position(|w| w == b"\r\n")?;
+                let empty = end == pos;
+                pos = end + 2;
+                if empty {
+                    return Some((body, pos));
+                }
+            }
+        }
+        let chunk_end = pos.checked_add(size)?;
+        if data.len() < chunk_end + 2 || &data[chunk_end..chunk_end + 2] != b"\r\n" {
+            return None;
+        }
+        body.extend_from_slice(&data[pos..chunk_end]);
+        pos = chunk_end + 2;
+    }
+}
+
+/// 是否使用分块传输编码
+pub fn is_chunked(headers: &HeaderMap) -> bool {
+    headers.tokens("transfer-encoding").last().is_some_and(|t| t == "chunked")
+}
+
+pub fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers.get_str("content-length")?.trim().parse().ok()
+}
+
+/// 从头部之后的数据中取出一个完整消息的消息体, 返回(消息体, 消耗的字节数).
+/// 数据被截断时尽量返回已有部分
+pub fn message_body(headers: &HeaderMap, rest: &[u8]) -> (Vec<u8>, usize) {
+    if is_chunked(headers) {
+        return decode_chunked(rest).unwrap_or_else(|| (rest.to_vec(), rest.len()));
+    }
+    match content_length(headers) {
+        Some(len) => {
+            let len = len.min(rest.len());
+            (rest[..len].to_vec(), len)
+        }
+        None => (rest.to_vec(), rest.len()),
+    }
+}
+
+/// 消息体的分帧方式 (RFC 9112 §6)
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// 读到连接关闭为止, 只用于响应
+    UntilClose,
+}
+
+pub fn request_framing(headers: &HeaderMap) -> Framing {
+    if is_chunked(headers) {
+        Framing::Chunked
+    } else {
+        match content_length(headers) {
+            Some(len) if len > 0 => Framing::Length(len as u64),
+            _ => Framing::Empty,
+        }
+    }
+}
+
+/// 校验客户端请求的分帧头部 (RFC 9112 §6.3). 最后的传输编码不是 chunked,
+/// `Content-Length` 无法解析或出现多次时拒绝, 避免与上游对消息边界理解不一致.
+/// 同时有两者时以分块编码为准并删除 `Content-Length`
+pub fn checked_request_framing(headers: &mut HeaderMap) -> io::Result<Framing> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if headers.contains("transfer-encoding") {
+        if !is_chunked(headers) {
+            return Err(invalid("unsupported transfer-encoding"));
+        }
+        headers.remove("content-length");
+        return Ok(Framing::Chunked);
+    }
+    let lengths: Vec<&[u8]> = headers.get_all("content-length").collect();
+    match lengths.as_slice() {
+        [] => Ok(Framing::Empty),
+        [value] => {
+            let value = std::str::from_utf8(value).map(str::trim).unwrap_or_default();
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid("invalid content-length"));
+            }
+            let len: u64 = value.parse().map_err(|_| invalid("invalid content-length"))?;
+            Ok(if len > 0 { Framing::Length(len) } else { Framing::Empty })
+        }
+        _ => Err(invalid("multiple content-length")),
+    }
+}
+
+pub fn response_framing(method: &Method, status: u16, headers: &HeaderMap) -> Framing {
+    if *method == Method::HEAD || (100..200).contains(&status) || status == 204 || status == 304 {
+        return Framing::Empty;
+    }
+    if *method == Method::CONNECT && (200..300).contains(&status) {
+        return Framing::Empty;
+    }
+    if is_chunked(headers) {
+        return Framing::Chunked;
+    }
+    match content_length(headers) {
+        Some(len) => Framing::Length(len as u64),
+        None => Framing::UntilClose,
+    }
+}
+
+/// 消息体缓存完毕后改用 `Content-Length` 分帧, 分块编码属于逐跳信息
+pub fn fix_framing(headers: &mut HeaderMap, body_len: usize) {
+    headers.remove("transfer-encoding");
+    headers.insert("Content-Length", body_len.to_string());
+}
+
+/// 按原消息的分帧方式设置头部, 用于拦截器修改头部后继续转发剩余的消息体
+pub fn apply_framing(headers: &mut HeaderMap, framing: Framing) {
+    match framing {
+        Framing::Length(len) => fix_framing(headers, len as usize),
+        Framing::Chunked => {
+            headers.remove("content-length");
+            headers.insert("Transfer-Encoding", "chunked");
+        }
+        Framing::Empty | Framing::UntilClose => {
+            headers.remove("content-length");
+            headers.remove("transfer-encoding");
+        }
+    }
+}
+
+/// 超过缓存上限的消息体中尚未读取的部分
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Pending {
+    pub framing: Framing,
+    /// `Length` 时为剩余字节数, `Chunked` 时为当前块剩余的字节数
+    pub remaining: u64,
+}
+
+/// 带读缓冲的HTTP/1.x连接
+pub struct Conn<S> {
+    pub stream: S,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Conn<S> {
+    pub fn new(stream: S) -> Self {
+        Self::with_buffer(stream, Vec::new())
+    }
+
+    /// `buf` 是已经从 `stream` 读出但还没有处理的数据
+    pub fn with_buffer(stream: S, buf: Vec<u8>) -> Self {
+        Conn { stream, buf }
+    }
+
+    /// 拆出底层流和缓冲中剩余的数据, 切换为原始隧道时使用
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    async fn fill(&mut self) -> io::Result<usize> {
+        let mut tmp = [0u8; crate::DEFAULT_BUF_SIZE];
+        let n = self.stream.read(&mut tmp).await?;
+        self.buf.extend_from_slice(&tmp[..n]);
+        Ok(n)
+    }
+
+    /// 读取一个消息头(含结尾空行), 在读到任何数据之前连接关闭时返回None
+    pub async fn read_head(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // 消息之间多余的空行直接忽略 (RFC 9112 §2.2)
+            while self.buf.starts_with(b"\r\n") {
+                self.buf.drain(..2);
+            }
+            if let Some(len) = head_len(&self.buf) {
+                return Ok(Some(self.buf.drain(..len).collect()));
+            }
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "message head too large"));
+            }
+            if self.fill().await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    async fn take_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buf.drain(..pos + 2).take(pos).collect();
+                return Ok(line);
+            }
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk line too large"));
+            }
+            if self.fill().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    async fn take_exact(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        while self.buf.len() < n {
+            if self.fill().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    /// 取出当前缓冲, 缓冲为空时从流中读一次; 返回空表示连接已关闭
+    async fn take_some(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        if self.buf.is_empty() && self.fill().await? == 0 {
+            return Ok(Vec::new());
+        }
+        let n = self.buf.len().min(max);
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    async fn chunk_size(&mut self) -> io::Result<u64> {
+        let line = self.take_line().await?;
+        let line = String::from_utf8_lossy(&line);
+        let size = line.split(';').next().unwrap_or_default().trim();
+        u64::from_str_radix(size, 16).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size"))
+    }
+
+    /// 每个分块的数据之后必须是 CRLF
+    async fn chunk_end(&mut self) -> io::Result<()> {
+        if self.take_exact(2).await? != b"\r\n" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing CRLF after chunk data"));
+        }
+        Ok(())
+    }
+
+    /// 跳过分块编码结尾的 trailer
+    async fn skip_trailers(&mut self) -> io::Result<()> {
+        while !self.take_line().await?.is_empty() {}
+        Ok(())
+    }
+
+    /// 读取消息体, 超过 `limit` 时只缓存前缀, 剩余部分由 `relay_body` 转发
+    pub async fn read_body(&mut self, framing: Framing, limit: usize) -> io::Result<(Vec<u8>, Option<Pending>)> {
+        match framing {
+            Framing::Empty => Ok((Vec::new(), None)),
+            Framing::Length(len) => {
+                let take = len.min(limit as u64);
+                let body = self.take_exact(take as usize).await?;
+                let pending = (len > take).then_some(Pending { framing, remaining: len - take });
+                Ok((body, pending))
+            }
+            Framing::Chunked => {
+                let mut body = Vec::new();
+                loop {
+                    let size = self.chunk_size().await?;
+                    if size == 0 {
+                        self.skip_trailers().await?;
+                        return Ok((body, None));
+                    }
+                    if size > limit.saturating_sub(body.len()) as u64 {
+                        return Ok((body, Some(Pending { framing, remaining: size })));
+                    }
+                    body.extend(self.take_exact(size as usize).await?);
+                    self.chunk_end().await?;
+                }
+            }
+            Framing::UntilClose => {
+                let mut body = Vec::new();
+                while body.len() < limit {
+                    let data = self.take_some(limit - body.len()).await?;
+                    if data.is_empty() {
+                        return Ok((body, None));
+                    }
+                    body.extend(data);
+                }
+                Ok((body, Some(Pending { framing, remaining: 0 })))
+            }
+        }
+    }
+
+    /// 把超出缓存上限的剩余消息体原样转发给 `out`, 分帧方式保持不变
+    pub async fn relay_body<W: AsyncWrite + Unpin>(&mut self, pending: Pending, out: &mut W) -> io::Result<()> {
+        match pending.framing {
+            Framing::Empty => {}
+            Framing::Length(_) => {
+                let mut remaining = pending.remaining;
+                while remaining > 0 {
+                    let data = self.take_some(remaining.min(crate::DEFAULT_BUF_SIZE as u64) as usize).await?;
+                    if data.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    remaining -= data.len() as u64;
+                    out.write_all(&data).await?;
+                }
+            }
+            Framing::Chunked => {
+                let mut size = pending.remaining;
+                while size > 0 {
+                    out.write_all(format!("{:x}\r\n", size).as_bytes()).await?;
+                    let mut left = size;
+                    while left > 0 {
+                        let data = self.take_some(left.min(crate::DEFAULT_BUF_SIZE as u64) as usize).await?;
+                        if data.is_empty() {
+                            return Err(io::ErrorKind::UnexpectedEof.into());
+                        }
+                        left -= data.len() as u64;
+                        out.write_all(&data).await?;
+                    }
+                    self.chunk_end().await?;
+                    out.write_all(b"\r\n").await?;
+                    size = self.chunk_size().await?;
+                }
+                self.skip_trailers().await?;
+                out.write_all(b"0\r\n\r\n").await?;
+            }
+            Framing::UntilClose => loop {
+                let data = self.take_some(crate::DEFAULT_BUF_SIZE).await?;
+                if data.is_empty() {
+                    break;
+                }
+                out.write_all(&data).await?;
+            },
+        }
+        out.flush().await
+    }
+
+    /// 读取一个完整请求. 客户端发送 `Expect: 100-continue` 时先回复 100,
+    /// 该头部随后被删除, 不再转发给源站
+    pub async fn read_request(&mut self, limit: usize) -> io::Result<Option<(Request, Option<Pending>)>> {
+        let Some(head) = self.read_head().await? else {
+            return Ok(None);
+        };
+        let mut req = Request::from_bytes(&head)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid request head"))?;
+        req.received_at = Some(std::time::SystemTime::now());
+        let framing = checked_request_framing(&mut req.headers)?;
+        let expect = req.headers.remove("expect");
+        if framing != Framing::Empty && expect.iter().any(|v| v.eq_ignore_ascii_case(b"100-continue")) && self.buf.is_empty() {
+            self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        let (body, pending) = self.read_body(framing, limit).await?;
+        req.body = Bytes::from(body);
+        Ok(Some((req, pending)))
+    }
+
+    /// 读取 `method` 请求对应的最终响应, `100 Continue` 等中间响应被丢弃
+    pub async fn read_response(&mut self, method: &Method, limit: usize) -> io::Result<(Response, Option<Pending>)> {
+        loop {
+            let head = self.read_head().await?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            let received_at = std::time::SystemTime::now();
+            let mut resp = Response::from_bytes(&head)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            if resp.is_informational() && resp.status != 101 {
+                continue;
+            }
+            let framing = response_framing(method, resp.status, &resp.headers);
+            let (body, pending) = self.read_body(framing, limit).await?;
+            resp.body = Bytes::from(body);
+            resp.received_at = Some(received_at);
+            resp.completed_at = Some(std::time::SystemTime::now());
+            return Ok((resp, pending));
+        }
+    }
+}
+
+/// 写出缓存的前缀, 分帧方式与 `pending` 一致, 之后接着 `relay_body`
+pub async fn write_prefix<W: AsyncWrite + Unpin>(out: &mut W, prefix: &[u8], pending: &Pending) -> io::Result<()> {
+    if pending.framing == Framing::Chunked && !prefix.is_empty() {
+        out.write_all(format!("{:x}\r\n", prefix.len()).as_bytes()).await?;
+        out.write_all(prefix).await?;
+        out.write_all(b"\r\n").await
+    } else {
+        out.write_all(prefix).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunked_body() {
+        let data = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nNEXT";
+        let (body, used) = decode_chunked(data).unwrap();
+        assert_eq!(body, b"Wikipedia");
+        assert_eq!(&data[used..], b"NEXT");
+        assert!(decode_chunked(b"4\r\nWik").is_none());
+    }
+
+    #[test]
+    fn rejects_ambiguous_framing() {
+        let headers = |fields: &[(&str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in fields {
+                map.append(name, *value);
+            }
+            map
+        };
+        let mut both = headers(&[("Content-Length", "5"), ("Transfer-Encoding", "chunked")]);
+        assert_eq!(checked_request_framing(&mut both).unwrap(), Framing::Chunked);
+        assert!(!both.contains("content-length"));
+        assert_eq!(checked_request_framing(&mut headers(&[("Content-Length", "12")])).unwrap(), Framing::Length(12));
+        for bad in [
+            headers(&[("Transfer-Encoding", "gzip")]),
+            headers(&[("Transfer-Encoding", "chunked, gzip")]),
+            headers(&[("Content-Length", "5"), ("Content-Length", "5")]),
+            headers(&[("Content-Length", "5, 6")]),
+            headers(&[("Content-Length", "+5")]),
+            headers(&[("Content-Length", "")]),
+        ] {
+            assert!(checked_request_framing(&mut bad.clone()).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn conn_reads_pipelined_messages() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        server.write_all(b"POST /a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+                           GET /b HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        drop(server);
+        let mut conn = Conn::new(client);
+        let (req, pending) = conn.read_request(1024).await.unwrap().unwrap();
+        assert_eq!((req.uri.path.as_str(), &req.body[..], pending), ("/a", &b"abc"[..], None));
+        let (req, _) = conn.read_request(1024).await.unwrap().unwrap();
+        assert_eq!(req.uri.path, "/b");
+        assert!(conn.read_request(1024).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn huge_chunk_size_is_not_buffered() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        server.write_all(b"POST /a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\nffffffffffffffff\r\nxyz").await.unwrap();
+        let mut conn = Conn::new(client);
+        let (req, pending) = conn.read_request(1024).await.unwrap().unwrap();
+        assert_eq!((&req.body[..], pending.map(|p| p.remaining)), (&b"abcd"[..], Some(u64::MAX)));
+
+        // 分块数据之后不是 CRLF
+        let (client, mut server) = tokio::io::duplex(1024);
+        server.write_all(b"POST /a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcXY0\r\n\r\n").await.unwrap();
+        assert!(Conn::new(client).read_request(1024).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_relayed() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        server.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n6\r\nefghij\r\n0\r\n\r\n").await.unwrap();
+        drop(server);
+        let mut conn = Conn::new(client);
+        let (resp, pending) = conn.read_response(&Method::GET, 5).await.unwrap();
+        assert_eq!(&resp.body[..], b"abcd");
+        let pending = pending.unwrap();
+        let mut out = Vec::new();
+        write_prefix(&mut out, &resp.body, &pending).await.unwrap();
+        conn.relay_body(pending, &mut out).await.unwrap();
+        assert_eq!(decode_chunked(&out).unwrap().0, b"abcdefghij");
+    }
+}
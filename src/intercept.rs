@@ -0,0 +1,181 @@
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use crate::copy::Direction;
+use crate::prelude::{Request, Response, TlsInfo};
+
+/// 传给拦截器的会话信息
+#[derive(Debug,Clone,Default)]
+pub struct InterceptContext {
+    pub session_id: u32,
+    pub client_addr: Option<SocketAddr>,
+    /// CONNECT 请求的目标, 普通HTTP会话为None
+    pub connect_target: Option<(String, u16)>,
+    /// 是否在解密后的 TLS 连接中
+    pub tls: bool,
+    /// 与客户端握手协商的参数, 握手完成后才有
+    pub client_tls: Option<TlsInfo>,
+    /// 通过代理认证的用户名, 未启用认证时为None
+    pub user: Option<String>,
+}
+
+/// 拦截器对一个事务的处理结果
+// 只作为返回值短暂存在, 不值得为 Respond 装箱
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug,Clone)]
+pub enum Verdict {
+    /// 继续处理(可能已经修改过请求/响应)
+    Continue,
+    /// 不再访问上游, 直接用这个响应回复客户端; 在 `on_response` 中表示替换响应
+    Respond(Response),
+    /// 丢弃事务并关闭客户端连接
+    Drop,
+}
+
+/// 请求/响应拦截钩子, 所有方法都有默认实现, 只需实现关心的部分.
+/// 同一会话中的钩子按顺序调用, 普通HTTP和 MITM 解密后的 HTTPS 流量都会经过这里
+#[async_trait::async_trait]
+pub trait Interceptor: Send + Sync {
+    /// 客户端连接并发出第一个请求(或 CONNECT)之后, 连接上游之前
+    async fn on_connect(&self, _ctx: &InterceptContext, _req: &Request) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// 请求发往上游之前, 可以修改请求
+    async fn on_request(&self, _ctx: &InterceptContext, _req: &mut Request) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// 响应返回客户端之前, 可以修改响应
+    async fn on_response(&self, _ctx: &InterceptContext, _req: &Request, _resp: &mut Response) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// 协议升级或无法解析为HTTP后按原始字节转发的数据, 只能观察.
+    /// 在数据转发路径上同步调用, 不能阻塞
+    fn on_tunnel_data(&self, _ctx: &InterceptContext, _direction: Direction, _data: &[u8]) {}
+
+    /// 响应已经写给客户端之后调用, 只能观察; 用于记录和统计
+    async fn on_complete(&self, _ctx: &InterceptContext, _req: &Request, _resp: &Response) {}
+
+    /// 连接上游失败, TLS 握手失败或消息解析失败时调用
+    async fn on_error(&self, _ctx: &InterceptContext, _err: &anyhow::Error) {}
+}
+
+/// 按注册顺序调用的拦截器链, 第一个不返回 `Continue` 的拦截器决定结果
+#[derive(Clone,Default)]
+pub struct InterceptorChain {
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl InterceptorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, interceptor: Arc<dyn Interceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    pub fn len(&self) -> usize {
+        self.interceptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    pub async fn on_connect(&self, ctx: &InterceptContext, req: &Request) -> Verdict {
+        for interceptor in &self.interceptors {
+            match interceptor.on_connect(ctx, req).await {
+                Verdict::Continue => continue,
+                verdict => return verdict,
+            }
+        }
+        Verdict::Continue
+    }
+
+    pub async fn on_request(&self, ctx: &InterceptContext, req: &mut Request) -> Verdict {
+        for interceptor in &self.interceptors {
+            match interceptor.on_request(ctx, req).await {
+                Verdict::Continue => continue,
+                verdict => return verdict,
+            }
+        }
+        Verdict::Continue
+    }
+
+    /// `Respond` 替换当前响应后继续交给后面的拦截器
+    pub async fn on_response(&self, ctx: &InterceptContext, req: &Request, resp: &mut Response) -> Verdict {
+        for interceptor in &self.interceptors {
+            match interceptor.on_response(ctx, req, resp).await {
+                Verdict::Continue => continue,
+                Verdict::Respond(replacement) => *resp = replacement,
+                Verdict::Drop => return Verdict::Drop,
+            }
+        }
+        Verdict::Continue
+    }
+
+    pub fn on_tunnel_data(&self, ctx: &InterceptContext, direction: Direction, data: &[u8]) {
+        for interceptor in &self.interceptors {
+            interceptor.on_tunnel_data(ctx, direction, data);
+        }
+    }
+
+    pub async fn on_complete(&self, ctx: &InterceptContext, req: &Request, resp: &Response) {
+        for interceptor in &self.interceptors {
+            interceptor.on_complete(ctx, req, resp).await;
+        }
+    }
+
+    pub async fn on_error(&self, ctx: &InterceptContext, err: &anyhow::Error) {
+        for interceptor in &self.interceptors {
+            interceptor.on_error(ctx, err).await;
+        }
+    }
+}
+
+impl fmt::Debug for InterceptorChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InterceptorChain({} interceptors)", self.interceptors.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct AddHeader;
+    struct Block;
+
+    #[async_trait::async_trait]
+    impl Interceptor for AddHeader {
+        async fn on_request(&self, _ctx: &InterceptContext, req: &mut Request) -> Verdict {
+            req.headers.insert("X-Seen", "1");
+            Verdict::Continue
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Interceptor for Block {
+        async fn on_request(&self, _ctx: &InterceptContext, req: &mut Request) -> Verdict {
+            match req.header("x-seen") {
+                Some(_) => Verdict::Respond(Response::new(403)),
+                None => Verdict::Continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_runs_in_order_and_short_circuits() {
+        let mut chain = InterceptorChain::new();
+        chain.push(Arc::new(AddHeader));
+        chain.push(Arc::new(Block));
+        let mut req = Request::from_string("GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        match chain.on_request(&InterceptContext::default(), &mut req).await {
+            Verdict::Respond(resp) => assert_eq!(resp.status, 403),
+            other => panic!("unexpected verdict {:?}", other),
+        }
+        assert_eq!(req.header("x-seen"), Some("1"));
+    }
+}
@@ -1,6 +1,13 @@
 #![allow(unused)]
 pub const DEFAULT_BUF_SIZE: usize = 8192;
+/// 每个消息最多缓存/记录的消息体大小, 超出部分照常转发但不再记录
+pub const MAX_CAPTURE_SIZE: usize = 16 * 1024 * 1024;
+/// 拒绝连接前等待客户端发完请求的时间
+const REJECT_READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// 同时回复 503 的连接数上限, 超出时直接关闭连接
+const MAX_REJECTING: usize = 64;
 
+use std::path::Path;
 use std::thread::JoinHandle;
 use anyhow::Context;
 use prelude::*;
@@ -8,154 +15,368 @@ use session::{Session};
 use time::Duration;
 use tokio::{io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::Mutex, time::sleep};
 use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
 mod prelude;
+mod acl;
+mod config;
 mod ca_cert;
 mod session;
 mod debug_stream;
 mod copy;
+mod uri;
+mod header;
+mod http1;
+mod decode;
+mod intercept;
+mod upstream;
+mod rules;
+mod map_local;
+mod map_remote;
+mod breakpoint;
+mod replay;
+mod store;
+mod filter;
+mod capture;
+mod web;
+mod dashboard;
+mod tui;
+mod admin;
+mod auth;
+mod fault;
+mod metrics;
+mod limit;
+mod telemetry;
+mod throttle;
+mod timing;
+
+pub use crate::capture::{to_har, Flow, FlowLog, FlowSummary, DEFAULT_FLOW_CAPACITY};
+pub use crate::dashboard::Dashboard;
+pub use crate::acl::{Acl, AclConfig, ClientAcl, DestinationAcl};
+pub use crate::admin::AdminApi;
+pub use crate::auth::{Authenticator, HtpasswdFile, ProxyAuth, NONCE_TTL};
+pub use crate::breakpoint::{BreakpointRule, Breakpoints, Paused, DEFAULT_BREAKPOINT_TIMEOUT};
+pub use crate::copy::Direction;
+pub use crate::fault::{Fault, FaultInjector, FaultRule, Faulty, StreamFault};
+pub use crate::filter::Filter;
+pub use crate::header::HeaderMap;
+pub use crate::intercept::{InterceptContext, Interceptor, InterceptorChain, Verdict};
+pub use crate::prelude::{Method, Request, Response, TlsInfo};
+pub use crate::metrics::{metrics, Metrics};
+pub use crate::limit::{Admission, ConnectionLimiter, ConnectionLimits, ConnectionPermit, Rejected, Ticket};
+pub use crate::throttle::{BandwidthScope, NetworkConfig, NetworkPreset, RateLimited, RequestLimits, Shaper, Throttle, ThrottleConfig, Throttled, TokenBucket};
+pub use crate::telemetry::{init_tracing, LogConfig, LogFormat, TracePropagation, TracingGuard};
+pub use crate::map_local::{MapLocal, MapLocalRule};
+pub use crate::map_remote::{MapRemote, MapRemoteRule};
+pub use crate::replay::{load_batch, repeat, replay, replay_file, ReplayResult, RequestEdit};
+pub use crate::rules::{RuleEngine, RuleFile, RuleConfig, MatchConfig, ActionConfig, Phase};
+pub use crate::store::{Query, Store, StoreOptions, StoredExchange};
+pub use crate::timing::Timings;
+pub use crate::tui::Tui;
+pub use crate::uri::{Authority, TargetForm, Uri};
+
 // set_proxy_port
 async fn set_proxy_port(host: String, port: u32) -> Result<tokio::net::TcpListener, anyhow::Error> {
     let addr = format!("{}:{}", host, port);
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    println!("[+] Listening on {}\n", &*addr);
+    info!(%addr, "[+] Listening");
     Ok(listener)
 }
 
-async fn entry() -> Result<(), anyhow::Error> {
-    // test code
-    let listener = set_proxy_port("127.0.0.1".to_string(), 9990).await.context("[-] Failed to set_proxy_port func error: bad listener.")?;
-    let cacert = Arc::new(generate_ca_certificate().await.context("[-] Failed to generate ca certificate")?);
-
-    loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                    let task = tokio::spawn({
-                        let uuid = uuid::Uuid::new_v4();
-                        let session_id = u32::from_le_bytes(uuid.as_bytes()[0..4].try_into().unwrap());
-                        //println!("[Session {}] => [", session_id);
-                        let session = Arc::new(Mutex::new(Session::new(session_id, stream).unwrap()));
-                        let ca_cert = Arc::clone(&cacert);
-                        let session_clone = Arc::clone(&session);
-                        async move {
-                            let mut session_lock = session_clone.lock().await;
-                            session_lock.session_connect(addr).await;
-                            let method = session_lock.request.method.clone();
-                            let url = session_lock.request.url.clone();
-                            let initial_data = session_lock.initial_data.clone();
-                            let header_host = session_lock.request.host.clone();
-                            println!("[Session {}] Request Method: {:?}, URL: {}", session_id, method, url);
-                            match method {
-                                Method::CONNECT => {
-                                    let url_split: Vec<&str> = url.split(":").collect();
-                                    let host = url_split[0].to_string();
-                                    let port = url_split[1].to_string();
-                                    session_lock.handle_https(host, port, ca_cert).await;
-                                }
-                                Method::GET => {
-                                    let (host, port) = match header_host.split(':').collect::<Vec<&str>>().as_slice() {
-                                        // 如果只有主机名，没有端口号
-                                        [host] => (host.to_string(), "80".to_string()),
-                                        // 如果有主机名和端口号
-                                        [host, port] => (host.to_string(), port.to_string()),
-                                        // 处理其他异常情况
-                                        _ => ("".to_string(), "80".to_string())
-                                    };
-                                    session_lock.handle_http(host, port, initial_data).await;
+fn connection_rejected(addr: std::net::SocketAddr, reason: Rejected) {
+    warn!(client_addr = %addr, %reason, "[-] Too many concurrent connections");
+    metrics::metrics().rejected_connections.with_label_values(&[reason.as_str()]).inc();
+}
 
+/// 回复 503 后关闭连接. 先读掉请求再回复, 否则客户端可能因为连接重置看不到 503
+async fn reject_busy(stream: TcpStream, addr: std::net::SocketAddr) {
+    let Some(mut session) = Session::new(0, stream) else {
+        return;
+    };
+    let _ = tokio::time::timeout(REJECT_READ_TIMEOUT, session.session_connect(addr)).await;
+    session.respond(limit::busy_response()).await;
+}
+
+/// 代理服务: 监听地址和按顺序调用的拦截器
+pub struct Proxy {
+    host: String,
+    port: u32,
+    interceptors: InterceptorChain,
+    log_filter: Option<Arc<Filter>>,
+    trace_propagation: TracePropagation,
+    auth: Option<ProxyAuth>,
+    acl: Option<Arc<Acl>>,
+    limiter: ConnectionLimiter,
+    throttle: Option<Arc<Throttle>>,
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl Proxy {
+    pub fn new(host: impl Into<String>, port: u32) -> Self {
+        Proxy { host: host.into(), port, interceptors: InterceptorChain::new(), log_filter: None, trace_propagation: TracePropagation::Off, auth: None, acl: None, limiter: ConnectionLimiter::default(), throttle: None, faults: None }
+    }
+
+    /// 注册拦截器, 按注册顺序调用
+    pub fn add_interceptor(&mut self, interceptor: impl Interceptor + 'static) -> &mut Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// 只在日志中打印匹配过滤表达式的事务
+    pub fn set_log_filter(&mut self, filter: Filter) -> &mut Self {
+        self.log_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// 转发时如何处理 `traceparent`, 默认不处理
+    pub fn set_trace_propagation(&mut self, propagation: TracePropagation) -> &mut Self {
+        self.trace_propagation = propagation;
+        self
+    }
+
+    /// 要求客户端通过 `Proxy-Authorization` 认证, 每个连接在第一个请求上校验一次
+    pub fn set_auth(&mut self, auth: ProxyAuth) -> &mut Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// 按客户端地址和目标限制访问, 拒绝时回复 403
+    pub fn set_acl(&mut self, acl: Acl) -> &mut Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// 并发连接限制, 默认最多 `MAX_CONCURRENT_REQUESTS` 个连接
+    pub fn set_limits(&mut self, limits: ConnectionLimits) -> &mut Self {
+        self.limiter = ConnectionLimiter::new(limits);
+        self
+    }
+
+    /// 按客户端/目标主机限制请求速率, 限制客户端连接的带宽和延迟
+    pub fn set_throttle(&mut self, throttle: Throttle) -> &mut Self {
+        self.throttle = Some(Arc::new(throttle));
+        self
+    }
+
+    /// 按规则向转发的流量注入故障
+    pub fn set_faults(&mut self, faults: FaultInjector) -> &mut Self {
+        self.faults = Some(Arc::new(faults));
+        self
+    }
+
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        let listener = set_proxy_port(self.host.clone(), self.port).await.context("[-] Failed to set_proxy_port func error: bad listener.")?;
+        let certs = Arc::new(CertCache::new(generate_ca_certificate().await.context("[-] Failed to generate ca certificate")?));
+
+        // 会话任务都放在 JoinSet 中, 接受连接时先由 limiter 决定是否受理,
+        // 任务数不超过并发上限加排队上限. 结束的任务在循环中回收
+        let mut tasks = JoinSet::new();
+        let mut rejecting = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(done) = tasks.join_next(), if !tasks.is_empty() => {
+                    if let Err(e) = done {
+                        error!(error = ?e, "[-] Session task failed");
+                    }
+                    continue;
+                }
+                Some(_) = rejecting.join_next(), if !rejecting.is_empty() => continue,
+            };
+            match accepted {
+                Ok((stream, addr)) => {
+                        let admission = match self.limiter.admit(addr.ip()) {
+                            Ok(admission) => admission,
+                            Err(reason) => {
+                                connection_rejected(addr, reason);
+                                if rejecting.len() < MAX_REJECTING {
+                                    rejecting.spawn(reject_busy(stream, addr));
                                 }
-                                Method::POST => {
-                                    let (host, port) = match header_host.split(':').collect::<Vec<&str>>().as_slice() {
-                                        // 如果只有主机名，没有端口号
-                                        [host] => (host.to_string(), "80".to_string()),
-                                        // 如果有主机名和端口号
-                                        [host, port] => (host.to_string(), port.to_string()),
-                                        // 处理其他异常情况
-                                        _ => ("".to_string(), "80".to_string())
-                                    };
-                                    session_lock.handle_http(host, port, initial_data).await;
-                                }
-                                Method::PUT => {
-                                    let (host, port) = match header_host.split(':').collect::<Vec<&str>>().as_slice() {
-                                        // 如果只有主机名，没有端口号
-                                        [host] => (host.to_string(), "80".to_string()),
-                                        // 如果有主机名和端口号
-                                        [host, port] => (host.to_string(), port.to_string()),
-                                        // 处理其他异常情况
-                                        _ => ("".to_string(), "80".to_string())
-                                    };
-                                    session_lock.handle_http(host, port, initial_data).await;
-                                },
-                                Method::DELETE => {
-                                    let (host, port) = match header_host.split(':').collect::<Vec<&str>>().as_slice() {
-                                        // 如果只有主机名，没有端口号
-                                        [host] => (host.to_string(), "80".to_string()),
-                                        // 如果有主机名和端口号
-                                        [host, port] => (host.to_string(), port.to_string()),
-                                        // 处理其他异常情况
-                                        _ => ("".to_string(), "80".to_string())
-                                    };
-                                    session_lock.handle_http(host, port, initial_data).await;
-                                },
-                                Method::HEAD => {
-                                    let (host, port) = match header_host.split(':').collect::<Vec<&str>>().as_slice() {
-                                        // 如果只有主机名，没有端口号
-                                        [host] => (host.to_string(), "80".to_string()),
-                                        // 如果有主机名和端口号
-                                        [host, port] => (host.to_string(), port.to_string()),
-                                        // 处理其他异常情况
-                                        _ => ("".to_string(), "80".to_string())
-                                    };
-                                    session_lock.handle_http(host, port, initial_data).await;
-                                },
-                                Method::OPTIONS => {
-                                    let (host, port) = match header_host.split(':').collect::<Vec<&str>>().as_slice() {
-                                        // 如果只有主机名，没有端口号
-                                        [host] => (host.to_string(), "80".to_string()),
-                                        // 如果有主机名和端口号
-                                        [host, port] => (host.to_string(), port.to_string()),
-                                        // 处理其他异常情况
-                                        _ => ("".to_string(), "80".to_string())
-                                    };
-                                    session_lock.handle_http(host, port, initial_data).await;
-                                },
-                                Method::PATCH => {
-                                    let (host, port) = match header_host.split(':').collect::<Vec<&str>>().as_slice() {
-                                        // 如果只有主机名，没有端口号
-                                        [host] => (host.to_string(), "80".to_string()),
-                                        // 如果有主机名和端口号
-                                        [host, port] => (host.to_string(), port.to_string()),
-                                        // 处理其他异常情况
-                                        _ => ("".to_string(), "80".to_string())
-                                    };
-                                    session_lock.handle_http(host, port, initial_data).await;
-                                },
-                                Method::TRACE => {
-                                    let (host, port) = match header_host.split(':').collect::<Vec<&str>>().as_slice() {
-                                        // 如果只有主机名，没有端口号
-                                        [host] => (host.to_string(), "80".to_string()),
-                                        // 如果有主机名和端口号
-                                        [host, port] => (host.to_string(), port.to_string()),
-                                        // 处理其他异常情况
-                                        _ => ("".to_string(), "80".to_string())
-                                    };
-                                    session_lock.handle_http(host, port, initial_data).await;
-                                },
+                                continue;
                             }
-                            // After task completion, log session data
-                            println!("[Session {}] => Session completed. Session data: {:?}", session_id, session_lock);
-                    }});
-                // Await the spawned task to ensure it completes before proceeding
-                //task.await.context("[-] Failed to await session task.")?;
-                
-            }
-            Err(e) => {
-                eprintln!("[-] Failed to listener accept: {:?}", e);
+                        };
+                        tasks.spawn({
+                            let uuid = uuid::Uuid::new_v4();
+                            let session_id = u32::from_le_bytes(uuid.as_bytes()[0..4].try_into().unwrap());
+                            let session = Arc::new(Mutex::new(Session::new(session_id, stream).unwrap()));
+                            let certs = Arc::clone(&certs);
+                            let session_clone = Arc::clone(&session);
+                            let interceptors = self.interceptors.clone();
+                            let log_filter = self.log_filter.clone();
+                            let trace_propagation = self.trace_propagation;
+                            let auth = self.auth.clone();
+                            let acl = self.acl.clone();
+                            let throttle = self.throttle.clone();
+                            let faults = self.faults.clone();
+                            // 会话内的所有事件都带上这些字段, target 在读到第一个请求后补上
+                            let span = info_span!("session", session_id, client_addr = %addr, target = field::Empty, user = field::Empty);
+                            async move {
+                                let mut session_lock = session_clone.lock().await;
+                                let _permit = match admission {
+                                    Admission::Ready(permit) => permit,
+                                    Admission::Queued(ticket) => match ticket.wait().await {
+                                        Ok(permit) => permit,
+                                        Err(reason) => {
+                                            connection_rejected(addr, reason);
+                                            let _ = tokio::time::timeout(REJECT_READ_TIMEOUT, session_lock.session_connect(addr)).await;
+                                            session_lock.respond(limit::busy_response()).await;
+                                            return;
+                                        }
+                                    },
+                                };
+                                let _active = metrics::metrics().session_started();
+                                session_lock.set_interceptors(interceptors);
+                                session_lock.log_filter = log_filter;
+                                session_lock.trace_propagation = trace_propagation;
+                                session_lock.acl = acl;
+                                session_lock.throttle = throttle;
+                                session_lock.faults = faults;
+                                if let Err(e) = session_lock.session_connect(addr).await {
+                                    warn!(error = ?e, "[-] Failed to parse initial request");
+                                    session_lock.reject("400 Bad Request").await;
+                                    return;
+                                }
+                                let method = session_lock.request.method.clone();
+                                let url = session_lock.request.url.clone();
+                                if let Some((host, port)) = session_lock.request.target() {
+                                    Span::current().record("target", format!("{}:{}", host, port));
+                                }
+                                if !session_lock.check_acl().await {
+                                    return;
+                                }
+                                if let Some(auth) = &auth {
+                                    match auth.authenticate(&mut session_lock.request).await {
+                                        Ok(user) => {
+                                            Span::current().record("user", user.as_str());
+                                            session_lock.user = Some(user);
+                                        }
+                                        Err(challenge) => {
+                                            debug!("[-] Proxy authentication required");
+                                            session_lock.respond(challenge).await;
+                                            return;
+                                        }
+                                    }
+                                }
+                                info!(method = method.as_str(), %url, "[+] New session");
+                                if !session_lock.run_connect_hooks().await {
+                                    info!("[+] Session ended by interceptor");
+                                    return;
+                                }
+                                match method {
+                                    Method::CONNECT => {
+                                        match session_lock.request.target() {
+                                            Some((host, port)) => {
+                                                session_lock.handle_https(host, port, certs).await;
+                                            }
+                                            None => {
+                                                session_lock.reject("400 Bad Request").await;
+                                            }
+                                        }
+                                    }
+                                    _ => {
+                                        session_lock.forward_http().await;
+                                    }
+                                }
+                                debug!(session = ?*session_lock, "[+] Session completed");
+                        }.instrument(span)});
+                }
+                Err(e) => {
+                    error!(error = ?e, "[-] Failed to listener accept");
+                }
             }
         }
+
+        Ok(())
     }
+}
+
+async fn entry() -> Result<(), anyhow::Error> {
+    entry_in(Path::new("."), 9990, "127.0.0.1:9991", "127.0.0.1:9992").await
+}
 
-    Ok(())
+/// 配置文件和 sessions.db 都在 `dir` 下, 代理监听 `port`, 看板和管理接口监听给定地址
+async fn entry_in(dir: &Path, port: u32, dashboard_addr: &str, admin_addr: &str) -> Result<(), anyhow::Error> {
+    // test code
+    // 已经安装过全局 subscriber 时(如多个测试)沿用原来的
+    let _tracing = telemetry::init_tracing(&LogConfig::from_env()?).ok();
+    let file = |name: &str| dir.join(name);
+    let mut proxy = Proxy::new("127.0.0.1", port);
+    // PROXY_TRACE_PROPAGATION=continue|inject 时转发 traceparent
+    if let Some(propagation) = std::env::var("PROXY_TRACE_PROPAGATION").ok().filter(|v| !v.is_empty()) {
+        proxy.set_trace_propagation(propagation.parse()?);
+    }
+    // PROXY_MAX_CONNECTIONS, PROXY_MAX_PER_CLIENT 调整并发连接限制
+    let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+    let mut limits = ConnectionLimits::default();
+    if let Some(max) = var("PROXY_MAX_CONNECTIONS") {
+        limits.max_connections = max.parse().context("[-] Invalid PROXY_MAX_CONNECTIONS")?;
+    }
+    limits.max_per_client = var("PROXY_MAX_PER_CLIENT").map(|max| max.parse()).transpose().context("[-] Invalid PROXY_MAX_PER_CLIENT")?;
+    proxy.set_limits(limits);
+    // 目录下有 throttle.yaml 时限速, PROXY_NETWORK=3g 等模拟慢速网络
+    let mut throttle = if file("throttle.yaml").exists() { ThrottleConfig::load(file("throttle.yaml"))? } else { ThrottleConfig::default() };
+    if let Some(preset) = var("PROXY_NETWORK") {
+        throttle.network.preset = Some(preset.parse()?);
+    }
+    if !throttle.is_empty() {
+        proxy.set_throttle(Throttle::new(throttle));
+    }
+    // 目录下有 htpasswd 时要求代理认证
+    if file("htpasswd").exists() {
+        proxy.set_auth(ProxyAuth::new("proxy", HtpasswdFile::load(file("htpasswd"))?));
+    }
+    // 目录下有 acl.yaml 时限制客户端和目标
+    if file("acl.yaml").exists() {
+        proxy.set_acl(Acl::load(file("acl.yaml"))?);
+    }
+    // 目录下有 faults.yaml 时按规则注入故障
+    if file("faults.yaml").exists() {
+        proxy.set_faults(FaultInjector::load(file("faults.yaml"))?);
+    }
+    // 目录下有 rules.yaml 时加载改写规则, 之后可以通过管理接口增删
+    let rules = if file("rules.yaml").exists() { RuleEngine::load(file("rules.yaml"))? } else { RuleEngine::default() };
+    proxy.add_interceptor(rules.clone());
+    if file("map_local.yaml").exists() {
+        proxy.add_interceptor(MapLocal::load(file("map_local.yaml"))?);
+    }
+    if file("map_remote.yaml").exists() {
+        proxy.add_interceptor(MapRemote::load(file("map_remote.yaml"))?);
+    }
+    let breakpoints = Breakpoints::default();
+    proxy.add_interceptor(breakpoints.clone());
+    // 所有事务写入 sessions.db
+    let store = Store::open(file("sessions.db"), StoreOptions::default())?;
+    proxy.add_interceptor(store.clone());
+    // 最近的事务保存在内存中, 通过看板 (默认 http://127.0.0.1:9991/) 查看
+    let flows = FlowLog::default();
+    proxy.add_interceptor(flows.clone());
+    // 管理接口和看板的写操作共用令牌, 取自 PROXY_ADMIN_TOKEN, 未设置时随机生成
+    let token = std::env::var("PROXY_ADMIN_TOKEN").unwrap_or_else(|_| {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        info!(%token, "[+] Admin API token");
+        token
+    });
+    // 看板的重放和代理转发使用同一份 ACL
+    let mut dashboard = Dashboard::new(token.clone(), flows.clone());
+    if let Some(acl) = &proxy.acl {
+        dashboard = dashboard.with_acl(acl.clone());
+    }
+    tokio::spawn({
+        let dashboard_addr = dashboard_addr.to_string();
+        async move {
+            if let Err(e) = dashboard.run(&dashboard_addr).await {
+                error!(error = ?e, "[-] Dashboard stopped");
+            }
+        }
+    });
+    let admin = AdminApi::new(token, flows).with_breakpoints(breakpoints).with_rules(rules).with_store(store);
+    let admin_addr = admin_addr.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = admin.run(&admin_addr).await {
+            error!(error = ?e, "[-] Admin API stopped");
+        }
+    });
+    proxy.run().await
 }
 
 #[cfg(test)]
@@ -166,6 +387,11 @@ mod test {
     // test模块测试
     #[tokio::test]
     async fn task_test_run() {
-        let _ = entry().await.context("[-] Failed to entry.");
+        // 在临时目录和随机端口上启动, 会一直accept下去, 只验证能正常启动
+        let dir = std::env::temp_dir().join(format!("proxy-entry-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(1), entry_in(&dir, 0, "127.0.0.1:0", "127.0.0.1:0")).await;
+        assert!(dir.join("sessions.db").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
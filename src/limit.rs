@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, TryAcquireError};
+
+use crate::metrics::metrics;
+use crate::prelude::*;
+
+/// 并发连接限制
+#[derive(Debug,Clone)]
+pub struct ConnectionLimits {
+    /// 同时处理的连接总数
+    pub max_connections: usize,
+    /// 每个客户端 IP 同时处理的连接数, None 不限制
+    pub max_per_client: Option<usize>,
+    /// 达到上限后最多排队等待的连接数, 为 0 时立即拒绝
+    pub max_queue: usize,
+    /// 排队等待的最长时间
+    pub queue_timeout: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: MAX_CONCURRENT_REQUESTS,
+            max_per_client: None,
+            max_queue: MAX_CONCURRENT_REQUESTS,
+            queue_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// 拒绝连接的原因, 代理以 503 回复
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Rejected {
+    QueueFull,
+    Timeout,
+}
+
+impl Rejected {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejected::QueueFull => "queue_full",
+            Rejected::Timeout => "timeout",
+        }
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 按 `ConnectionLimits` 发放连接许可, 克隆后共享同一份计数
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    global: Arc<Semaphore>,
+    clients: Arc<Mutex<HashMap<IpAddr, Arc<Semaphore>>>>,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for ConnectionLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionLimiter").field("limits", &self.limits).field("available", &self.global.available_permits()).finish()
+    }
+}
+
+impl Default for ConnectionLimiter {
+    fn default() -> Self {
+        Self::new(ConnectionLimits::default())
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        ConnectionLimiter {
+            global: Arc::new(Semaphore::new(limits.max_connections)),
+            limits,
+            clients: Arc::default(),
+            waiting: Arc::default(),
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    /// 正在排队的连接数
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// 取得许可, 许可释放前该连接计入限制. 没有空闲名额时排队等待
+    pub async fn acquire(&self, ip: IpAddr) -> Result<ConnectionPermit, Rejected> {
+        match self.admit(ip)? {
+            Admission::Ready(permit) => Ok(permit),
+            Admission::Queued(ticket) => ticket.wait().await,
+        }
+    }
+
+    /// 不等待地决定如何处理新连接, 在接受连接的循环中调用: 有空闲名额时直接发放许可,
+    /// 否则占一个排队位置, 排队也满时拒绝. 先取客户端的许可再取全局的,
+    /// 单个客户端排队时不占用全局名额
+    pub fn admit(&self, ip: IpAddr) -> Result<Admission, Rejected> {
+        let mut permit = ConnectionPermit { global: None, client: None, ip, clients: Arc::clone(&self.clients) };
+        let semaphore = self
+            .limits
+            .max_per_client
+            .map(|max| Arc::clone(self.clients.lock().unwrap().entry(ip).or_insert_with(|| Arc::new(Semaphore::new(max)))));
+        permit.client = match &semaphore {
+            Some(semaphore) => try_take(semaphore),
+            None => None,
+        };
+        if semaphore.is_none() || permit.client.is_some() {
+            permit.global = try_take(&self.global);
+            if permit.global.is_some() {
+                return Ok(Admission::Ready(permit));
+            }
+        }
+        if self.waiting.fetch_add(1, Ordering::Relaxed) >= self.limits.max_queue {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            // permit 释放时清理表项, 先放掉这里的引用
+            drop(semaphore);
+            return Err(Rejected::QueueFull);
+        }
+        Ok(Admission::Queued(Ticket {
+            _queued: Queued::new(Arc::clone(&self.waiting)),
+            semaphore,
+            global: Arc::clone(&self.global),
+            timeout: self.limits.queue_timeout,
+            permit,
+        }))
+    }
+}
+
+fn try_take(semaphore: &Arc<Semaphore>) -> Option<OwnedSemaphorePermit> {
+    match Arc::clone(semaphore).try_acquire_owned() {
+        Ok(permit) => Some(permit),
+        Err(TryAcquireError::Closed) => unreachable!("semaphore is never closed"),
+        Err(TryAcquireError::NoPermits) => None,
+    }
+}
+
+/// 见 [`ConnectionLimiter::admit`]
+pub enum Admission {
+    Ready(ConnectionPermit),
+    Queued(Ticket),
+}
+
+/// 排队位置, 丢弃时(包括等待被取消)让出位置
+pub struct Ticket {
+    _queued: Queued,
+    /// 在 permit 之前释放, permit 释放时才能按引用计数清理表项
+    semaphore: Option<Arc<Semaphore>>,
+    global: Arc<Semaphore>,
+    timeout: Duration,
+    permit: ConnectionPermit,
+}
+
+impl Ticket {
+    /// 等到名额或者超时
+    pub async fn wait(mut self) -> Result<ConnectionPermit, Rejected> {
+        let acquire = async {
+            if let (Some(semaphore), None) = (&self.semaphore, &self.permit.client) {
+                self.permit.client = Some(Arc::clone(semaphore).acquire_owned().await.expect("semaphore is never closed"));
+            }
+            self.permit.global = Some(Arc::clone(&self.global).acquire_owned().await.expect("semaphore is never closed"));
+        };
+        match tokio::time::timeout(self.timeout, acquire).await {
+            Ok(()) => Ok(self.permit),
+            Err(_) => Err(Rejected::Timeout),
+        }
+    }
+}
+
+/// 排队计数, 等待被取消(如任务被中止)时也能减回去
+struct Queued(Arc<AtomicUsize>);
+
+impl Queued {
+    /// 调用前 `waiting` 已经加一
+    fn new(waiting: Arc<AtomicUsize>) -> Self {
+        metrics().queued_connections.inc();
+        Queued(waiting)
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        metrics().queued_connections.dec();
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 见 [`ConnectionLimiter::acquire`]
+pub struct ConnectionPermit {
+    global: Option<OwnedSemaphorePermit>,
+    client: Option<OwnedSemaphorePermit>,
+    ip: IpAddr,
+    clients: Arc<Mutex<HashMap<IpAddr, Arc<Semaphore>>>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.global.take();
+        self.client.take();
+        forget_idle_client(&self.clients, self.ip);
+    }
+}
+
+/// 没有其他连接持有或等待这个客户端的信号量时删除表项, 避免表无限增长
+fn forget_idle_client(clients: &Mutex<HashMap<IpAddr, Arc<Semaphore>>>, ip: IpAddr) {
+    let mut clients = clients.lock().unwrap();
+    if clients.get(&ip).is_some_and(|s| Arc::strong_count(s) == 1) {
+        clients.remove(&ip);
+    }
+}
+
+/// 达到上限时回复给客户端的响应
+pub fn busy_response() -> Response {
+    Response::new(503).with_header("Retry-After", "1").with_header("Content-Length", "0")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn per_client_and_queue() {
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            max_connections: 2,
+            max_per_client: Some(1),
+            max_queue: 1,
+            queue_timeout: Duration::from_millis(50),
+        });
+        let (a, b, c): (IpAddr, IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap());
+        let first = limiter.acquire(a).await.unwrap();
+        // 同一客户端的第二个连接排队直到超时
+        assert_eq!(limiter.acquire(a).await.err(), Some(Rejected::Timeout));
+        let second = limiter.acquire(b).await.unwrap();
+
+        // 全局名额用完, 一个排队, 再来的立即拒绝
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(c).await.is_ok() }
+        });
+        while limiter.waiting() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limiter.acquire("10.0.0.4".parse().unwrap()).await.err(), Some(Rejected::QueueFull));
+        drop(first);
+        assert!(queued.await.unwrap());
+        drop(second);
+        assert!(limiter.clients.lock().unwrap().is_empty());
+
+        // 接受连接时不等待: 直接受理, 占排队位置, 或者拒绝
+        let limiter = ConnectionLimiter::new(ConnectionLimits { max_connections: 1, max_queue: 1, ..limiter.limits().clone() });
+        let Ok(Admission::Ready(first)) = limiter.admit(a) else { panic!("expected a permit") };
+        let Ok(Admission::Queued(ticket)) = limiter.admit(b) else { panic!("expected a queue slot") };
+        assert_eq!(limiter.admit(c).err(), Some(Rejected::QueueFull));
+        drop(first);
+        let third = ticket.wait().await.unwrap();
+        assert_eq!(limiter.waiting(), 0);
+        drop(third);
+        assert!(limiter.clients.lock().unwrap().is_empty());
+    }
+}
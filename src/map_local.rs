@@ -0,0 +1,180 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::intercept::{InterceptContext, Interceptor, Verdict};
+use crate::config::load_config;
+use crate::prelude::*;
+use crate::rules::status_code;
+
+/// Map Local 配置文件: YAML 或 TOML, 顶层是 `rules` 列表
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct MapLocalFile {
+    #[serde(default)]
+    pub rules: Vec<MapLocalRule>,
+}
+
+/// 一条 URL 到本地文件/目录的映射
+#[derive(Debug,Clone,Deserialize,serde::Serialize)]
+pub struct MapLocalRule {
+    /// 完整 URL(不含查询串)的通配符, 如 `https://api.example.com/v1/*`
+    pub url: String,
+    /// 本地文件或目录. 映射到目录时 URL 模式最后的 `*` 匹配的部分作为相对路径
+    pub path: PathBuf,
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// 覆盖或追加的响应头
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default = "crate::config::default_true")]
+    pub enabled: bool,
+}
+
+/// 用本地文件回复匹配的请求, 不会连接上游
+#[derive(Debug,Clone,Default)]
+pub struct MapLocal {
+    rules: Vec<MapLocalRule>,
+}
+
+impl MapLocal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `status` 必须是 200-599 的最终响应, 1xx 会和协议升级的处理冲突
+    pub fn add(&mut self, rule: MapLocalRule) -> Result<&mut Self, anyhow::Error> {
+        if let Some(status) = rule.status {
+            if status_code(status)? < 200 {
+                return Err(anyhow!("[-] map local status must be a final response: {}", status));
+            }
+        }
+        self.rules.push(rule);
+        Ok(self)
+    }
+
+    pub fn rules(&self) -> &[MapLocalRule] {
+        &self.rules
+    }
+
+    /// 按扩展名读取 `.yaml`/`.yml` 或 `.toml` 配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let file: MapLocalFile = load_config(path, "map local")?;
+        let mut map = MapLocal::new();
+        for rule in file.rules {
+            map.add(rule)?;
+        }
+        Ok(map)
+    }
+
+    /// 找到第一条匹配的规则并生成响应, 没有匹配时返回None
+    pub async fn respond(&self, req: &Request) -> Option<Response> {
+        let url = request_url(req);
+        let (rule, file) = self.rules.iter().filter(|r| r.enabled).find_map(|r| Some((r, resolve(r, &url)?)))?;
+        let mut resp = match tokio::fs::read(&file).await {
+            Ok(body) => {
+                let mime = mime_guess::from_path(&file).first_or_octet_stream();
+                Response::new(200).with_header("Content-Type", mime.essence_str().as_bytes()).with_body(body)
+            }
+            Err(e) => {
+                warn!(%url, file = %file.display(), error = %e, "[-] Map local file unreadable");
+                Response::new(404)
+            }
+        };
+        if let Some(status) = rule.status {
+            resp.status = status;
+            resp.reason = reason_phrase(status).to_string();
+        }
+        for (name, value) in &rule.headers {
+            resp.headers.insert(name, value.as_bytes());
+        }
+        Some(resp)
+    }
+}
+
+#[async_trait::async_trait]
+impl Interceptor for MapLocal {
+    async fn on_request(&self, _ctx: &InterceptContext, req: &mut Request) -> Verdict {
+        match self.respond(req).await {
+            Some(resp) => Verdict::Respond(resp),
+            None => Verdict::Continue,
+        }
+    }
+}
+
+/// 用于匹配的完整 URL, 不含查询串
+fn request_url(req: &Request) -> String {
+    let scheme = req.uri.scheme.as_deref().unwrap_or("http");
+    let authority = match &req.uri.authority {
+        Some(authority) => authority.host_header(),
+        None => req.header("host").unwrap_or_default().to_string(),
+    };
+    format!("{}://{}{}", scheme, authority, req.uri.path)
+}
+
+/// 规则匹配时返回要读取的本地文件
+fn resolve(rule: &MapLocalRule, url: &str) -> Option<PathBuf> {
+    if !wildcard_match(&rule.url, url) {
+        return None;
+    }
+    if !rule.path.is_dir() {
+        return Some(rule.path.clone());
+    }
+    let rel = Path::new(wildcard_tail(&rule.url, url)?.trim_start_matches('/'));
+    // 不允许跳出映射的目录
+    if rel.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    let file = rule.path.join(rel);
+    Some(if file.is_dir() { file.join("index.html") } else { file })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_files_and_directories() {
+        let dir = std::env::temp_dir().join(format!("map_local_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("static")).unwrap();
+        std::fs::write(dir.join("me.json"), b"{\"id\":1}").unwrap();
+        std::fs::write(dir.join("static/app.js"), b"let a;").unwrap();
+        std::fs::write(dir.join("static/index.html"), b"<p>").unwrap();
+
+        let mut map = MapLocal::new();
+        map.add(MapLocalRule {
+            url: "https://api.example.com/v1/me".to_string(),
+            path: dir.join("me.json"),
+            status: Some(201),
+            headers: vec![("X-Mapped".to_string(), "1".to_string())],
+            enabled: true,
+        })
+        .unwrap();
+        let assets = MapLocalRule { url: "http://*.example.com/assets/*".to_string(), path: dir.join("static"), status: None, headers: Vec::new(), enabled: true };
+        for status in [101, 600] {
+            assert!(map.add(MapLocalRule { status: Some(status), ..assets.clone() }).is_err(), "{}", status);
+        }
+        map.add(assets).unwrap();
+
+        let req = Request::from_string("GET https://api.example.com/v1/me?x=1 HTTP/1.1\r\n\r\n").unwrap();
+        let resp = map.respond(&req).await.unwrap();
+        assert_eq!((resp.status, resp.content_type()), (201, Some("application/json")));
+        assert_eq!(resp.headers.get_str("x-mapped"), Some("1"));
+        assert_eq!(&resp.body[..], b"{\"id\":1}");
+
+        let req = Request::from_string("GET http://cdn.example.com/assets/app.js HTTP/1.1\r\n\r\n").unwrap();
+        let resp = map.respond(&req).await.unwrap();
+        assert_eq!(resp.content_type(), Some("text/javascript"));
+        let req = Request::from_string("GET http://cdn.example.com/assets/ HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(&map.respond(&req).await.unwrap().body[..], b"<p>");
+        let req = Request::from_string("GET http://cdn.example.com/assets/missing.css HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(map.respond(&req).await.unwrap().status, 404);
+        let req = Request::from_string("GET http://cdn.example.com/assets/../me.json HTTP/1.1\r\n\r\n").unwrap();
+        assert!(map.respond(&req).await.is_none());
+        let req = Request::from_string("GET http://other.com/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(map.respond(&req).await.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
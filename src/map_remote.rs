@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::intercept::{InterceptContext, Interceptor, Verdict};
+use crate::config::load_config;
+use crate::prelude::*;
+
+/// Map Remote 配置文件: YAML 或 TOML, 顶层是 `rules` 列表
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct MapRemoteFile {
+    #[serde(default)]
+    pub rules: Vec<MapRemoteRule>,
+}
+
+/// 把匹配 `from` 的请求转发到 `to`
+#[derive(Debug,Clone,Deserialize,serde::Serialize)]
+pub struct MapRemoteRule {
+    /// 完整 URL(不含查询串)的通配符, 如 `https://api.prod.example/*`
+    pub from: String,
+    /// 新的目标, 如 `http://localhost:8080/*`. 两边都以 `*` 结尾时 `from` 中 `*` 匹配的部分接在后面
+    pub to: String,
+    #[serde(default = "crate::config::default_true")]
+    pub enabled: bool,
+}
+
+/// 改写请求的上游地址, 客户端看到的仍然是原来的主机
+#[derive(Debug,Clone,Default)]
+pub struct MapRemote {
+    rules: Vec<MapRemoteRule>,
+}
+
+impl MapRemote {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `to` 必须是绝对 URL
+    pub fn add(&mut self, rule: MapRemoteRule) -> Result<&mut Self, anyhow::Error> {
+        let to = Uri::parse(rule.to.trim_end_matches('*'))?;
+        if to.form != TargetForm::Absolute {
+            return Err(anyhow!("[-] map remote target must be an absolute URL: {}", rule.to));
+        }
+        self.rules.push(rule);
+        Ok(self)
+    }
+
+    pub fn rules(&self) -> &[MapRemoteRule] {
+        &self.rules
+    }
+
+    /// 按扩展名读取 `.yaml`/`.yml` 或 `.toml` 配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let file: MapRemoteFile = load_config(path, "map remote")?;
+        let mut map = MapRemote::new();
+        for rule in file.rules {
+            map.add(rule)?;
+        }
+        Ok(map)
+    }
+
+    /// 按第一条匹配的规则改写请求目标和 `Host`, 返回是否改写.
+    /// 上游的主机, 端口和是否使用 TLS 都由改写后的 URI 决定
+    pub fn rewrite(&self, req: &mut Request) -> bool {
+        let Some(scheme) = req.uri.scheme.clone() else {
+            return false;
+        };
+        let Some(authority) = req.uri.authority.clone() else {
+            return false;
+        };
+        let url = format!("{}://{}{}", scheme, authority, req.uri.path);
+        let Some(target) = self.rules.iter().filter(|r| r.enabled).find_map(|r| map_url(r, &url)) else {
+            return false;
+        };
+        let Ok(mut uri) = Uri::parse(&target) else {
+            return false;
+        };
+        if uri.query.is_none() {
+            uri.query = req.uri.query.clone();
+        }
+        info!(from = %url, to = %uri, "[+] Map remote");
+        if let Some(authority) = &uri.authority {
+            req.host = authority.host_header();
+            req.headers.insert("Host", req.host.as_bytes());
+        }
+        // `url` 是原始的请求目标, 保持原来的形式 (解密后的请求是 origin-form)
+        req.url = if req.url.starts_with('/') { uri.origin_form() } else { uri.to_string() };
+        req.uri = uri;
+        true
+    }
+}
+
+#[async_trait::async_trait]
+impl Interceptor for MapRemote {
+    async fn on_request(&self, _ctx: &InterceptContext, req: &mut Request) -> Verdict {
+        self.rewrite(req);
+        Verdict::Continue
+    }
+}
+
+fn map_url(rule: &MapRemoteRule, url: &str) -> Option<String> {
+    if !wildcard_match(&rule.from, url) {
+        return None;
+    }
+    match (wildcard_tail(&rule.from, url), rule.to.strip_suffix('*')) {
+        (Some(tail), Some(base)) => Some(format!("{}{}", base, tail)),
+        _ => Some(rule.to.trim_end_matches('*').to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rewrites_target_and_host() {
+        let mut map = MapRemote::new();
+        map.add(MapRemoteRule { from: "https://api.prod.example/*".to_string(), to: "http://localhost:8080/*".to_string(), enabled: true }).unwrap();
+        map.add(MapRemoteRule { from: "http://old.example/login".to_string(), to: "https://new.example/auth?v=2".to_string(), enabled: true }).unwrap();
+        assert!(map.add(MapRemoteRule { from: "*".to_string(), to: "/relative".to_string(), enabled: true }).is_err());
+
+        let mut req = Request::from_string("GET https://api.prod.example/v1/users?page=2 HTTP/1.1\r\nHost: api.prod.example\r\n\r\n").unwrap();
+        assert!(map.rewrite(&mut req));
+        assert_eq!(req.target(), Some(("localhost".to_string(), 8080)));
+        assert_eq!(req.uri.scheme.as_deref(), Some("http"));
+        assert_eq!(req.uri.origin_form(), "/v1/users?page=2");
+        assert_eq!(req.header("host"), Some("localhost:8080"));
+        assert_eq!(req.url, "http://localhost:8080/v1/users?page=2");
+
+        let mut req = Request::from_string("POST http://old.example/login?x=1 HTTP/1.1\r\n\r\n").unwrap();
+        assert!(map.rewrite(&mut req));
+        assert_eq!(req.target(), Some(("new.example".to_string(), 443)));
+        assert_eq!(req.uri.origin_form(), "/auth?v=2");
+        assert_eq!(req.url, "https://new.example/auth?v=2");
+
+        // 解密后的 HTTPS 请求
+        let mut req = Request::from_string("GET /v2/items HTTP/1.1\r\nHost: api.prod.example\r\n\r\n").unwrap();
+        req.uri.scheme = Some("https".to_string());
+        req.uri.authority = Some(Authority { host: "api.prod.example".to_string(), port: None });
+        assert!(map.rewrite(&mut req));
+        assert_eq!((req.url.as_str(), req.target()), ("/v2/items", Some(("localhost".to_string(), 8080))));
+
+        let mut req = Request::from_string("GET http://other.example/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!map.rewrite(&mut req));
+    }
+}
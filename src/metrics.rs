@@ -0,0 +1,240 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::task::{Context, Poll};
+
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::copy::Direction;
+use crate::prelude::*;
+
+/// 延迟直方图的桶, 单位秒
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics::new().expect("[-] Failed to register metrics"));
+
+/// 代理的 Prometheus 指标, 进程内全局唯一, 通过 [`metrics()`] 访问
+pub struct Metrics {
+    registry: Registry,
+    pub active_sessions: IntGauge,
+    pub connections: IntCounter,
+    /// 等待连接许可的连接数
+    pub queued_connections: IntGauge,
+    /// 超过并发限制被拒绝的连接, `reason`
+    pub rejected_connections: IntCounterVec,
+    /// `direction`: request 为客户端发来的字节, response 为写回客户端的字节
+    pub bytes: IntCounterVec,
+    /// `method`, `status` (如 2xx)
+    pub requests: IntCounterVec,
+    /// `side` (client/upstream), `reason`
+    pub tls_handshake_failures: IntCounterVec,
+    /// `reason`
+    pub upstream_connect_errors: IntCounterVec,
+    pub cert_cache_hits: IntCounter,
+    pub cert_cache_misses: IntCounter,
+    /// `scope` (client/destination)
+    pub acl_denials: IntCounterVec,
+    /// 超过请求速率被拒绝的请求, `scope` (client/host)
+    pub throttled_requests: IntCounterVec,
+    /// 注入的故障, `fault`
+    pub injected_faults: IntCounterVec,
+    /// 从读完请求头到响应写回客户端
+    pub request_duration: Histogram,
+    pub upstream_connect_duration: Histogram,
+    /// `side`
+    pub tls_handshake_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+        let latency = |name: &str, help: &str| HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+        let metrics = Metrics {
+            active_sessions: IntGauge::new("proxy_active_sessions", "Client connections currently being served")?,
+            connections: IntCounter::new("proxy_connections_total", "Accepted client connections")?,
+            queued_connections: IntGauge::new("proxy_queued_connections", "Client connections waiting for a concurrency slot")?,
+            rejected_connections: IntCounterVec::new(
+                Opts::new("proxy_rejected_connections_total", "Client connections rejected by the concurrency limit"),
+                &["reason"],
+            )?,
+            bytes: IntCounterVec::new(Opts::new("proxy_bytes_total", "Bytes exchanged with clients"), &["direction"])?,
+            requests: IntCounterVec::new(Opts::new("proxy_requests_total", "Completed HTTP exchanges"), &["method", "status"])?,
+            tls_handshake_failures: IntCounterVec::new(
+                Opts::new("proxy_tls_handshake_failures_total", "Failed TLS handshakes"),
+                &["side", "reason"],
+            )?,
+            upstream_connect_errors: IntCounterVec::new(
+                Opts::new("proxy_upstream_connect_errors_total", "Failed TCP connections to upstream servers"),
+                &["reason"],
+            )?,
+            cert_cache_hits: IntCounter::new("proxy_cert_cache_hits_total", "Leaf certificates served from the cache")?,
+            cert_cache_misses: IntCounter::new("proxy_cert_cache_misses_total", "Leaf certificates signed on demand")?,
+            acl_denials: IntCounterVec::new(Opts::new("proxy_acl_denials_total", "Connections and requests rejected by the ACL"), &["scope"])?,
+            throttled_requests: IntCounterVec::new(Opts::new("proxy_throttled_requests_total", "Requests rejected by the rate limit"), &["scope"])?,
+            injected_faults: IntCounterVec::new(Opts::new("proxy_injected_faults_total", "Faults injected into proxied traffic"), &["fault"])?,
+            request_duration: Histogram::with_opts(latency("proxy_request_duration_seconds", "Time from request head to response written"))?,
+            upstream_connect_duration: Histogram::with_opts(latency("proxy_upstream_connect_duration_seconds", "TCP connect time to upstream"))?,
+            tls_handshake_duration: HistogramVec::new(latency("proxy_tls_handshake_duration_seconds", "Successful TLS handshake time"), &["side"])?,
+            registry,
+        };
+        let r = &metrics.registry;
+        r.register(Box::new(metrics.active_sessions.clone()))?;
+        r.register(Box::new(metrics.connections.clone()))?;
+        r.register(Box::new(metrics.queued_connections.clone()))?;
+        r.register(Box::new(metrics.rejected_connections.clone()))?;
+        r.register(Box::new(metrics.bytes.clone()))?;
+        r.register(Box::new(metrics.requests.clone()))?;
+        r.register(Box::new(metrics.tls_handshake_failures.clone()))?;
+        r.register(Box::new(metrics.upstream_connect_errors.clone()))?;
+        r.register(Box::new(metrics.cert_cache_hits.clone()))?;
+        r.register(Box::new(metrics.cert_cache_misses.clone()))?;
+        r.register(Box::new(metrics.acl_denials.clone()))?;
+        r.register(Box::new(metrics.throttled_requests.clone()))?;
+        r.register(Box::new(metrics.injected_faults.clone()))?;
+        r.register(Box::new(metrics.request_duration.clone()))?;
+        r.register(Box::new(metrics.upstream_connect_duration.clone()))?;
+        r.register(Box::new(metrics.tls_handshake_duration.clone()))?;
+        Ok(metrics)
+    }
+
+    /// 新的客户端连接, 返回的守卫释放时活动会话数减一
+    pub fn session_started(&self) -> SessionGuard {
+        self.connections.inc();
+        self.active_sessions.inc();
+        SessionGuard(())
+    }
+
+    pub fn add_bytes(&self, direction: Direction, n: usize) {
+        let label = match direction {
+            Direction::Request => "request",
+            Direction::Response => "response",
+        };
+        self.bytes.with_label_values(&[label]).inc_by(n as u64);
+    }
+
+    pub fn observe_exchange(&self, req: &Request, resp: &Response) {
+        // 扩展方法不作为标签, 避免基数失控
+        let method = match &req.method {
+            Method::Extension(_) => "OTHER",
+            method => method.as_str(),
+        };
+        let status = format!("{}xx", resp.status / 100);
+        self.requests.with_label_values(&[method, &status]).inc();
+        if let Some(elapsed) = req.received_at.and_then(|t| t.elapsed().ok()) {
+            self.request_duration.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    pub fn tls_failed(&self, side: &str, err: &io::Error) {
+        self.tls_handshake_failures.with_label_values(&[side, &tls_failure_reason(err)]).inc();
+    }
+
+    /// Prometheus 文本格式
+    pub fn render(&self) -> String {
+        let mut out = Vec::new();
+        // 只有写入失败时出错, 写入 Vec 不会失败
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut out);
+        String::from_utf8_lossy(&out).into_owned()
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// 见 [`Metrics::session_started`]
+pub struct SessionGuard(());
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        metrics().active_sessions.dec();
+    }
+}
+
+/// 握手失败的原因: rustls 错误取变体名(收到的告警带上告警类型), 其他取 io 错误类型
+fn tls_failure_reason(err: &io::Error) -> String {
+    match err.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        Some(rustls::Error::AlertReceived(alert)) => format!("AlertReceived({:?})", alert),
+        Some(e) => {
+            let debug = format!("{:?}", e);
+            debug.split(['(', ' ', '{']).next().unwrap_or_default().to_string()
+        }
+        None => format!("{:?}", err.kind()),
+    }
+}
+
+/// 统计客户端连接上读写字节数的包装
+pub struct Metered<S> {
+    inner: S,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S) -> Self {
+        Metered { inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            metrics().add_bytes(Direction::Request, buf.filled().len() - before);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            metrics().add_bytes(Direction::Response, n);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_and_renders() {
+        let m = metrics();
+        let before = m.bytes.with_label_values(&["request"]).get();
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut metered = Metered::new(a);
+        b.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        metered.read_exact(&mut buf).await.unwrap();
+        metered.write_all(b"hi").await.unwrap();
+        assert!(m.bytes.with_label_values(&["request"]).get() >= before + 5);
+
+        {
+            let _guard = m.session_started();
+            assert!(m.active_sessions.get() >= 1);
+        }
+        let mut req = Request::from_string("PROPFIND /x HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        req.received_at = Some(time::SystemTime::now());
+        m.observe_exchange(&req, &Response::new(207));
+
+        let err = io::Error::new(io::ErrorKind::InvalidData, rustls::Error::AlertReceived(rustls::AlertDescription::UnknownCA));
+        m.tls_failed("client", &err);
+        let text = m.render();
+        assert!(text.contains("proxy_requests_total{method=\"OTHER\",status=\"2xx\"}"));
+        assert!(text.contains("proxy_tls_handshake_failures_total{reason=\"AlertReceived(UnknownCA)\",side=\"client\"}"));
+        assert!(text.contains("proxy_request_duration_seconds_bucket"));
+    }
+}
@@ -2,10 +2,27 @@ pub use std::{io::{Read, Write}, net::TcpListener, sync::Arc, time};
 pub use rcgen::{Certificate, CertificateParams, DistinguishedName,CertifiedKey, DnType, KeyPair, SerialNumber, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_RSA_SHA256};
 pub use rustls::{pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName}, Stream};
 pub use crate::ca_cert::*;
+pub use crate::uri::*;
+pub use crate::header::*;
+pub use bytes::Bytes;
 pub use tokio::{sync::Semaphore, task::JoinSet};
 pub use std::result::Result::Ok;
 pub const MAX_CONCURRENT_REQUESTS: usize = 100;
 
+/// 逐跳头部 (RFC 9110 §7.6.1), 代理转发前必须删除.
+/// `Transfer-Encoding` 不在其中: 它由消息分帧单独处理, 见 `http1::fix_framing`.
+pub const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "upgrade",
+    "proxy-authorization",
+    "proxy-authenticate",
+];
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug,Clone,PartialEq,Eq,Hash,Default,serde::Serialize)]
 pub enum Method {
     #[default] GET,
@@ -16,11 +33,15 @@ pub enum Method {
     OPTIONS,
     CONNECT,
     PATCH,
-    TRACE
+    TRACE,
+    /// 其他方法, 如 WebDAV 的 `PROPFIND`, `MKCOL` 或 `QUERY`
+    Extension(String),
 }
 
 impl Method {
-    /// 将字符串转换为`Method`枚举
+    /// 将字符串转换为`Method`枚举, 未知但合法的token作为`Extension`保留原样,
+    /// 只有不符合 RFC 9110 token 语法时返回None
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(method:&str) -> Option<Self> {
         match method.to_uppercase().as_str() {
             "GET" => Some(Self::GET),
@@ -32,6 +53,7 @@ impl Method {
             "CONNECT" => Some(Self::CONNECT),
             "PATCH" => Some(Self::PATCH),
             "TRACE" => Some(Self::TRACE),
+            _ if is_token(method) => Some(Self::Extension(method.to_string())),
             _ => None
         }
     }
@@ -47,166 +69,466 @@ impl Method {
             Self::CONNECT => "CONNECT",
             Self::PATCH => "PATCH",
             Self::TRACE => "TRACE",
+            Self::Extension(method) => method,
         }
     }
 }
 
+/// RFC 9110 §5.6.2 中的 token
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
 #[derive(Clone,Debug,Default,serde::Serialize)]
 pub struct Request {
     pub method : Method,
     pub url : String,
+    pub uri : Uri,
     pub http_version:String,
-    pub headers:Vec<String>,
-    pub body: Vec<String>,
+    pub headers:HeaderMap,
+    /// 消息体原始字节, 已去掉分块编码
+    #[serde(serialize_with = "serialize_lossy")]
+    pub body: Bytes,
     pub host: String,
+    /// 读完请求头的时间
+    pub received_at: Option<time::SystemTime>,
 }
 
 impl Request {
 
+    /// 用方法和绝对 URL 构造请求, `Host` 取自 URL
+    pub fn new(method: Method, url: &str) -> Result<Self, anyhow::Error> {
+        let uri = Uri::parse(url)?;
+        let authority = uri.authority.clone().ok_or_else(|| anyhow::anyhow!("URL has no host: {}", url))?;
+        let mut headers = HeaderMap::new();
+        headers.insert("Host", authority.host_header());
+        Ok(Request {
+            method,
+            url: url.to_string(),
+            uri,
+            http_version: "HTTP/1.1".to_string(),
+            headers,
+            body: Bytes::new(),
+            host: authority.host_header(),
+            received_at: None,
+        })
+    }
+
+    /// 按名称(不区分大小写)查找头部的值
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get_str(name)
+    }
+
+    /// 从请求目标推导上游的主机和端口, 请求目标没有authority时才退回到 `Host` 头
+    pub fn target(&self) -> Option<(String, u16)> {
+        let authority = match &self.uri.authority {
+            Some(authority) => authority.clone(),
+            None => Authority::parse(&self.host).ok()?,
+        };
+        let port = match authority.port {
+            Some(port) => port,
+            None if self.method == Method::CONNECT => 443,
+            None => self.uri.scheme.as_deref().and_then(default_port).unwrap_or(80),
+        };
+        Some((authority.host, port))
+    }
+
+    /// 生成转发给源站的请求头: 请求目标改写为 origin-form, 删除逐跳头部,
+    /// `Host` 以请求目标中的authority为准 (RFC 9112 §3.2.2)
+    pub fn to_origin_head(&self) -> Vec<u8> {
+        let mut headers = self.headers.clone();
+        // `Connection` 中列出的头部同样是逐跳的
+        for name in headers.tokens("connection") {
+            headers.remove(&name);
+        }
+        let upgrade = headers.get("upgrade").map(<[u8]>::to_vec);
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(name);
+        }
+        if let Some(authority) = &self.uri.authority {
+            headers.insert("Host", authority.host_header());
+        }
+        // 协议升级需要保留, 升级成功后连接转为隧道
+        if let Some(proto) = upgrade {
+            headers.append("Connection", "Upgrade");
+            headers.append("Upgrade", proto);
+        }
+
+        let mut head = format!("{} {} {}\r\n", self.method.as_str(), self.uri.origin_form(), self.http_version).into_bytes();
+        headers.write_to(&mut head);
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    /// 客户端是否要求在这个请求之后关闭连接
+    pub fn wants_close(&self) -> bool {
+        let tokens = self.headers.tokens("connection");
+        let proxy_tokens = self.headers.tokens("proxy-connection");
+        if self.http_version == "HTTP/1.0" {
+            !tokens.iter().chain(&proxy_tokens).any(|t| t == "keep-alive")
+        } else {
+            tokens.iter().chain(&proxy_tokens).any(|t| t == "close")
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         serde_json::to_vec(self).expect("Failed to serialize Request")
     }
+
     // 请求数据转换解析
     pub fn from_string(raw_data:&str) -> Option<Self> {
-        let mut  lines = raw_data.lines();
-        let first_line = lines.next().unwrap_or("NULL");
-        if first_line.is_empty() {
-           return None; 
-        }
-        let split_first = first_line.split(" ").collect::<Vec<&str>>();
-        let method = split_first[0];
-        if method == Method::CONNECT.as_str() {
-            //println!("    [DEBUG] Tcp Connect Parse.");
-            let headers_vec:Vec<String> = lines.map(|line|line.to_string()).collect();
-            let pos = headers_vec.iter().position(|line|line.is_empty()).unwrap();
-            let headers = headers_vec[..pos].to_vec();
-            let host_line = headers.iter()
-                .find(|line| line.to_lowercase().starts_with("host:"))
-                .map(|line| {
-                    // 去掉"Host:"前缀并删除首尾空格
-                    line.splitn(2, ':')
-                        .nth(1)
-                        .unwrap_or("")
-                        .trim()
-                        .to_string()
-                });
-            Some(
-                Request{
-                    method :Method::from_str(method).unwrap(),
-                    url: split_first[1].to_string(),
-                    http_version:split_first[2].to_string(),
-                    headers,
-                    body:Vec::new(),
-                    host:host_line.unwrap(),
-                }
-            )            
+        Self::from_bytes(raw_data.as_bytes())
+    }
+
+    /// 从原始字节解析请求, 请求行不完整或方法不是合法token时返回None.
+    /// 数据不完整(没有空行)时全部视为头部
+    pub fn from_bytes(raw_data:&[u8]) -> Option<Self> {
+        let (head, body) = split_head(raw_data);
+        let line_end = head.iter().position(|&b| b == b'\n').unwrap_or(head.len());
+        let first_line = String::from_utf8_lossy(&head[..line_end]);
+        let mut split_first = first_line.trim_end_matches('\r').split(' ');
+        let method = Method::from_str(split_first.next()?)?;
+        let url = split_first.next()?.to_string();
+        let http_version = split_first.next()?.to_string();
+
+        let headers = HeaderMap::parse(head.get(line_end + 1..).unwrap_or_default());
+        let body = if method == Method::CONNECT {
+            Bytes::new()
         } else {
-            //println!("     [DEBUG] HTTP DATA Parse.");
-            let next_lines = lines.map(|line|line.to_string()).collect::<Vec<String>>();
-            //println!("     [DEBUG] HTTP DATA {next_lines:?}");   
-            let pos = next_lines.iter().position(|line|line.is_empty()).unwrap();
-            if (pos == next_lines.len()-1) {
-                //println!("     [DEBUG] None Body.");
-                let headers = next_lines[..pos].to_vec();
-                let body  = Vec::new();
-                let host_line = headers.iter()
-                .find(|line| line.to_lowercase().starts_with("host:"))
-                .map(|line| {
-                    // 去掉"Host:"前缀并删除首尾空格
-                    line.splitn(2, ':')
-                        .nth(1)
-                        .unwrap_or("")
-                        .trim()
-                        .to_string()
-                });
-                Some(
-                Request{
-                    method: Method::from_str(method).unwrap(),
-                    url: split_first[1].to_string(),
-                    http_version: split_first[2].to_string(),
-                    headers,
-                    body,
-                    host:host_line.unwrap(),
-                }
-                )
-
-            } else {
-                let headers = next_lines[..pos].to_vec();
-                let body  = next_lines[pos+1..].to_vec();
-                let host_line = headers.iter()
-                .find(|line| line.to_lowercase().starts_with("host:"))
-                .map(|line| {
-                    // 去掉"Host:"前缀并删除首尾空格
-                    line.splitn(2, ':')
-                        .nth(1)
-                        .unwrap_or("")
-                        .trim()
-                        .to_string()
-                });
-                Some(
-                Request{
-                    method: Method::from_str(method).unwrap(),
-                    url: split_first[1].to_string(),
-                    http_version: split_first[2].to_string(),
-                    headers,
-                    body,
-                    host:host_line.unwrap(),
-                }
-                )
+            Bytes::from(crate::http1::message_body(&headers, body).0)
+        };
+        let host = headers.get_str("host").unwrap_or_default().to_string();
+        Some(
+            Request{
+                method,
+                uri: Uri::parse(&url).unwrap_or_default(),
+                url,
+                http_version,
+                headers,
+                body,
+                host,
+                received_at: None,
             }
-        } 
+        )
     }
 }
 
-#[derive(Debug, Clone, Default,serde::Serialize)]
+#[derive(Debug, Clone, Default)]
 pub struct Response {
-    pub status_code: String,
-    pub message: String,
-    pub data: Option<String>,
+    pub http_version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: HeaderMap,
+    /// 消息体原始字节: 已去掉分块编码, 但保留 `Content-Encoding` 压缩
+    pub body: Bytes,
+    /// 解析失败或错误状态码(>=400)时的说明
     pub error: Option<String>,
+    /// 收到响应第一个字节的时间
+    pub received_at: Option<time::SystemTime>,
+    /// 响应接收完毕的时间
+    pub completed_at: Option<time::SystemTime>,
+    /// 代理转发时测得的各阶段耗时
+    pub timings: crate::timing::Timings,
+    /// 上游 TLS 连接协商的参数, 明文连接为None
+    pub tls: Option<TlsInfo>,
+}
+
+/// 一次 TLS 握手协商出的版本, 密码套件和 SNI
+#[derive(Debug,Clone,Default,PartialEq,serde::Serialize,serde::Deserialize)]
+pub struct TlsInfo {
+    /// 如 `TLSv1_3`
+    pub version: String,
+    /// 如 `TLS13_AES_128_GCM_SHA256`
+    pub cipher: String,
+    pub sni: Option<String>,
+}
+
+impl TlsInfo {
+    pub fn new(conn: &rustls::CommonState, sni: Option<&str>) -> Self {
+        TlsInfo {
+            version: conn.protocol_version().map(|v| format!("{:?}", v)).unwrap_or_default(),
+            cipher: conn.negotiated_cipher_suite().map(|s| format!("{:?}", s.suite())).unwrap_or_default(),
+            sni: sni.map(str::to_string),
+        }
+    }
 }
 
 impl Response {
+    /// 构造一个 HTTP/1.1 响应, 用于代理自己生成的回复
+    pub fn new(status: u16) -> Self {
+        Response {
+            http_version: "HTTP/1.1".to_string(),
+            status,
+            reason: reason_phrase(status).to_string(),
+            error: (status >= 400).then(|| format!("{} {}", status, reason_phrase(status))),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// 转发给客户端的状态行和头部, 删除逐跳头部; 101 保留升级相关的头部
+    pub fn to_head(&self) -> Vec<u8> {
+        let mut headers = self.headers.clone();
+        if self.status != 101 {
+            for name in headers.tokens("connection") {
+                headers.remove(&name);
+            }
+            for name in HOP_BY_HOP_HEADERS {
+                headers.remove(name);
+            }
+        }
+        let mut head = format!("{} {} {}\r\n", self.http_version, self.status, self.reason).into_bytes();
+        headers.write_to(&mut head);
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    /// 代理自己生成的响应的状态行和头部, 逐跳头部原样保留 (如 407 的 `Proxy-Authenticate`)
+    pub fn to_raw_head(&self) -> Vec<u8> {
+        let mut head = format!("{} {} {}\r\n", self.http_version, self.status, self.reason).into_bytes();
+        self.headers.write_to(&mut head);
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    /// 源站是否会在这个响应之后关闭连接
+    pub fn wants_close(&self) -> bool {
+        let tokens = self.headers.tokens("connection");
+        if self.http_version == "HTTP/1.0" {
+            !tokens.iter().any(|t| t == "keep-alive")
+        } else {
+            tokens.iter().any(|t| t == "close")
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         serde_json::to_vec(self).expect("Failed to serialize Request")
     }
+
     /// 从HTTP响应字符串解析出Response
     pub fn from_string(raw_response: &str) -> Result<Self, anyhow::Error> {
-        let mut response = Response::default();
-
-        // 将原始响应分为行，第一行通常是状态行
-        let mut lines = raw_response.lines();
-        if let Some(status_line) = lines.next() {
-            let parts: Vec<&str> = status_line.split_whitespace().collect();
-            if parts.len() >= 2 {
-                // 提取状态码
-                response.status_code = parts[1].to_string();
-                // 提取消息
-                if parts.len() > 2 {
-                    response.message = parts[2..].join(" ");
-                }
-            } else {
+        Self::from_bytes(raw_response.as_bytes())
+    }
+
+    /// 从原始字节解析出第一个最终响应, 跳过 `100 Continue` 之类的中间响应.
+    /// 按 `Content-Length` 或分块编码截取消息体, 数据被截断时保留已有部分
+    pub fn from_bytes(raw_response: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut raw = raw_response;
+        loop {
+            let (head, rest) = split_head(raw);
+            let line_end = head.iter().position(|&b| b == b'\n').unwrap_or(head.len());
+            let status_line = String::from_utf8_lossy(&head[..line_end]);
+            let mut parts = status_line.trim_end_matches('\r').splitn(3, ' ');
+            let http_version = parts.next().unwrap_or_default().to_string();
+            if !http_version.starts_with("HTTP/") {
                 return Err(anyhow::anyhow!("Invalid status line in response"));
             }
+            let status = parts.next()
+                .and_then(|code| code.parse::<u16>().ok())
+                .filter(|code| (100..1000).contains(code))
+                .ok_or_else(|| anyhow::anyhow!("Invalid status code in response"))?;
+            let reason = parts.next().unwrap_or_default().to_string();
+            let headers = HeaderMap::parse(head.get(line_end + 1..).unwrap_or_default());
+
+            // 1xx 中间响应没有消息体, 101 之后的数据已经不是HTTP
+            if (100..200).contains(&status) && status != 101 && !rest.is_empty() {
+                raw = rest;
+                continue;
+            }
+            let (body, _) = crate::http1::message_body(&headers, rest);
+            let error = (status >= 400).then(|| format!("{} {}", status, reason));
+            return Ok(Response {
+                http_version,
+                status,
+                reason,
+                headers,
+                body: Bytes::from(body),
+                error,
+                received_at: None,
+                completed_at: None,
+                timings: Default::default(),
+                tls: None,
+            });
         }
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.status)
+    }
 
-        // 合并剩余行作为数据
-        let body: Vec<&str> = lines.collect();
-        let body_text = body.join("\r\n");
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
 
-        // 如果内容非空，填充到 data 字段
-        if !body_text.is_empty() {
-            response.data = Some(body_text.to_string());
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.status)
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.status)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.status)
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get_str("content-type")
+    }
+
+    /// 按 `Content-Encoding` 解压后的消息体, 没有压缩时不复制数据.
+    /// 转发给客户端的始终是 `body` 中的原始字节
+    pub fn decoded_body(&self) -> Result<Bytes, anyhow::Error> {
+        if self.headers.tokens("content-encoding").iter().all(|e| e == "identity") {
+            return Ok(self.body.clone());
         }
+        crate::decode::decode_body(&self.headers, &self.body, crate::decode::MAX_DECODED_SIZE).map(Bytes::from)
+    }
+
+    /// 解压后消息体的文本视图, 解压失败时退回原始字节, 非UTF-8字节会被替换
+    pub fn text(&self) -> String {
+        let body = self.decoded_body().unwrap_or_else(|_| self.body.clone());
+        String::from_utf8_lossy(&body).to_string()
+    }
+
+    /// 解压后消息体的JSON视图, 不是合法JSON时返回None
+    pub fn json(&self) -> Option<serde_json::Value> {
+        serde_json::from_slice(&self.decoded_body().ok()?).ok()
+    }
+
+    /// 从收到第一个字节到接收完毕所用的时间
+    pub fn elapsed(&self) -> Option<time::Duration> {
+        self.completed_at?.duration_since(self.received_at?).ok()
+    }
+}
+
+fn serialize_lossy<S: serde::Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(body))
+}
 
-        response.error = Some(response.message.clone());
-        // 如果状态码表示错误，填充到 error 字段
-        // if response.status_code >= "400" {
-        //     response.error = Some(response.message.clone());
-        // }
+/// 常见状态码的原因短语
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
 
-        Ok(response)
+/// 序列化(日志/存储)时消息体输出解压后的文本
+impl serde::Serialize for Response {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut st = serializer.serialize_struct("Response", 10)?;
+        st.serialize_field("http_version", &self.http_version)?;
+        st.serialize_field("status", &self.status)?;
+        st.serialize_field("reason", &self.reason)?;
+        st.serialize_field("headers", &self.headers)?;
+        st.serialize_field("body", &self.text())?;
+        st.serialize_field("error", &self.error)?;
+        st.serialize_field("received_at", &self.received_at)?;
+        st.serialize_field("completed_at", &self.completed_at)?;
+        st.serialize_field("timings", &self.timings)?;
+        st.serialize_field("tls", &self.tls)?;
+        st.end()
     }
 }
 
+/// 请求头(含结尾空行)的长度
+pub fn head_len(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+/// 拆分为头部和消息体, 没有空行时全部视为头部
+pub fn split_head(data: &[u8]) -> (&[u8], &[u8]) {
+    match head_len(data) {
+        Some(len) => (&data[..len], &data[len..]),
+        None => (data, &[]),
+    }
+}
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_extension_method() {
+        let req = Request::from_string("PROPFIND /dav/ HTTP/1.1\r\nHost: example.com\r\nDepth: 1\r\n\r\n").unwrap();
+        assert_eq!(req.method, Method::Extension("PROPFIND".to_string()));
+        assert_eq!(req.method.as_str(), "PROPFIND");
+        assert_eq!(Method::from_str("get"), Some(Method::GET));
+        assert_eq!(Method::from_str("BAD(METHOD"), None);
+        assert!(Request::from_string("GET\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn parse_binary_response() {
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 503 Service Unavailable\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n2\r\n\xff\x00\r\n0\r\n\r\nHTTP/1.1 200 OK\r\n\r\n";
+        let resp = Response::from_bytes(raw).unwrap();
+        assert_eq!(resp.status, 503);
+        assert_eq!(resp.reason, "Service Unavailable");
+        assert!(resp.is_server_error());
+        assert_eq!(&resp.body[..], b"\xff\x00");
+        assert_eq!(resp.error.as_deref(), Some("503 Service Unavailable"));
+
+        let resp = Response::from_string("HTTP/1.0 204 No Content\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert!(resp.is_success() && resp.error.is_none() && resp.body.is_empty());
+        assert!(Response::from_string("garbage").is_err());
+    }
+
+    #[test]
+    fn decoded_views_keep_raw_body() {
+        use std::io::Write;
+        let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(br#"{"ok":true}"#).unwrap();
+        let gz = enc.finish().unwrap();
+        let mut raw = format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", gz.len()).into_bytes();
+        raw.extend_from_slice(&gz);
+        let resp = Response::from_bytes(&raw).unwrap();
+        assert_eq!(&resp.body[..], &gz[..]);
+        assert_eq!(resp.text(), r#"{"ok":true}"#);
+        assert_eq!(resp.json().unwrap()["ok"], true);
+    }
+
+    #[test]
+    fn origin_head_strips_hop_by_hop() {
+        let raw = "GET http://[::1]:8080/a?b=1 HTTP/1.1\r\nHost: wrong\r\nProxy-Connection: keep-alive\r\n\
+                   Proxy-Authorization: Basic eDp5\r\nConnection: X-Drop\r\nX-Drop: 1\r\nX-Keep: 2\r\n\r\n";
+        let req = Request::from_string(raw).unwrap();
+        assert_eq!(req.target(), Some(("::1".to_string(), 8080)));
+        let head = String::from_utf8(req.to_origin_head()).unwrap();
+        assert_eq!(head, "GET /a?b=1 HTTP/1.1\r\nHost: [::1]:8080\r\nX-Keep: 2\r\n\r\n");
+    }
+}
@@ -0,0 +1,328 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::acl::Acl;
+use crate::http1::{fix_framing, request_framing, Conn, Framing};
+use crate::metrics::metrics;
+use crate::prelude::*;
+use crate::timing::{ms_between, Timings};
+use crate::upstream::Upstream;
+
+/// 重放前对请求的修改, 未设置的部分保持原样
+#[derive(Debug,Clone,Default,Deserialize,serde::Serialize)]
+#[serde(default)]
+pub struct RequestEdit {
+    pub method: Option<String>,
+    /// 新的绝对 URL, `Host` 随之改变
+    pub url: Option<String>,
+    pub set_headers: Vec<(String, String)>,
+    pub remove_headers: Vec<String>,
+    pub body: Option<String>,
+}
+
+impl RequestEdit {
+    pub fn apply(&self, req: &Request) -> Result<Request, anyhow::Error> {
+        let mut req = req.clone();
+        if let Some(method) = &self.method {
+            req.method = Method::from_str(method).ok_or_else(|| anyhow!("[-] bad method: {}", method))?;
+        }
+        if let Some(url) = &self.url {
+            let uri = Uri::parse(url)?;
+            if let Some(authority) = &uri.authority {
+                req.host = authority.host_header();
+                req.headers.insert("Host", req.host.as_bytes());
+            }
+            req.url = url.clone();
+            req.uri = uri;
+        }
+        for name in &self.remove_headers {
+            req.headers.remove(name);
+        }
+        for (name, value) in &self.set_headers {
+            req.headers.insert(name, value.as_bytes());
+        }
+        if let Some(body) = &self.body {
+            req.body = Bytes::from(body.clone());
+        }
+        Ok(req)
+    }
+}
+
+/// 一次重放的结果, 原始响应(如果有)和新响应放在一起便于对比
+#[derive(Debug,Clone,serde::Serialize)]
+pub struct ReplayResult {
+    pub request: Request,
+    pub original: Option<Response>,
+    pub response: Option<Response>,
+    pub error: Option<String>,
+    pub started_at: SystemTime,
+}
+
+/// 通过新建的上游连接发送请求并读取响应. `https` 请求使用与 MITM 相同的 TLS 配置.
+/// 有 `acl` 时和代理转发一样先检查目标, 并且只连接检查过的地址
+pub async fn replay(req: &Request, acl: Option<&Acl>) -> Result<Response, anyhow::Error> {
+    let (host, port) = req.target().ok_or_else(|| anyhow!("[-] request has no target host"))?;
+    let tls = req.uri.scheme.as_deref() == Some("https");
+    let pinned = match acl {
+        Some(acl) => acl.check_destination(&host, port).await.map_err(|reason| {
+            metrics().acl_denials.with_label_values(&["destination"]).inc();
+            anyhow!("[-] Destination denied by ACL: {}", reason)
+        })?,
+        None => None,
+    };
+    let started_at = SystemTime::now();
+    let mut timings = Timings::default();
+    let conn = match &pinned {
+        Some(addrs) => Upstream::connect_addrs(&host, port, addrs, tls, &mut timings).await?,
+        None => Upstream::connect_timed(&host, port, tls, &mut timings).await?,
+    };
+    let mut server = Conn::new(conn);
+
+    let mut req = req.clone();
+    if !req.body.is_empty() || request_framing(&req.headers) != Framing::Empty {
+        fix_framing(&mut req.headers, req.body.len());
+    }
+    let mut data = req.to_origin_head();
+    data.extend_from_slice(&req.body);
+    let sending = SystemTime::now();
+    server.stream.write_all(&data).await.context("[-] Failed to send replayed request")?;
+    server.stream.flush().await?;
+    let sent = SystemTime::now();
+    timings.send = ms_between(sending, sent);
+
+    let (mut resp, pending) = server
+        .read_response(&req.method, crate::MAX_CAPTURE_SIZE)
+        .await
+        .with_context(|| format!("[-] Failed to read replayed response from {}:{}", host, port))?;
+    if pending.is_some() {
+        warn!(%host, port, "[-] Replayed response exceeds capture limit, body truncated");
+    }
+    timings.wait = resp.received_at.and_then(|first| ms_between(sent, first));
+    timings.receive = resp.received_at.zip(resp.completed_at).and_then(|(first, last)| ms_between(first, last));
+    timings.total = resp.completed_at.and_then(|last| ms_between(started_at, last));
+    resp.timings = timings;
+    Ok(resp)
+}
+
+/// 修改后重放, 出错时记录在结果中而不是返回错误
+pub async fn repeat(req: &Request, edit: &RequestEdit, original: Option<Response>, acl: Option<&Acl>) -> ReplayResult {
+    let started_at = SystemTime::now();
+    let request = match edit.apply(req) {
+        Ok(request) => request,
+        Err(e) => return ReplayResult { request: req.clone(), original, response: None, error: Some(format!("{:#}", e)), started_at },
+    };
+    let (response, error) = match replay(&request, acl).await {
+        Ok(resp) => (Some(resp), None),
+        Err(e) => (None, Some(format!("{:#}", e))),
+    };
+    ReplayResult { request, original, response, error, started_at }
+}
+
+/// 读取 HAR (`.har`) 或每行一个请求的 JSONL 文件, 返回请求和记录中的原始响应
+pub fn load_batch(path: impl AsRef<Path>) -> Result<Vec<(Request, Option<Response>)>, anyhow::Error> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).with_context(|| format!("[-] Failed to read {}", path.display()))?;
+    if path.extension().and_then(|e| e.to_str()) == Some("har") {
+        let har: Value = serde_json::from_str(&text).context("[-] Failed to parse HAR")?;
+        let entries = har["log"]["entries"].as_array().ok_or_else(|| anyhow!("[-] HAR has no log.entries"))?;
+        entries
+            .iter()
+            .map(|entry| Ok((request_from_json(&entry["request"])?, response_from_json(&entry["response"]))))
+            .collect()
+    } else {
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                let value: Value = serde_json::from_str(line).with_context(|| format!("[-] bad JSON on line {}", i + 1))?;
+                // 整个事务 `{request, response}` 或者单独的请求
+                match value.get("request") {
+                    Some(req) => Ok((request_from_json(req)?, response_from_json(&value["response"]))),
+                    None => Ok((request_from_json(&value)?, None)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// 按顺序重放文件中的所有请求
+pub async fn replay_file(path: impl AsRef<Path>, acl: Option<&Acl>) -> Result<Vec<ReplayResult>, anyhow::Error> {
+    let mut results = Vec::new();
+    for (req, original) in load_batch(path)? {
+        let result = repeat(&req, &RequestEdit::default(), original, acl).await;
+        match (&result.response, &result.error) {
+            (Some(resp), _) => info!(method = req.method.as_str(), url = %req.uri, status = resp.status, "[+] Replay"),
+            (None, Some(err)) => warn!(method = req.method.as_str(), url = %req.uri, error = %err, "[-] Replay failed"),
+            _ => {}
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// 支持 HAR 的请求对象和 `Request` 序列化后的 JSON
+fn request_from_json(value: &Value) -> Result<Request, anyhow::Error> {
+    let method = match &value["method"] {
+        Value::String(m) => m.clone(),
+        // `Method::Extension` 序列化为 `{"Extension": "PROPFIND"}`
+        Value::Object(m) => m.get("Extension").and_then(Value::as_str).unwrap_or_default().to_string(),
+        _ => String::new(),
+    };
+    let method = Method::from_str(&method).ok_or_else(|| anyhow!("[-] bad method in {}", value))?;
+    let url = absolute_url(value)?;
+    let mut req = Request::new(method, &url)?;
+    for (name, val) in headers_from_json(&value["headers"]) {
+        // HTTP/2 的伪头部和分帧头部不能按 HTTP/1.1 发送
+        if name.starts_with(':') || name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("transfer-encoding") {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("host") {
+            req.headers.insert(&name, val);
+        } else {
+            req.headers.append(&name, val);
+        }
+    }
+    let body = value["postData"]["text"].as_str().or_else(|| value["body"].as_str()).unwrap_or_default();
+    req.body = Bytes::from(body.to_string());
+    Ok(req)
+}
+
+/// 解密后的 HTTPS 请求记录的 `url` 是 origin-form, 用记录的 `uri` 或 `Host` 头补全
+fn absolute_url(value: &Value) -> Result<String, anyhow::Error> {
+    let url = value["url"].as_str().ok_or_else(|| anyhow!("[-] request has no url"))?;
+    if !url.starts_with('/') {
+        return Ok(url.to_string());
+    }
+    let uri = &value["uri"];
+    let authority = match uri["authority"]["host"].as_str() {
+        Some(host) => {
+            let authority = Authority { host: host.to_string(), port: uri["authority"]["port"].as_u64().and_then(|p| u16::try_from(p).ok()) };
+            Some(authority.host_header())
+        }
+        None => headers_from_json(&value["headers"]).into_iter().find(|(name, _)| name.eq_ignore_ascii_case("host")).map(|(_, v)| v),
+    };
+    let authority = authority.ok_or_else(|| anyhow!("[-] request {} has no host", url))?;
+    Ok(format!("{}://{}{}", uri["scheme"].as_str().unwrap_or("http"), authority, url))
+}
+
+fn response_from_json(value: &Value) -> Option<Response> {
+    let status = value["status"].as_u64().filter(|s| (100..1000).contains(s))? as u16;
+    let mut resp = Response::new(status);
+    for (name, val) in headers_from_json(&value["headers"]) {
+        resp.headers.append(&name, val);
+    }
+    // 记录中的消息体已经解压, 压缩相关的头部不再适用
+    resp.headers.remove("content-encoding");
+    resp.headers.remove("content-length");
+    resp.headers.remove("transfer-encoding");
+    let body = value["content"]["text"].as_str().filter(|_| value["content"]["encoding"].is_null()).or_else(|| value["body"].as_str());
+    resp.body = Bytes::from(body.unwrap_or_default().to_string());
+    Some(resp)
+}
+
+/// `[{"name","value"}]` (HAR), `[[name, value]]` (`HeaderMap`) 或 `{name: value}`
+fn headers_from_json(value: &Value) -> Vec<(String, String)> {
+    let pair = |name: Option<&str>, val: Option<&str>| Some((name?.to_string(), val?.to_string()));
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|item| match item {
+                Value::Array(kv) => pair(kv.first().and_then(Value::as_str), kv.get(1).and_then(Value::as_str)),
+                Value::Object(_) => pair(item["name"].as_str(), item["value"].as_str()),
+                _ => None,
+            })
+            .collect(),
+        Value::Object(map) => map.iter().filter_map(|(k, v)| pair(Some(k), v.as_str())).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn repeat_with_edits() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            // 把收到的请求作为响应体返回
+            let resp = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", n);
+            stream.write_all(resp.as_bytes()).await.unwrap();
+            stream.write_all(&buf[..n]).await.unwrap();
+        });
+
+        let req = Request::from_string("GET http://example.com/old HTTP/1.1\r\nHost: example.com\r\nCookie: a=1\r\n\r\n").unwrap();
+        let edit = RequestEdit {
+            method: Some("POST".to_string()),
+            url: Some(format!("http://127.0.0.1:{}/new?x=1", port)),
+            set_headers: vec![("X-Replay".to_string(), "1".to_string())],
+            remove_headers: vec!["cookie".to_string()],
+            body: Some("hi".to_string()),
+        };
+        let result = repeat(&req, &edit, None, None).await;
+        assert_eq!(result.error, None);
+        let echoed = result.response.unwrap().text();
+        assert!(echoed.starts_with("POST /new?x=1 HTTP/1.1\r\n"));
+        assert!(echoed.contains(&format!("Host: 127.0.0.1:{}\r\n", port)));
+        assert!(echoed.contains("X-Replay: 1\r\n") && echoed.contains("Content-Length: 2\r\n"));
+        assert!(!echoed.contains("Cookie") && echoed.ends_with("\r\n\r\nhi"));
+
+        // 重放同样受目标 ACL 限制
+        let acl = Acl::new(serde_yaml::from_str("destinations:\n  deny_private: true\n").unwrap());
+        let edit = RequestEdit { url: Some("http://169.254.169.254/latest/meta-data/".to_string()), ..Default::default() };
+        let result = repeat(&req, &edit, None, Some(&acl)).await;
+        assert!(result.response.is_none() && result.error.unwrap().contains("denied by ACL"));
+    }
+
+    #[test]
+    fn load_har_and_jsonl() {
+        let dir = std::env::temp_dir();
+        let har = dir.join(format!("replay_{}.har", std::process::id()));
+        std::fs::write(&har, r#"{"log":{"entries":[{
+            "request":{"method":"POST","url":"https://api.example.com/v1?q=1","httpVersion":"HTTP/2",
+                "headers":[{"name":":authority","value":"api.example.com"},{"name":"X-A","value":"1"}],
+                "postData":{"mimeType":"application/json","text":"{}"}},
+            "response":{"status":201,"headers":[{"name":"Content-Encoding","value":"gzip"}],"content":{"text":"ok"}}}]}}"#).unwrap();
+        let batch = load_batch(&har).unwrap();
+        let (req, resp) = &batch[0];
+        assert_eq!(req.target(), Some(("api.example.com".to_string(), 443)));
+        assert_eq!((req.header("x-a"), req.header("host"), &req.body[..]), (Some("1"), Some("api.example.com"), &b"{}"[..]));
+        let resp = resp.as_ref().unwrap();
+        assert_eq!((resp.status, resp.text(), resp.headers.contains("content-encoding")), (201, "ok".to_string(), false));
+
+        let jsonl = dir.join(format!("replay_{}.jsonl", std::process::id()));
+        let captured = Request::from_string("PROPFIND http://dav.test/a HTTP/1.1\r\nDepth: 1\r\n\r\n").unwrap();
+        let line = serde_json::json!({"request": captured, "response": Response::new(207)});
+        std::fs::write(&jsonl, format!("{}\n\n{}\n", line, r#"{"method":"GET","url":"http://b.test/","headers":{"X-B":"2"}}"#)).unwrap();
+        let batch = load_batch(&jsonl).unwrap();
+        assert_eq!(batch[0].0.method, Method::Extension("PROPFIND".to_string()));
+        assert_eq!(batch[0].0.header("depth"), Some("1"));
+        assert_eq!(batch[0].1.as_ref().map(|r| r.status), Some(207));
+        assert_eq!((batch[1].0.header("x-b"), batch[1].1.is_none()), (Some("2"), true));
+
+        // 解密后的 HTTPS 请求: origin-form 的 url 加上 CONNECT 目标补全的 uri
+        let mut captured = Request::from_string("GET /secure?q=1 HTTP/1.1\r\nHost: api.test:8443\r\n\r\n").unwrap();
+        captured.uri.scheme = Some("https".to_string());
+        captured.uri.authority = Some(Authority { host: "api.test".to_string(), port: Some(8443) });
+        captured.uri.form = TargetForm::Absolute;
+        std::fs::write(&jsonl, format!("{}\n{}\n", serde_json::json!({"request": captured}), r#"{"method":"GET","url":"/p","headers":{"Host":"h.test"}}"#)).unwrap();
+        let batch = load_batch(&jsonl).unwrap();
+        assert_eq!(batch[0].0.url, "https://api.test:8443/secure?q=1");
+        assert_eq!(batch[0].0.target(), Some(("api.test".to_string(), 8443)));
+        assert_eq!(batch[1].0.url, "http://h.test/p");
+
+        std::fs::remove_file(har).unwrap();
+        std::fs::remove_file(jsonl).unwrap();
+    }
+}
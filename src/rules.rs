@@ -0,0 +1,597 @@
+use std::{path::Path, sync::RwLock, time::Duration};
+
+use anyhow::{anyhow, Context};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::filter::Filter;
+use crate::intercept::{InterceptContext, Interceptor, Verdict};
+use crate::config::load_config;
+use crate::prelude::*;
+
+/// 规则文件: YAML 或 TOML, 顶层是 `rules` 列表
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct RuleFile {
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// 规则在哪个阶段生效
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default,Deserialize,serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    #[default] Request,
+    Response,
+}
+
+#[derive(Debug,Clone,Deserialize,serde::Serialize)]
+pub struct RuleConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "crate::config::default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub phase: Phase,
+    #[serde(rename = "match", default)]
+    pub matcher: MatchConfig,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+}
+
+/// 匹配条件, 未设置的条件视为满足
+#[derive(Debug,Clone,Default,Deserialize,serde::Serialize)]
+#[serde(default)]
+pub struct MatchConfig {
+    /// 主机通配符, 如 `*.example.com`
+    pub host: Option<String>,
+    /// 路径(不含查询串)正则
+    pub path: Option<String>,
+    /// 一个或多个方法
+    pub method: Option<OneOrMany>,
+    pub header: Option<HeaderMatch>,
+    /// `Content-Type` 子串, 不区分大小写; 响应阶段匹配响应的类型
+    pub content_type: Option<String>,
+    /// 过滤表达式, 见 `Filter`
+    pub filter: Option<String>,
+}
+
+#[derive(Debug,Clone,Deserialize,serde::Serialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug,Clone,Deserialize,serde::Serialize)]
+pub struct HeaderMatch {
+    pub name: String,
+    /// 值的正则, 不设置时只要求头部存在
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+#[derive(Debug,Clone,Deserialize,serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionConfig {
+    SetHeader { name: String, value: String },
+    RemoveHeader(String),
+    /// 对(解压后的)消息体做正则替换, `with` 中可以用 `$1`
+    ReplaceBody { pattern: String, with: String },
+    /// 用 JSONPath 定位并替换 JSON 消息体中的值
+    ReplaceJson { path: String, value: serde_json::Value },
+    Status(u16),
+    Redirect {
+        location: String,
+        #[serde(default = "default_redirect")]
+        status: u16,
+    },
+    DelayMs(u64),
+    Block {
+        #[serde(default = "default_block")]
+        status: u16,
+        #[serde(default)]
+        body: Option<String>,
+    },
+}
+
+fn default_redirect() -> u16 {
+    302
+}
+
+fn default_block() -> u16 {
+    403
+}
+
+/// 编译后的匹配条件
+#[derive(Debug,Clone,Default)]
+pub struct Matcher {
+    host: Option<String>,
+    path: Option<Regex>,
+    methods: Vec<String>,
+    header: Option<(String, Option<Regex>)>,
+    content_type: Option<String>,
+    filter: Option<Filter>,
+}
+
+impl Matcher {
+    pub fn compile(cfg: &MatchConfig) -> Result<Self, anyhow::Error> {
+        let path = cfg.path.as_deref().map(Regex::new).transpose().context("[-] bad path regex")?;
+        let methods = match &cfg.method {
+            Some(OneOrMany::One(m)) => vec![m.to_uppercase()],
+            Some(OneOrMany::Many(ms)) => ms.iter().map(|m| m.to_uppercase()).collect(),
+            None => Vec::new(),
+        };
+        let header = match &cfg.header {
+            Some(h) => Some((h.name.clone(), h.value.as_deref().map(Regex::new).transpose().context("[-] bad header regex")?)),
+            None => None,
+        };
+        Ok(Matcher {
+            host: cfg.host.clone(),
+            path,
+            methods,
+            header,
+            content_type: cfg.content_type.as_ref().map(|c| c.to_lowercase()),
+            filter: cfg.filter.as_deref().map(Filter::parse).transpose()?,
+        })
+    }
+
+    /// 主机/路径/方法总是取自请求; 头部和类型在有响应时取自响应
+    pub fn matches(&self, req: &Request, resp: Option<&Response>) -> bool {
+        if let Some(pattern) = &self.host {
+            let host = req.uri.host().map(str::to_string).or_else(|| req.target().map(|(h, _)| h)).unwrap_or_default();
+            if !wildcard_match(pattern, &host) {
+                return false;
+            }
+        }
+        if let Some(path) = &self.path {
+            if !path.is_match(&req.uri.path) {
+                return false;
+            }
+        }
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| m == req.method.as_str()) {
+            return false;
+        }
+        let headers = resp.map(|r| &r.headers).unwrap_or(&req.headers);
+        if let Some((name, value)) = &self.header {
+            let found = headers.get_all(name).any(|v| match value {
+                Some(re) => re.is_match(&String::from_utf8_lossy(v)),
+                None => true,
+            });
+            if !found {
+                return false;
+            }
+        }
+        if let Some(ct) = &self.content_type {
+            let actual = headers.get_str("content-type").unwrap_or_default().to_lowercase();
+            if !actual.contains(ct.as_str()) {
+                return false;
+            }
+        }
+        if let Some(filter) = &self.filter {
+            if !filter.matches(req, resp) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug,Clone)]
+enum Action {
+    SetHeader(String, String),
+    RemoveHeader(String),
+    ReplaceBody(Regex, String),
+    ReplaceJson(JsonPath, serde_json::Value),
+    Status(u16),
+    Redirect(String, u16),
+    Delay(Duration),
+    Block(u16, Option<String>),
+}
+
+impl Action {
+    fn compile(cfg: &ActionConfig) -> Result<Self, anyhow::Error> {
+        Ok(match cfg {
+            ActionConfig::SetHeader { name, value } => Action::SetHeader(name.clone(), value.clone()),
+            ActionConfig::RemoveHeader(name) => Action::RemoveHeader(name.clone()),
+            ActionConfig::ReplaceBody { pattern, with } => Action::ReplaceBody(Regex::new(pattern).context("[-] bad body regex")?, with.clone()),
+            ActionConfig::ReplaceJson { path, value } => Action::ReplaceJson(JsonPath::parse(path)?, value.clone()),
+            ActionConfig::Status(status) => Action::Status(status_code(*status)?),
+            ActionConfig::Redirect { location, status } => Action::Redirect(location.clone(), status_code(*status)?),
+            ActionConfig::DelayMs(ms) => Action::Delay(Duration::from_millis(*ms)),
+            ActionConfig::Block { status, body } => Action::Block(status_code(*status)?, body.clone()),
+        })
+    }
+}
+
+/// 状态码必须在 100-599 之间
+pub(crate) fn status_code(status: u16) -> Result<u16, anyhow::Error> {
+    if !(100..=599).contains(&status) {
+        return Err(anyhow!("[-] invalid status code: {}", status));
+    }
+    Ok(status)
+}
+
+/// 编译后的一条规则
+#[derive(Debug,Clone)]
+pub struct Rule {
+    pub config: RuleConfig,
+    matcher: Matcher,
+    actions: Vec<Action>,
+}
+
+impl Rule {
+    pub fn compile(config: RuleConfig) -> Result<Self, anyhow::Error> {
+        let name = config.name.clone().unwrap_or_default();
+        let matcher = Matcher::compile(&config.matcher).with_context(|| format!("[-] rule '{}'", name))?;
+        let actions = config.actions.iter().map(Action::compile).collect::<Result<_, _>>().with_context(|| format!("[-] rule '{}'", name))?;
+        Ok(Rule { config, matcher, actions })
+    }
+}
+
+/// 规则引擎, 作为拦截器注册到代理上.
+/// 克隆后共享同一组规则, 运行时可以增删
+#[derive(Debug,Clone,Default)]
+pub struct RuleEngine {
+    rules: Arc<RwLock<Arc<Vec<Rule>>>>,
+}
+
+impl RuleEngine {
+    /// 按扩展名读取 `.yaml`/`.yml` 或 `.toml` 规则文件
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        Self::from_file(load_config(path, "rules")?)
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Self, anyhow::Error> {
+        // 动作写成 `- set_header: {...}` 形式的单键映射, 而不是 YAML tag
+        let file = serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(text))
+            .context("[-] Failed to parse YAML rules")?;
+        Self::from_file(file)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, anyhow::Error> {
+        Self::from_file(toml::from_str(text).context("[-] Failed to parse TOML rules")?)
+    }
+
+    pub fn from_file(file: RuleFile) -> Result<Self, anyhow::Error> {
+        let rules: Vec<Rule> = file.rules.into_iter().map(Rule::compile).collect::<Result<_, _>>()?;
+        Ok(RuleEngine { rules: Arc::new(RwLock::new(Arc::new(rules))) })
+    }
+
+    /// 当前规则的快照
+    pub fn rules(&self) -> Arc<Vec<Rule>> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// 编译并追加一条规则
+    pub fn add_rule(&self, config: RuleConfig) -> Result<(), anyhow::Error> {
+        let rule = Rule::compile(config)?;
+        self.update(|rules| rules.push(rule));
+        Ok(())
+    }
+
+    /// 按名称删除规则, 返回删除的数量
+    pub fn remove_rule(&self, name: &str) -> usize {
+        let mut removed = 0;
+        self.update(|rules| {
+            let before = rules.len();
+            rules.retain(|r| r.config.name.as_deref() != Some(name));
+            removed = before - rules.len();
+        });
+        removed
+    }
+
+    /// 用新的规则文件替换全部规则, 编译失败时保留原规则
+    pub fn replace(&self, file: RuleFile) -> Result<(), anyhow::Error> {
+        let rules = Self::from_file(file)?.rules();
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    // 写时复制, 正在执行的事务继续使用旧的快照
+    fn update(&self, f: impl FnOnce(&mut Vec<Rule>)) {
+        let mut guard = self.rules.write().unwrap();
+        let mut rules = guard.as_ref().clone();
+        f(&mut rules);
+        *guard = Arc::new(rules);
+    }
+
+    fn active(rules: &[Rule], phase: Phase) -> impl Iterator<Item = &Rule> {
+        rules.iter().filter(move |r| r.config.enabled && r.config.phase == phase)
+    }
+
+    /// 对请求执行规则, 返回非 `Continue` 时不再访问上游
+    pub async fn apply_request(&self, req: &mut Request) -> Verdict {
+        let rules = self.rules();
+        for rule in Self::active(&rules, Phase::Request) {
+            if !rule.matcher.matches(req, None) {
+                continue;
+            }
+            for action in &rule.actions {
+                match action {
+                    Action::SetHeader(name, value) => req.headers.insert(name, value.as_bytes()),
+                    Action::RemoveHeader(name) => {
+                        req.headers.remove(name);
+                    }
+                    Action::ReplaceBody(re, with) => {
+                        if let Some(body) = replace_body(&req.headers, &req.body, re, with) {
+                            req.headers.remove("content-encoding");
+                            req.body = body;
+                        }
+                    }
+                    Action::ReplaceJson(path, value) => {
+                        if let Some(body) = replace_json(&req.body, path, value) {
+                            req.body = body;
+                        }
+                    }
+                    Action::Status(status) => return Verdict::Respond(Response::new(*status)),
+                    Action::Redirect(location, status) => {
+                        return Verdict::Respond(Response::new(*status).with_header("Location", location.as_bytes()))
+                    }
+                    Action::Delay(delay) => tokio::time::sleep(*delay).await,
+                    Action::Block(status, body) => {
+                        return Verdict::Respond(Response::new(*status).with_body(body.clone().unwrap_or_default()))
+                    }
+                }
+            }
+        }
+        Verdict::Continue
+    }
+
+    /// 对响应执行规则; 改写消息体时先解压, 之后去掉 `Content-Encoding`
+    pub async fn apply_response(&self, req: &Request, resp: &mut Response) -> Verdict {
+        let rules = self.rules();
+        for rule in Self::active(&rules, Phase::Response) {
+            if !rule.matcher.matches(req, Some(resp)) {
+                continue;
+            }
+            for action in &rule.actions {
+                match action {
+                    Action::SetHeader(name, value) => resp.headers.insert(name, value.as_bytes()),
+                    Action::RemoveHeader(name) => {
+                        resp.headers.remove(name);
+                    }
+                    Action::ReplaceBody(re, with) => {
+                        if let Some(body) = replace_body(&resp.headers, &resp.body, re, with) {
+                            resp.headers.remove("content-encoding");
+                            resp.body = body;
+                        }
+                    }
+                    Action::ReplaceJson(path, value) => {
+                        if let Some(body) = resp.decoded_body().ok().and_then(|b| replace_json(&b, path, value)) {
+                            resp.headers.remove("content-encoding");
+                            resp.body = body;
+                        }
+                    }
+                    Action::Status(status) => {
+                        resp.status = *status;
+                        resp.reason = reason_phrase(*status).to_string();
+                        resp.error = (*status >= 400).then(|| format!("{} {}", status, resp.reason));
+                    }
+                    Action::Redirect(location, status) => {
+                        *resp = Response::new(*status).with_header("Location", location.as_bytes());
+                    }
+                    Action::Delay(delay) => tokio::time::sleep(*delay).await,
+                    Action::Block(status, body) => {
+                        *resp = Response::new(*status).with_body(body.clone().unwrap_or_default());
+                    }
+                }
+            }
+        }
+        Verdict::Continue
+    }
+}
+
+#[async_trait::async_trait]
+impl Interceptor for RuleEngine {
+    async fn on_request(&self, _ctx: &InterceptContext, req: &mut Request) -> Verdict {
+        self.apply_request(req).await
+    }
+
+    async fn on_response(&self, _ctx: &InterceptContext, req: &Request, resp: &mut Response) -> Verdict {
+        self.apply_response(req, resp).await
+    }
+}
+
+/// 解压后按文本替换; 解不开, 不是UTF-8或没有匹配时返回None, 消息体保持原样
+fn replace_body(headers: &HeaderMap, body: &[u8], re: &Regex, with: &str) -> Option<Bytes> {
+    let decoded = crate::decode::decode_body(headers, body, crate::decode::MAX_DECODED_SIZE).ok()?;
+    let text = std::str::from_utf8(&decoded).ok()?;
+    if !re.is_match(text) {
+        return None;
+    }
+    Some(Bytes::from(re.replace_all(text, with).into_owned()))
+}
+
+fn replace_json(body: &[u8], path: &JsonPath, value: &serde_json::Value) -> Option<Bytes> {
+    let mut json: serde_json::Value = serde_json::from_slice(body).ok()?;
+    if path.set(&mut json, value) == 0 {
+        return None;
+    }
+    serde_json::to_vec(&json).ok().map(Bytes::from)
+}
+
+/// JSONPath 的一个子集: `$`, `.name`, `['name']`, `[0]`, `[*]`, `.*`
+#[derive(Debug,Clone,PartialEq)]
+pub struct JsonPath(Vec<Segment>);
+
+#[derive(Debug,Clone,PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<Self, anyhow::Error> {
+        let rest = path.strip_prefix('$').ok_or_else(|| anyhow!("[-] JSONPath must start with '$': {}", path))?;
+        let mut segments = Vec::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => {
+                    let mut key = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c == '.' || c == '[' {
+                            break;
+                        }
+                        key.push(c);
+                        chars.next();
+                    }
+                    match key.as_str() {
+                        "" => return Err(anyhow!("[-] empty JSONPath segment: {}", path)),
+                        "*" => segments.push(Segment::Wildcard),
+                        _ => segments.push(Segment::Key(key)),
+                    }
+                }
+                '[' => {
+                    let mut inner = String::new();
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            break;
+                        }
+                        inner.push(c);
+                    }
+                    let inner = inner.trim();
+                    if inner == "*" {
+                        segments.push(Segment::Wildcard);
+                    } else if let Some(key) = inner.strip_prefix('\'').and_then(|k| k.strip_suffix('\'')) {
+                        segments.push(Segment::Key(key.to_string()));
+                    } else if let Some(key) = inner.strip_prefix('"').and_then(|k| k.strip_suffix('"')) {
+                        segments.push(Segment::Key(key.to_string()));
+                    } else {
+                        let index = inner.parse().map_err(|_| anyhow!("[-] bad JSONPath index '{}' in {}", inner, path))?;
+                        segments.push(Segment::Index(index));
+                    }
+                }
+                _ => return Err(anyhow!("[-] unexpected '{}' in JSONPath {}", c, path)),
+            }
+        }
+        Ok(JsonPath(segments))
+    }
+
+    /// 把所有匹配位置的值替换为 `value`, 返回替换的数量
+    pub fn set(&self, root: &mut serde_json::Value, value: &serde_json::Value) -> usize {
+        fn walk(node: &mut serde_json::Value, segments: &[Segment], value: &serde_json::Value) -> usize {
+            let Some((first, rest)) = segments.split_first() else {
+                *node = value.clone();
+                return 1;
+            };
+            match (first, node) {
+                (Segment::Key(key), serde_json::Value::Object(map)) => map.get_mut(key).map_or(0, |n| walk(n, rest, value)),
+                (Segment::Index(i), serde_json::Value::Array(arr)) => arr.get_mut(*i).map_or(0, |n| walk(n, rest, value)),
+                (Segment::Wildcard, serde_json::Value::Object(map)) => map.values_mut().map(|n| walk(n, rest, value)).sum(),
+                (Segment::Wildcard, serde_json::Value::Array(arr)) => arr.iter_mut().map(|n| walk(n, rest, value)).sum(),
+                _ => 0,
+            }
+        }
+        walk(root, &self.0, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const YAML: &str = r#"
+rules:
+  - name: staging header
+    match:
+      host: "*.staging.example.com"
+      method: [GET, POST]
+    actions:
+      - set_header: { name: X-Env, value: staging }
+      - remove_header: Cookie
+  - name: mask token
+    phase: response
+    match:
+      path: "^/api/"
+      content_type: json
+    actions:
+      - replace_json: { path: "$.user.token", value: "***" }
+      - status: 299
+  - name: block admin
+    match: { path: "^/admin" }
+    actions:
+      - block: { body: nope }
+"#;
+
+    #[tokio::test]
+    async fn yaml_rules_apply() {
+        let engine = RuleEngine::from_yaml(YAML).unwrap();
+        let mut req = Request::from_string("GET http://a.staging.example.com/api/me HTTP/1.1\r\nCookie: s=1\r\n\r\n").unwrap();
+        assert!(matches!(engine.apply_request(&mut req).await, Verdict::Continue));
+        assert_eq!(req.header("x-env"), Some("staging"));
+        assert_eq!(req.header("cookie"), None);
+
+        let mut resp = Response::from_string("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"user\":{\"token\":\"abc\",\"id\":1}}").unwrap();
+        engine.apply_response(&req, &mut resp).await;
+        assert_eq!(resp.status, 299);
+        assert_eq!(resp.json().unwrap()["user"], serde_json::json!({"token": "***", "id": 1}));
+
+        let mut req = Request::from_string("GET http://x.com/admin HTTP/1.1\r\n\r\n").unwrap();
+        match engine.apply_request(&mut req).await {
+            Verdict::Respond(resp) => assert_eq!((resp.status, &resp.body[..]), (403, &b"nope"[..])),
+            other => panic!("unexpected verdict {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn toml_rules_apply() {
+        let engine = RuleEngine::from_toml(r#"
+[[rules]]
+name = "old api"
+match = { path = "^/v1/(.*)" }
+actions = [{ redirect = { location = "/v2/" } }]
+
+[[rules]]
+phase = "response"
+actions = [{ replace_body = { pattern = "secret-(\\d+)", with = "masked-$1" } }, { delay_ms = 1 }]
+
+[[rules]]
+match = { filter = "method == DELETE && host ~ 'prod'" }
+actions = [{ status = 405 }]
+"#).unwrap();
+        let mut req = Request::from_string("GET /v1/users HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        match engine.apply_request(&mut req).await {
+            Verdict::Respond(resp) => assert_eq!((resp.status, resp.headers.get_str("location")), (302, Some("/v2/"))),
+            other => panic!("unexpected verdict {:?}", other),
+        }
+        let mut resp = Response::from_string("HTTP/1.1 200 OK\r\n\r\nid=secret-42").unwrap();
+        engine.apply_response(&req, &mut resp).await;
+        assert_eq!(resp.text(), "id=masked-42");
+
+        // 没有匹配或不是文本时消息体不变, 压缩的消息体解压后替换
+        let binary = Bytes::from_static(&[0xff, 0xfe, b's', 0x00]);
+        let mut resp = Response::new(200).with_body(binary.clone());
+        engine.apply_response(&req, &mut resp).await;
+        assert_eq!(resp.body, binary);
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gz, b"x secret-7").unwrap();
+        let mut resp = Response::new(200).with_header("Content-Encoding", "gzip").with_body(gz.finish().unwrap());
+        engine.apply_response(&req, &mut resp).await;
+        assert_eq!((resp.headers.get_str("content-encoding"), &resp.body[..]), (None, &b"x masked-7"[..]));
+        assert!(RuleEngine::from_toml("[[rules]]\nactions = [{ status = 700 }]\n").is_err());
+
+        let mut req = Request::from_string("DELETE http://db.prod.example/rows HTTP/1.1\r\n\r\n").unwrap();
+        assert!(matches!(engine.apply_request(&mut req).await, Verdict::Respond(resp) if resp.status == 405));
+
+        // 运行时增删规则, 克隆共享同一组规则
+        let shared = engine.clone();
+        let rule: RuleConfig = serde_json::from_str(r#"{"name": "tag", "actions": [{"set_header": {"name": "X-Tag", "value": "1"}}]}"#).unwrap();
+        shared.add_rule(rule).unwrap();
+        let mut req = Request::from_string("GET /x HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        engine.apply_request(&mut req).await;
+        assert_eq!(req.header("x-tag"), Some("1"));
+        assert_eq!(shared.remove_rule("tag"), 1);
+        assert_eq!(engine.rules().len(), 3);
+    }
+
+    #[test]
+    fn json_path_subset() {
+        let path = JsonPath::parse("$.items[*]['price']").unwrap();
+        let mut json = serde_json::json!({"items": [{"price": 1}, {"price": 2}, {"name": "x"}]});
+        assert_eq!(path.set(&mut json, &serde_json::json!(0)), 2);
+        assert_eq!(json["items"][1]["price"], 0);
+        assert!(JsonPath::parse("items").is_err());
+    }
+}
@@ -1,59 +1,142 @@
-use std::{future::ready, net::SocketAddr, sync::mpsc::channel, thread::spawn};
-use anyhow::{Context as ct};
-use rustls::{client, ClientConfig};
-use time::SystemTime;
-use tokio::{io::{ copy, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::TcpStream, sync::Mutex};
-use tokio_rustls::{TlsAcceptor, TlsConnector};
 use crate::{debug_stream::copy_bidirectional, prelude::*};
+use anyhow::Context as ct;
+use rustls::{client, ClientConfig};
 use std::future::poll_fn;
 use std::io;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
+use std::{future::ready, net::SocketAddr, sync::mpsc::channel, thread::spawn};
+use time::{Instant, SystemTime};
+use tokio::{
+    io::{copy, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
+use crate::acl::{forbidden, Acl};
 use crate::copy::{CopyBuffer, Direction};
+use crate::debug_stream::copy_bidirectional_shaped;
+use crate::fault::{
+    fault_response, Fault, FaultInjector, Faulty, StreamFault, TLS_HANDSHAKE_FAILURE,
+};
+use crate::filter::Filter;
+use crate::http1::{
+    apply_framing, fix_framing, response_framing, write_prefix, Conn, Framing, Pending,
+};
+use crate::intercept::{InterceptContext, InterceptorChain, Verdict};
+use crate::metrics::{metrics, Metered};
+use crate::telemetry::{exchange_span, inject_context, TracePropagation};
+use crate::throttle::{too_many_requests, Shaper, Throttle, Throttled};
+use crate::timing::{ms, ms_between, Timings};
+use crate::upstream::Upstream;
+use tracing::{debug, info, warn, Instrument, Span};
+
+/// 当前复用的上游连接及其 (主机, 端口, 是否TLS)
+type UpstreamSlot = Option<((String, u16, bool), Conn<Upstream>)>;
 
+/// 一个事务处理完之后客户端连接怎么继续
+enum Next {
+    KeepAlive,
+    Close,
+    /// 不再写任何数据, 重置客户端连接
+    Reset,
+    /// 101 已经写给客户端, 转为隧道, 带上要注入的故障
+    Upgrade(Option<StreamFault>),
+}
+
+// 每个转发方向只有一个, 不值得为 Running 装箱
+#[allow(clippy::large_enum_variant)]
 enum TransferState {
     Running(CopyBuffer),
     ShuttingDown(u64),
     Done(u64),
 }
 
-#[derive(Debug,Clone)]
-pub struct Session{
+#[derive(Debug, Clone)]
+pub struct Session {
     //  请求体
-    pub request : Request,
+    pub request: Request,
     //  响应体
     pub response: Response,
     // 其他
-    pub session_id:u32,
+    pub session_id: u32,
     pub time: Option<SystemTime>,
-    pub stream : Option<Arc<Mutex<TcpStream>>>,
-    pub initial_data:Vec<u8>,
+    pub stream: Option<Arc<Mutex<TcpStream>>>,
+    pub initial_data: Vec<u8>,
+    pub client_addr: Option<SocketAddr>,
+    pub interceptors: InterceptorChain,
+    /// 只打印匹配的事务, 不影响转发和记录
+    pub log_filter: Option<Arc<Filter>>,
+    pub trace_propagation: TracePropagation,
+    /// 通过代理认证的用户名
+    pub user: Option<String>,
+    /// 连接上游前检查目标
+    pub acl: Option<Arc<Acl>>,
+    /// 请求速率和客户端连接的带宽
+    pub throttle: Option<Arc<Throttle>>,
+    /// 按主机和路径注入故障
+    pub faults: Option<Arc<FaultInjector>>,
+    /// 关闭时以 RST 结束客户端连接
+    reset_client: bool,
+    /// 客户端 TLS 握手耗时, 记到连接上的第一个事务后清空
+    client_tls: Option<f64>,
+    /// 客户端 TLS 握手协商的参数
+    client_tls_info: Option<TlsInfo>,
 }
 
 impl Session {
-    pub fn new(session_id:u32,stream: TcpStream) ->  Option<Self>{
-
-        Some(
-            Session { 
-                request: Request::default(), 
-                response: Response::default(), 
-                session_id, 
-                time: Some(SystemTime::now()), 
-                stream: Some(Arc::new(Mutex::new(stream))),
-                initial_data:Vec::new(),
-            }
-        )
+    pub fn new(session_id: u32, stream: TcpStream) -> Option<Self> {
+        Some(Session {
+            request: Request::default(),
+            response: Response::default(),
+            session_id,
+            time: Some(SystemTime::now()),
+            stream: Some(Arc::new(Mutex::new(stream))),
+            initial_data: Vec::new(),
+            client_addr: None,
+            interceptors: InterceptorChain::new(),
+            log_filter: None,
+            trace_propagation: TracePropagation::Off,
+            user: None,
+            acl: None,
+            throttle: None,
+            faults: None,
+            reset_client: false,
+            client_tls: None,
+            client_tls_info: None,
+        })
     }
 
-    pub fn set_request(&mut self,req:Request) {
+    pub fn set_request(&mut self, req: Request) {
         self.request = req;
     }
 
-    pub fn set_response(&mut self,resp:Response) { 
+    pub fn set_response(&mut self, resp: Response) {
         self.response = resp;
     }
 
+    pub fn set_interceptors(&mut self, interceptors: InterceptorChain) {
+        self.interceptors = interceptors;
+    }
+
+    /// 传给拦截器的会话信息
+    pub fn context(&self) -> InterceptContext {
+        let connect_target = if self.request.method == Method::CONNECT {
+            self.request.target()
+        } else {
+            None
+        };
+        InterceptContext {
+            session_id: self.session_id,
+            client_addr: self.client_addr,
+            tls: connect_target.is_some(),
+            client_tls: self.client_tls_info.clone(),
+            connect_target,
+            user: self.user.clone(),
+        }
+    }
+
     // 新增方法以获取请求体和响应体的原始数据
     pub fn get_request_data(&self) -> String {
         String::from_utf8_lossy(&self.request.to_bytes()).to_string()
@@ -63,128 +146,639 @@ impl Session {
         String::from_utf8_lossy(&self.response.to_bytes()).to_string()
     }
 
-    pub async  fn session_connect(&mut self,addr:SocketAddr) {
-        let mut buffer = [0u8;8192]; 
-        let n = self.stream.as_ref().unwrap().lock().await.read(&mut buffer[..]).await.unwrap();
-        // println!(" -> connect recv data {n:?} bytes.");
-        self.initial_data = buffer[..n].to_vec();
-        let raw_data = String::from_utf8(buffer[..n].to_vec()).context("[-] connect recv data bytes failed to string.");
-        let res = Request::from_string(raw_data.unwrap().as_str()).unwrap();
-        self.request = res;
-
-    }
-
-
-
-    pub async fn handle_https(&mut self,host:String,port:String,ca_cert: Arc<CertifiedKey>) -> Result<(), anyhow::Error> {
-        let mut client_stream = self.stream.as_ref().unwrap().lock().await;
-        client_stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.context("[-] Failed to write http/1.1 200.")?;
-        // let mut client_stream = self.stream.as_ref().unwrap().lock().await;
-        // client_stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.context("[-] Failed to write http/1.1 200."); 
-        drop(client_stream);
-        let server_cert = generate_signed_cert(&ca_cert.cert, &ca_cert.key_pair, host.clone()).await?;
-        let  server_config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(vec![server_cert.cert.into()],rustls::pki_types::PrivateKeyDer::Pkcs8(server_cert.key_pair.serialize_der().into()))?;
-        let mut  root_store = rustls::RootCertStore::from_iter(
-            webpki_roots::TLS_SERVER_ROOTS
-                .iter()
-                .cloned(),
-        );
-        let mut server  = rustls::ServerConnection::new(Arc::new(server_config.clone()))?;
-        let tls_acceptor = TlsAcceptor::from(Arc::new(server_config.clone()));
-
-
-
-        let cert_der = CertificateDer::from_pem_file("ca.crt").expect("[-] Failed to RootCertStore read ca.crt");
-        let cert_der_iter = vec![cert_der];
-        root_store.add_parsable_certificates(cert_der_iter);
-            // 连接目标服务器
-            match TcpStream::connect(format!("{}:{}", host, port)).await {
-                Ok(target_stream) => {
-                    // 配置 Rustls 客户端配置
-                    let client_config = ClientConfig::builder()
-                        .with_root_certificates(root_store)
-                        .with_no_client_auth();
-                    let tls_connector = TlsConnector::from(Arc::new(client_config));
-                    // 构建服务器名称
-                    let server_name = ServerName::try_from(host).context("Invalid server name")?;
-                    // 将目标服务器流升级为 TLS 流
-                    let mut target_tls_stream = match tls_connector.connect(server_name, target_stream).await {
-                        Ok(stream) => stream,
-                        Err(e) => {
-                            eprintln!("[-] TLS handshake with target server failed: {:?}", e);
-                            return Ok(());
-                        }
-                    };
-
-                    let mut client_stream = self.stream.as_ref().unwrap().lock().await;
-                    // 传递引用而非移动
-                    let  mut tls_stream = match tls_acceptor.accept(&mut *client_stream).await {
-                        Ok(stream) => stream,
-                        Err(e) => {
-                            eprintln!("[-] TLS handshake failed: {:?}", e);
-                            return Ok(());
-                        }
-                    }; 
-
-
-                    match copy_bidirectional(&mut tls_stream, &mut target_tls_stream).await {
-                        Ok(((from_client_byte,from_client_data), (from_server_byte,from_server_data))) => {
-                            let req = Request::from_string(&from_client_data).unwrap();
-                            self.request = req;
-
-                            let resp = Response::from_string(&from_server_data).unwrap();
-                            self.response = resp;
-                            //println!("  [HANDLE {}] Connection closed: {} \n{} bytes from client, {}\n{} bytes from server", self.session_id,from_client_data,from_client_byte, from_server_data,from_server_byte);
-                        }
-                        Err(e) => {
-                            //eprintln!(" [-] Error during bidirectional copy: {:?}", e);
-                        }
-                    }
-                },
+    /// 读取第一个请求的完整消息头, 之后的 ACL 和认证都基于它.
+    /// 读到的所有数据保存在 `initial_data`, 普通 HTTP 请求从头重新解析
+    pub async fn session_connect(&mut self, addr: SocketAddr) -> Result<(), anyhow::Error> {
+        self.client_addr = Some(addr);
+        let stream = self
+            .stream
+            .clone()
+            .context("[-] Session has no client stream.")?;
+        let mut client_stream = stream.lock().await;
+        let mut conn = Conn::new(Metered::new(&mut *client_stream));
+        let head = conn
+            .read_head()
+            .await
+            .context("[-] connect recv data failed.")?
+            .context("[-] connection closed before request.")?;
+        let (_, rest) = conn.into_parts();
+        self.request = Request::from_bytes(&head)
+            .context("[-] connect recv data is not a valid http request.")?;
+        self.initial_data = head;
+        self.initial_data.extend_from_slice(&rest);
+        Ok(())
+    }
+
+    /// 调用拦截器的 `on_connect`, 返回false表示会话已被拦截器结束
+    pub async fn run_connect_hooks(&mut self) -> bool {
+        let ctx = self.context();
+        match self.interceptors.on_connect(&ctx, &self.request).await {
+            Verdict::Continue => true,
+            Verdict::Respond(resp) => {
+                self.respond(resp).await;
+                false
+            }
+            Verdict::Drop => false,
+        }
+    }
+
+    /// 检查客户端地址和 CONNECT 的目标, 被拒绝的目标不做 TLS 解密.
+    /// 拒绝时回复 403 并返回false
+    pub async fn check_acl(&mut self) -> bool {
+        let Some(acl) = self.acl.clone() else {
+            return true;
+        };
+        let mut denied = None;
+        if let Some(Err(reason)) = self.client_addr.map(|addr| acl.check_client(addr.ip())) {
+            denied = Some(("client", reason));
+        } else if let Some((host, port)) = self.context().connect_target {
+            denied = acl
+                .check_destination(&host, port)
+                .await
+                .err()
+                .map(|reason| ("destination", reason));
+        }
+        let Some((scope, reason)) = denied else {
+            return true;
+        };
+        warn!(scope, %reason, "[-] Denied by ACL");
+        metrics().acl_denials.with_label_values(&[scope]).inc();
+        self.respond(forbidden(&reason)).await;
+        false
+    }
+
+    /// 用给定响应回复初始请求, 之后关闭连接. 响应由代理生成, 逐跳头部不删除
+    pub async fn respond(&mut self, mut resp: Response) {
+        if let Some(stream) = self.stream.clone() {
+            let mut client = stream.lock().await;
+            resp.headers.insert("Connection", "close");
+            fix_framing(&mut resp.headers, resp.body.len());
+            let mut data = resp.to_raw_head();
+            if self.request.method != Method::HEAD {
+                data.extend_from_slice(&resp.body);
+            }
+            let _ = client.write_all(&data).await;
+        }
+        self.response = resp;
+    }
+
+    pub async fn handle_https(
+        &mut self,
+        host: String,
+        port: u16,
+        certs: Arc<CertCache>,
+    ) -> Result<(), anyhow::Error> {
+        let stream = self
+            .stream
+            .clone()
+            .context("[-] Session has no client stream.")?;
+        let mut client_stream = stream.lock().await;
+        client_stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .context("[-] Failed to write http/1.1 200.")?;
+        if let Some(fault) = self.faults.as_ref().and_then(|f| f.pick_connect(&host)) {
+            info!(%host, %fault, "[+] Injecting fault");
+            metrics()
+                .injected_faults
+                .with_label_values(&[fault.as_str()])
+                .inc();
+            // 读掉 ClientHello 再回复告警, 否则客户端可能只看到连接重置
+            let mut hello = [0u8; 4096];
+            let _ =
+                tokio::time::timeout(time::Duration::from_secs(1), client_stream.read(&mut hello))
+                    .await;
+            let _ = client_stream.write_all(TLS_HANDSHAKE_FAILURE).await;
+            return Ok(());
+        }
+        let tls_acceptor = TlsAcceptor::from(certs.server_config(&host).await?);
+
+        // 传递引用而非移动
+        let started = std::time::Instant::now();
+        let tls_stream = match tls_acceptor.accept(Metered::new(&mut *client_stream)).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                metrics().tls_failed("client", &e);
+                let err = anyhow::Error::new(e).context("[-] TLS handshake failed");
+                warn!(%host, error = ?err, "[-] Client TLS handshake failed");
+                self.interceptors.on_error(&self.context(), &err).await;
+                return Ok(());
+            }
+        };
+        metrics()
+            .tls_handshake_duration
+            .with_label_values(&["client"])
+            .observe(started.elapsed().as_secs_f64());
+        self.client_tls = Some(ms(started.elapsed()));
+        let (_, conn) = tls_stream.get_ref();
+        self.client_tls_info = Some(TlsInfo::new(conn, conn.server_name()));
+        debug!(%host, elapsed_ms = started.elapsed().as_millis() as u64, "[+] Client TLS handshake completed");
+        // 上游连接在读到第一个请求之后再建立, 拦截器可以改写目标或直接回复
+        let result = self.serve(Conn::new(tls_stream)).await;
+        if self.reset_client {
+            let _ = client_stream.set_linger(Some(time::Duration::ZERO));
+        }
+        result
+    }
+
+    /// 普通HTTP请求: 从请求目标推导上游地址后转发
+    pub async fn forward_http(&mut self) -> Result<(), anyhow::Error> {
+        let stream = self
+            .stream
+            .clone()
+            .context("[-] Session has no client stream.")?;
+        let mut client_stream = stream.lock().await;
+        let initial_data = std::mem::take(&mut self.initial_data);
+        let result = self
+            .serve(Conn::with_buffer(
+                Metered::new(&mut *client_stream),
+                initial_data,
+            ))
+            .await;
+        if self.reset_client {
+            let _ = client_stream.set_linger(Some(time::Duration::ZERO));
+        }
+        result
+    }
+
+    /// 直接以给定状态行回复客户端并结束会话
+    pub async fn reject(&mut self, status: &str) -> Result<(), anyhow::Error> {
+        if let Some(stream) = &self.stream {
+            let resp = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            stream
+                .lock()
+                .await
+                .write_all(resp.as_bytes())
+                .await
+                .context("[-] Failed to write reject response.")?;
+        }
+        Ok(())
+    }
+
+    /// 在一个客户端连接上逐个处理请求: 拦截器 -> 上游 -> 拦截器 -> 客户端.
+    /// 上游连接按 (主机, 端口, 是否TLS) 复用, 协议升级后转为原始隧道
+    async fn serve<C>(&mut self, client: Conn<C>) -> Result<(), anyhow::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let ctx = self.context();
+        // 限速作用于整个客户端连接, 转为隧道后改由 CopyBuffer 限速
+        let shaper = self.throttle.as_ref().and_then(|t| t.shaper());
+        let (stream, buf) = client.into_parts();
+        let mut client = Conn::with_buffer(Throttled::new(stream, shaper.as_ref()), buf);
+        let mut upstream: UpstreamSlot = None;
+        loop {
+            let (mut req, req_pending) = match client.read_request(crate::MAX_CAPTURE_SIZE).await {
+                Ok(Some(read)) => read,
+                Ok(None) => break,
                 Err(e) => {
-                    eprintln!("[-] Connection error: {:?}", e);
+                    let err = anyhow::Error::new(e).context("[-] Failed to read request");
+                    warn!(error = ?err, "[-] Failed to parse request");
+                    self.interceptors.on_error(&ctx, &err).await;
+                    let _ = write_response(
+                        &mut client.stream,
+                        &self.request,
+                        &mut Response::new(400).with_header("Connection", "close"),
+                    )
+                    .await;
+                    break;
                 }
+            };
+            debug!(method = req.method.as_str(), url = %req.uri, body = req.body.len(), streaming = req_pending.is_some(), "[+] Request parsed");
+            // 代理凭据在建立会话时已经校验, 不交给拦截器和上游
+            req.headers.remove("proxy-authorization");
+            // 解密后的请求是 origin-form, 用 CONNECT 的目标补全
+            if let (Some((host, port)), None) = (&ctx.connect_target, &req.uri.authority) {
+                req.uri.scheme = Some("https".to_string());
+                req.uri.authority = Some(Authority {
+                    host: host.clone(),
+                    port: (*port != 443).then_some(*port),
+                });
+                req.uri.form = TargetForm::Absolute;
             }
-        Ok(())
 
+            let span = exchange_span(&req, self.trace_propagation);
+            match self
+                .exchange(&ctx, &mut client, &mut upstream, req, req_pending)
+                .instrument(span)
+                .await?
+            {
+                Next::KeepAlive => {}
+                Next::Close => break,
+                Next::Reset => {
+                    self.reset_client = true;
+                    return Ok(());
+                }
+                Next::Upgrade(fault) => {
+                    let (_, server) = upstream.take().expect("upstream connected before upgrade");
+                    let (stream, buf) = client.into_parts();
+                    return self
+                        .tunnel(
+                            Conn::with_buffer(stream.into_inner(), buf),
+                            server,
+                            &ctx,
+                            shaper.as_ref(),
+                            fault,
+                        )
+                        .await;
+                }
+            }
+        }
+        let _ = client.stream.shutdown().await;
+        Ok(())
     }
 
-    pub async fn handle_http(&mut self, host: String, port: String, initial_data: Vec<u8>) -> Result<(), anyhow::Error> {
-        match TcpStream::connect(format!("{}:{}",host,port)).await {
-            Ok(mut target_stream) => {
-                // 发送初始请求数据到目标服务器
-                target_stream.write_all(&initial_data).await?;
-    
-                // 从目标服务器读取响应并直接转发给原始客户端
-                if let Some(stream) = &self.stream {
-                    let mut stream_lock = stream.lock().await;
-                    let mut buf = [0u8; 8192];
-                    loop {
-                        match target_stream.read(&mut buf).await {
-                            Ok(0) => break, // 连接关闭
-                            Ok(n) => {
-                                stream_lock.write_all(&buf[..n]).await?;
-                                let raw_data = String::from_utf8_lossy(&buf[..n]).to_string();
-                                let resp  = Response::from_string(&raw_data).unwrap();
-                                self.response = resp;
-                                break;
-                            }
-                            Err(e) => {
-                                eprintln!("Read error: {:?}", e);
-                                break;
-                            }
-                        }
-                    }
+    /// 处理一个请求直到响应写回客户端, 在该事务的 span 中执行
+    async fn exchange<C>(
+        &mut self,
+        ctx: &InterceptContext,
+        client: &mut Conn<C>,
+        upstream: &mut UpstreamSlot,
+        mut req: Request,
+        req_pending: Option<Pending>,
+    ) -> Result<Next, anyhow::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let started = Instant::now();
+        let prefix = req_pending.map(|_| req.body.clone());
+        match self.interceptors.on_request(ctx, &mut req).await {
+            Verdict::Continue => {}
+            Verdict::Respond(resp) => {
+                return self
+                    .reply(ctx, client, req, req_pending.is_some(), resp)
+                    .await
+            }
+            Verdict::Drop => return Ok(Next::Close),
+        }
+        // 拦截器改写了只读到前缀的请求体: 读掉剩余部分, 改写后的消息体作为完整请求发出
+        let mut req_pending = req_pending;
+        if let (Some(pending), Some(prefix)) = (req_pending, prefix) {
+            if req.body != prefix {
+                client.relay_body(pending, &mut tokio::io::sink()).await?;
+                req_pending = None;
+            }
+        }
+
+        let Some((host, port)) = req.target() else {
+            write_response(
+                &mut client.stream,
+                &req,
+                &mut Response::new(400).with_header("Connection", "close"),
+            )
+            .await?;
+            return Ok(Next::Close);
+        };
+        let span = Span::current();
+        span.record("server.address", host.as_str());
+        span.record("server.port", port);
+        // 拦截器可能已经改写了目标, 在这里检查最终要连接的地址
+        let mut pinned = None;
+        if let Some(acl) = &self.acl {
+            match acl.check_destination(&host, port).await {
+                Ok(addrs) => pinned = addrs,
+                Err(reason) => {
+                    warn!(%host, port, %reason, "[-] Destination denied by ACL");
+                    metrics()
+                        .acl_denials
+                        .with_label_values(&["destination"])
+                        .inc();
+                    return self
+                        .reply(ctx, client, req, req_pending.is_some(), forbidden(&reason))
+                        .await;
                 }
             }
+        }
+        if let Some(throttle) = &self.throttle {
+            if let Err(limited) =
+                throttle.check_request(self.client_addr.map(|addr| addr.ip()), &host)
+            {
+                warn!(%host, %limited, "[-] Request rate limited");
+                metrics()
+                    .throttled_requests
+                    .with_label_values(&[limited.scope])
+                    .inc();
+                return self
+                    .reply(
+                        ctx,
+                        client,
+                        req,
+                        req_pending.is_some(),
+                        too_many_requests(&limited),
+                    )
+                    .await;
+            }
+        }
+        let fault = self
+            .faults
+            .as_ref()
+            .and_then(|f| f.pick(&host, &req.uri.path));
+        if let Some(fault) = fault {
+            info!(%host, %fault, "[+] Injecting fault");
+            metrics()
+                .injected_faults
+                .with_label_values(&[fault.as_str()])
+                .inc();
+        }
+        match fault {
+            Some(Fault::Reset) => return Ok(Next::Reset),
+            Some(Fault::Status { status }) => {
+                return self
+                    .reply(
+                        ctx,
+                        client,
+                        req,
+                        req_pending.is_some(),
+                        fault_response(status),
+                    )
+                    .await
+            }
+            _ => {}
+        }
+        if self.trace_propagation.should_inject(&req.headers) {
+            inject_context(&span, &mut req.headers);
+        }
+        let tls = req.uri.scheme.as_deref() == Some("https");
+        let key = (host.clone(), port, tls);
+        let mut timings = Timings {
+            blocked: Some(ms(started.elapsed())),
+            ..Default::default()
+        };
+        if upstream.as_ref().map(|(k, _)| k) != Some(&key) {
+            // ACL 检查过的地址直接连接, 不再重新解析
+            let connected = match &pinned {
+                Some(addrs) => Upstream::connect_addrs(&host, port, addrs, tls, &mut timings).await,
+                None => Upstream::connect_timed(&host, port, tls, &mut timings).await,
+            };
+            match connected {
+                Ok(conn) => *upstream = Some((key, Conn::new(conn))),
+                Err(err) => {
+                    warn!(%host, port, error = ?err, "[-] Upstream connect failed");
+                    self.interceptors.on_error(ctx, &err).await;
+                    let mut resp = Response::new(502).with_header("Connection", "close");
+                    resp.timings = timings;
+                    write_response(&mut client.stream, &req, &mut resp).await?;
+                    self.finish_exchange(ctx, req, resp).await;
+                    // 覆盖 finish_exchange 记录的状态码
+                    span.record("error.type", "connect");
+                    return Ok(Next::Close);
+                }
+            }
+        }
+        let (_, server) = upstream.as_mut().expect("upstream connected above");
+
+        let mut sent_at = None;
+        let exchange = async {
+            let sending = Instant::now();
+            send_request(&mut req, req_pending, client, server).await?;
+            timings.send = Some(ms(sending.elapsed()));
+            sent_at = Some(SystemTime::now());
+            server
+                .read_response(&req.method, crate::MAX_CAPTURE_SIZE)
+                .await
+        };
+        let (mut resp, resp_pending) = match exchange.await {
+            Ok(read) => read,
             Err(e) => {
-                eprintln!("[-] Connection error: {:?}", e);
+                let err = anyhow::Error::new(e).context(format!(
+                    "[-] Upstream exchange with {}:{} failed",
+                    host, port
+                ));
+                warn!(%host, port, error = ?err, "[-] Upstream exchange failed");
+                self.interceptors.on_error(ctx, &err).await;
+                let mut resp = Response::new(502).with_header("Connection", "close");
+                resp.timings = timings;
+                write_response(&mut client.stream, &req, &mut resp).await?;
+                self.finish_exchange(ctx, req, resp).await;
+                // 覆盖 finish_exchange 记录的状态码
+                span.record("error.type", "upstream");
+                return Ok(Next::Close);
             }
+        };
+        timings.wait = sent_at
+            .zip(resp.received_at)
+            .and_then(|(sent, first)| ms_between(sent, first));
+        resp.timings = timings;
+        resp.tls = server.stream.tls_info(&host);
+        if let Some(Fault::Delay { ms }) = fault {
+            tokio::time::sleep(time::Duration::from_millis(ms)).await;
+        }
+        let body_fault = fault.as_ref().and_then(StreamFault::new);
+
+        if resp.status == 101 {
+            client.stream.write_all(&resp.to_head()).await?;
+            self.finish_exchange(ctx, req, resp).await;
+            return Ok(Next::Upgrade(body_fault));
+        }
+
+        let prefix = resp_pending.map(|_| resp.body.clone());
+        if let Verdict::Drop = self.interceptors.on_response(ctx, &req, &mut resp).await {
+            return Ok(Next::Close);
+        }
+        // 拦截器改写了只读到前缀的响应体: 改写后的消息体作为完整响应发出, 丢弃上游剩余部分
+        let mut resp_pending = resp_pending;
+        let discarded = resp_pending.is_some() && prefix.is_some_and(|prefix| resp.body != prefix);
+        if discarded {
+            resp_pending = None;
+        }
+        // 以连接关闭为结束的响应体无法再用 Content-Length 转发, 客户端连接也随之关闭
+        let until_close = resp_pending.is_some_and(|p| p.framing == Framing::UntilClose);
+        // 截断的响应体之后不能再复用连接
+        let truncated = matches!(fault, Some(Fault::Truncate { .. }));
+        let close = req.wants_close() || until_close || truncated;
+        let upstream_close = resp.wants_close() || until_close || truncated || discarded;
+        match resp_pending {
+            None => {
+                if close {
+                    resp.headers.insert("Connection", "close");
+                }
+                write_response_with(&mut client.stream, &req, &mut resp, body_fault).await?;
+            }
+            Some(pending) => {
+                // 超过缓存上限, 头部和已缓存部分先发出, 剩余部分边读边转发.
+                // 分帧头部按上游原样恢复, 拦截器的修改不能影响剩余部分
+                apply_framing(&mut resp.headers, pending.framing);
+                if close {
+                    resp.headers.insert("Connection", "close");
+                }
+                client.stream.write_all(&resp.to_head()).await?;
+                let mut body = Faulty::new(&mut client.stream, body_fault);
+                write_prefix(&mut body, &resp.body, &pending).await?;
+                if let Some((_, server)) = upstream.as_mut() {
+                    server.relay_body(pending, &mut body).await?;
+                }
+                body.flush().await?;
+                resp.completed_at = Some(SystemTime::now());
+            }
+        }
+        resp.timings.receive = resp
+            .received_at
+            .zip(resp.completed_at)
+            .and_then(|(first, last)| ms_between(first, last));
+        if upstream_close {
+            *upstream = None;
+        }
+        self.finish_exchange(ctx, req, resp).await;
+        Ok(if close { Next::Close } else { Next::KeepAlive })
+    }
+
+    /// 不连接上游直接回复. 请求体还没读完时之后关闭连接
+    async fn reply<C>(
+        &mut self,
+        ctx: &InterceptContext,
+        client: &mut Conn<C>,
+        req: Request,
+        body_pending: bool,
+        mut resp: Response,
+    ) -> Result<Next, anyhow::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let close = body_pending || req.wants_close();
+        if close {
+            resp.headers.insert("Connection", "close");
+        }
+        write_response(&mut client.stream, &req, &mut resp).await?;
+        self.finish_exchange(ctx, req, resp).await;
+        Ok(if close { Next::Close } else { Next::KeepAlive })
+    }
+
+    /// 记录一次完成的请求/响应
+    async fn finish_exchange(&mut self, ctx: &InterceptContext, req: Request, mut resp: Response) {
+        resp.timings.client_tls = self.client_tls.take();
+        resp.timings.total = req
+            .received_at
+            .and_then(|t| ms_between(t, SystemTime::now()));
+        if self
+            .log_filter
+            .as_ref()
+            .is_none_or(|f| f.matches(&req, Some(&resp)))
+        {
+            info!(method = req.method.as_str(), url = %req.uri, status = resp.status, reason = %resp.reason, timings = %resp.timings, "[+] Exchange completed");
+        }
+        metrics().observe_exchange(&req, &resp);
+        let span = Span::current();
+        span.record("http.response.status_code", resp.status);
+        // 客户端 span 的约定: 4xx 和 5xx 都算错误
+        if resp.status >= 400 {
+            span.record("otel.status_code", "ERROR");
+            span.record("error.type", resp.status.to_string());
+        }
+        self.interceptors.on_complete(ctx, &req, &resp).await;
+        self.request = req;
+        self.response = resp;
+    }
+
+    /// 协议升级后按原始字节双向转发, 数据交给拦截器的 `on_tunnel_data`
+    async fn tunnel<C>(
+        &mut self,
+        client: Conn<C>,
+        server: Conn<Upstream>,
+        ctx: &InterceptContext,
+        shaper: Option<&Shaper>,
+        fault: Option<StreamFault>,
+    ) -> Result<(), anyhow::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let (mut client_stream, client_buf) = client.into_parts();
+        let (mut server_stream, server_buf) = server.into_parts();
+        let chain = self.interceptors.clone();
+        let tap_ctx = ctx.clone();
+        let tap: crate::copy::Tap =
+            Arc::new(move |direction, data| chain.on_tunnel_data(&tap_ctx, direction, data));
+        // 升级前已经读入缓冲的数据先发出去
+        if !client_buf.is_empty() {
+            tap(Direction::Request, &client_buf);
+            server_stream.write_all(&client_buf).await?;
+        }
+        if !server_buf.is_empty() {
+            tap(Direction::Response, &server_buf);
+            client_stream.write_all(&server_buf).await?;
+        }
+        info!("[+] Switched to raw tunnel");
+        match copy_bidirectional_shaped(&mut client_stream, &mut server_stream, tap, shaper, fault)
+            .await
+        {
+            Ok((sent, received)) => debug!(
+                sent = sent.amt,
+                received = received.amt,
+                "[+] Tunnel closed"
+            ),
+            Err(e) => debug!(error = %e, "[-] Tunnel closed with error"),
         }
         Ok(())
     }
-    
 }
 
+/// 把请求写给上游. 完整缓存的请求体改用 `Content-Length`, 超过上限的继续从客户端转发
+async fn send_request<C>(
+    req: &mut Request,
+    pending: Option<Pending>,
+    client: &mut Conn<C>,
+    server: &mut Conn<Upstream>,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match pending {
+        None => {
+            if !req.body.is_empty() || crate::http1::request_framing(&req.headers) != Framing::Empty
+            {
+                fix_framing(&mut req.headers, req.body.len());
+            }
+            let mut data = req.to_origin_head();
+            data.extend_from_slice(&req.body);
+            server.stream.write_all(&data).await?;
+        }
+        Some(pending) => {
+            apply_framing(&mut req.headers, pending.framing);
+            server.stream.write_all(&req.to_origin_head()).await?;
+            write_prefix(&mut server.stream, &req.body, &pending).await?;
+            client.relay_body(pending, &mut server.stream).await?;
+        }
+    }
+    server.stream.flush().await
+}
+
+/// 把完整缓存的响应写给客户端, 有消息体的响应改用 `Content-Length`
+async fn write_response<W>(client: &mut W, req: &Request, resp: &mut Response) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_response_with(client, req, resp, None).await
+}
+
+/// 与 `write_response` 相同, 消息体按 `fault` 停顿或截断
+async fn write_response_with<W>(
+    client: &mut W,
+    req: &Request,
+    resp: &mut Response,
+    fault: Option<StreamFault>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let has_body = response_framing(&req.method, resp.status, &resp.headers) != Framing::Empty;
+    if has_body || !resp.body.is_empty() {
+        fix_framing(&mut resp.headers, resp.body.len());
+    }
+    let mut data = resp.to_head();
+    let Some(fault) = fault else {
+        if req.method != Method::HEAD {
+            data.extend_from_slice(&resp.body);
+        }
+        client.write_all(&data).await?;
+        return client.flush().await;
+    };
+    client.write_all(&data).await?;
+    if req.method != Method::HEAD {
+        Faulty::new(&mut *client, Some(fault))
+            .write_all(&resp.body)
+            .await?;
+    }
+    client.flush().await
+}
@@ -0,0 +1,502 @@
+use std::io::{Read as _, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, ToSql};
+use tracing::error;
+
+use crate::filter::Filter;
+use crate::intercept::{InterceptContext, Interceptor};
+use crate::prelude::*;
+
+/// 超过这个大小的消息体才压缩
+const COMPRESS_THRESHOLD: usize = 1024;
+/// 每写入多少条记录检查一次保留策略
+const RETENTION_INTERVAL: u64 = 100;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS exchanges (
+    id            INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id    INTEGER NOT NULL,
+    client_addr   TEXT,
+    tls           INTEGER NOT NULL,
+    method        TEXT NOT NULL,
+    scheme        TEXT,
+    host          TEXT NOT NULL,
+    port          INTEGER,
+    path          TEXT NOT NULL,
+    query         TEXT,
+    status        INTEGER NOT NULL,
+    reason        TEXT NOT NULL,
+    error         TEXT,
+    req_head      BLOB NOT NULL,
+    req_body      BLOB NOT NULL,
+    req_body_z    INTEGER NOT NULL,
+    resp_head     BLOB NOT NULL,
+    resp_body     BLOB NOT NULL,
+    resp_body_z   INTEGER NOT NULL,
+    size          INTEGER NOT NULL,
+    started_at    INTEGER NOT NULL,
+    received_at   INTEGER,
+    completed_at  INTEGER,
+    duration_ms   INTEGER,
+    timings       TEXT,
+    client_tls_version    TEXT,
+    client_tls_cipher     TEXT,
+    client_sni            TEXT,
+    upstream_tls_version  TEXT,
+    upstream_tls_cipher   TEXT,
+    upstream_sni          TEXT
+);
+CREATE INDEX IF NOT EXISTS idx_exchanges_host ON exchanges(host);
+CREATE INDEX IF NOT EXISTS idx_exchanges_path ON exchanges(path);
+CREATE INDEX IF NOT EXISTS idx_exchanges_status ON exchanges(status);
+CREATE INDEX IF NOT EXISTS idx_exchanges_started_at ON exchanges(started_at);
+";
+
+/// 存储选项
+#[derive(Debug,Clone)]
+pub struct StoreOptions {
+    /// 消息体用 zlib 压缩后保存
+    pub compress_bodies: bool,
+    /// 删除早于这个时间的记录
+    pub max_age: Option<Duration>,
+    /// 所有记录的总大小(头部加消息体, 压缩前)上限, 超出时从最旧的开始删除
+    pub max_size: Option<u64>,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions { compress_bodies: true, max_age: None, max_size: None }
+    }
+}
+
+/// 查询条件, 未设置的条件不限制. 结果按时间倒序
+#[derive(Debug,Clone,Default)]
+pub struct Query {
+    /// 主机通配符, 如 `*.example.com`
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+    pub method: Option<String>,
+    pub status_min: Option<u16>,
+    pub status_max: Option<u16>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    /// 在 SQL 条件之后再用过滤表达式筛选, `limit`/`offset` 按筛选后的结果计算
+    pub filter: Option<Filter>,
+}
+
+/// 从数据库读出的一个事务
+#[derive(Debug,Clone,serde::Serialize)]
+pub struct StoredExchange {
+    pub id: i64,
+    pub session_id: u32,
+    pub client_addr: Option<String>,
+    pub tls: bool,
+    /// 与客户端握手协商的参数, 上游的在 `response.tls`
+    pub client_tls: Option<TlsInfo>,
+    pub request: Request,
+    pub response: Response,
+    pub started_at: SystemTime,
+    pub duration_ms: Option<u64>,
+}
+
+/// 基于 SQLite 的事务存储, 作为拦截器注册后在每个事务结束时写入
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+    path: Option<PathBuf>,
+    options: StoreOptions,
+    inserted: Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store").field("options", &self.options).finish()
+    }
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>, options: StoreOptions) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let conn = Connection::open(path).with_context(|| format!("[-] Failed to open session store {}", path.display()))?;
+        Self::init(conn, Some(path.to_path_buf()), options)
+    }
+
+    pub fn open_in_memory(options: StoreOptions) -> Result<Self, anyhow::Error> {
+        Self::init(Connection::open_in_memory()?, None, options)
+    }
+
+    fn init(conn: Connection, path: Option<PathBuf>, options: StoreOptions) -> Result<Self, anyhow::Error> {
+        conn.execute_batch("PRAGMA journal_mode = WAL;").context("[-] Failed to set journal mode")?;
+        conn.execute_batch(SCHEMA).context("[-] Failed to create session store schema")?;
+        Ok(Store { conn: Arc::new(Mutex::new(conn)), path, options, inserted: Arc::default() })
+    }
+
+    /// 数据库文件路径, 内存数据库为None
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 把当前所有记录归档到 `archive` (新的SQLite文件) 并清空, 返回归档的数量
+    pub fn rotate(&self, archive: impl AsRef<Path>) -> Result<u64, anyhow::Error> {
+        let archive = archive.as_ref();
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM exchanges", [], |row| row.get(0))?;
+        conn.execute("VACUUM INTO ?1", [archive.to_string_lossy()])
+            .with_context(|| format!("[-] Failed to archive session store to {}", archive.display()))?;
+        conn.execute("DELETE FROM exchanges", [])?;
+        Ok(count as u64)
+    }
+
+    /// 写入一个完成的事务, 返回记录的id
+    pub fn insert(&self, ctx: &InterceptContext, req: &Request, resp: &Response) -> Result<i64, anyhow::Error> {
+        let req_head = request_head(req);
+        let resp_head = response_head(resp);
+        let (req_body, req_z) = self.pack(&req.body)?;
+        let (resp_body, resp_z) = self.pack(&resp.body)?;
+        let size = (req_head.len() + req.body.len() + resp_head.len() + resp.body.len()) as i64;
+        let started_at = req.received_at.or(resp.received_at).unwrap_or_else(SystemTime::now);
+        let duration = resp.completed_at.and_then(|end| end.duration_since(started_at).ok());
+        let (host, port) = req.target().unzip();
+        let (client_tls, upstream_tls) = (ctx.client_tls.as_ref(), resp.tls.as_ref());
+
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO exchanges (session_id, client_addr, tls, method, scheme, host, port, path, query, status, reason, error,
+                req_head, req_body, req_body_z, resp_head, resp_body, resp_body_z, size, started_at, received_at, completed_at, duration_ms, timings,
+                client_tls_version, client_tls_cipher, client_sni, upstream_tls_version, upstream_tls_cipher, upstream_sni)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24,
+                ?25, ?26, ?27, ?28, ?29, ?30)",
+            params![
+                ctx.session_id,
+                ctx.client_addr.map(|a| a.to_string()),
+                ctx.tls || req.uri.scheme.as_deref() == Some("https"),
+                req.method.as_str(),
+                req.uri.scheme,
+                host.unwrap_or_default().to_lowercase(),
+                port,
+                req.uri.path,
+                req.uri.query,
+                resp.status,
+                resp.reason,
+                resp.error,
+                req_head,
+                req_body,
+                req_z,
+                resp_head,
+                resp_body,
+                resp_z,
+                size,
+                millis(started_at),
+                resp.received_at.map(millis),
+                resp.completed_at.map(millis),
+                duration.map(|d| d.as_millis() as i64),
+                (!resp.timings.is_empty()).then(|| serde_json::to_string(&resp.timings)).transpose()?,
+                client_tls.map(|t| &t.version),
+                client_tls.map(|t| &t.cipher),
+                client_tls.and_then(|t| t.sni.as_ref()),
+                upstream_tls.map(|t| &t.version),
+                upstream_tls.map(|t| &t.cipher),
+                upstream_tls.and_then(|t| t.sni.as_ref()),
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn pack(&self, body: &[u8]) -> Result<(Vec<u8>, bool), anyhow::Error> {
+        if !self.options.compress_bodies || body.len() < COMPRESS_THRESHOLD {
+            return Ok((body.to_vec(), false));
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(body)?;
+        Ok((encoder.finish()?, true))
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<StoredExchange>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM exchanges WHERE id = ?1", COLUMNS))?;
+        stmt.query_row([id], read_row).optional()?.transpose()
+    }
+
+    pub fn query(&self, query: &Query) -> Result<Vec<StoredExchange>, anyhow::Error> {
+        let mut sql = format!("SELECT {} FROM exchanges WHERE 1 = 1", COLUMNS);
+        let mut args: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(host) = &query.host {
+            sql.push_str(" AND host GLOB ?");
+            args.push(Box::new(host.to_lowercase()));
+        }
+        if let Some(prefix) = &query.path_prefix {
+            sql.push_str(" AND substr(path, 1, ?) = ?");
+            args.push(Box::new(prefix.chars().count() as i64));
+            args.push(Box::new(prefix.clone()));
+        }
+        if let Some(method) = &query.method {
+            sql.push_str(" AND method = ?");
+            args.push(Box::new(method.to_uppercase()));
+        }
+        if let Some(min) = query.status_min {
+            sql.push_str(" AND status >= ?");
+            args.push(Box::new(min));
+        }
+        if let Some(max) = query.status_max {
+            sql.push_str(" AND status <= ?");
+            args.push(Box::new(max));
+        }
+        if let Some(since) = query.since {
+            sql.push_str(" AND started_at >= ?");
+            args.push(Box::new(millis(since)));
+        }
+        if let Some(until) = query.until {
+            sql.push_str(" AND started_at < ?");
+            args.push(Box::new(millis(until)));
+        }
+        sql.push_str(" ORDER BY started_at DESC, id DESC");
+        if query.filter.is_none() {
+            sql.push_str(" LIMIT ? OFFSET ?");
+            args.push(Box::new(query.limit.map(|l| l as i64).unwrap_or(-1)));
+            args.push(Box::new(query.offset as i64));
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(args.iter().map(|a| a.as_ref())), read_row)?;
+        let Some(filter) = &query.filter else {
+            return rows.map(|row| row?).collect();
+        };
+        let mut result = Vec::new();
+        let mut skipped = 0;
+        for row in rows {
+            let exchange = row??;
+            if !filter.matches(&exchange.request, Some(&exchange.response)) {
+                continue;
+            }
+            if skipped < query.offset {
+                skipped += 1;
+                continue;
+            }
+            result.push(exchange);
+            if query.limit.is_some_and(|l| result.len() >= l) {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn count(&self) -> Result<u64, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row("SELECT COUNT(*) FROM exchanges", [], |row| row.get::<_, i64>(0))? as u64)
+    }
+
+    /// 所有记录的总大小, 与 `StoreOptions::max_size` 的口径一致
+    pub fn total_size(&self) -> Result<u64, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row("SELECT COALESCE(SUM(size), 0) FROM exchanges", [], |row| row.get::<_, i64>(0))? as u64)
+    }
+
+    /// 按保留策略删除记录, 返回删除的数量
+    pub fn enforce_retention(&self) -> Result<usize, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut deleted = 0;
+        if let Some(max_age) = self.options.max_age {
+            let cutoff = SystemTime::now().checked_sub(max_age).unwrap_or(UNIX_EPOCH);
+            deleted += conn.execute("DELETE FROM exchanges WHERE started_at < ?1", [millis(cutoff)])?;
+        }
+        if let Some(max_size) = self.options.max_size {
+            let total: i64 = conn.query_row("SELECT COALESCE(SUM(size), 0) FROM exchanges", [], |row| row.get(0))?;
+            let mut excess = total - max_size as i64;
+            if excess > 0 {
+                // 找到需要删除的最旧记录的边界
+                let mut stmt = conn.prepare("SELECT id, size FROM exchanges ORDER BY id")?;
+                let mut rows = stmt.query([])?;
+                let mut cutoff = None;
+                while let Some(row) = rows.next()? {
+                    let (id, size): (i64, i64) = (row.get(0)?, row.get(1)?);
+                    cutoff = Some(id);
+                    excess -= size;
+                    if excess <= 0 {
+                        break;
+                    }
+                }
+                drop(rows);
+                if let Some(cutoff) = cutoff {
+                    deleted += conn.execute("DELETE FROM exchanges WHERE id <= ?1", [cutoff])?;
+                }
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+#[async_trait::async_trait]
+impl Interceptor for Store {
+    async fn on_complete(&self, ctx: &InterceptContext, req: &Request, resp: &Response) {
+        let store = self.clone();
+        let (ctx, req, resp) = (ctx.clone(), req.clone(), resp.clone());
+        // SQLite 是同步的, 放到阻塞线程池里执行
+        let result = tokio::task::spawn_blocking(move || {
+            store.insert(&ctx, &req, &resp)?;
+            let inserted = store.inserted.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+            if inserted.is_multiple_of(RETENTION_INTERVAL) {
+                store.enforce_retention()?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await;
+        match result {
+            Ok(Err(e)) => error!(error = ?e, "[-] Failed to store exchange"),
+            Err(e) => error!(error = ?e, "[-] Store task failed"),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+const COLUMNS: &str = "id, session_id, client_addr, tls, req_head, req_body, req_body_z, resp_head, resp_body, resp_body_z,
+    started_at, received_at, completed_at, duration_ms, error, timings,
+    client_tls_version, client_tls_cipher, client_sni, upstream_tls_version, upstream_tls_cipher, upstream_sni";
+
+fn read_row(row: &Row) -> rusqlite::Result<Result<StoredExchange, anyhow::Error>> {
+    let req_head: Vec<u8> = row.get(4)?;
+    let req_body = unpack(row.get(5)?, row.get(6)?);
+    let resp_head: Vec<u8> = row.get(7)?;
+    let resp_body = unpack(row.get(8)?, row.get(9)?);
+    let started_at: i64 = row.get(10)?;
+    let received_at: Option<i64> = row.get(11)?;
+    let completed_at: Option<i64> = row.get(12)?;
+    let duration_ms: Option<i64> = row.get(13)?;
+    let error: Option<String> = row.get(14)?;
+    let timings: Option<String> = row.get(15)?;
+    let client_tls = read_tls(row, 16)?;
+    let upstream_tls = read_tls(row, 19)?;
+    let (id, session_id, client_addr, tls) = (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+    Ok((|| {
+        let mut request = Request::from_bytes(&req_head).ok_or_else(|| anyhow!("[-] bad stored request head"))?;
+        request.body = Bytes::from(req_body?);
+        request.received_at = Some(from_millis(started_at));
+        let mut response = Response::from_bytes(&resp_head)?;
+        response.body = Bytes::from(resp_body?);
+        response.error = error;
+        response.received_at = received_at.map(from_millis);
+        response.completed_at = completed_at.map(from_millis);
+        if let Some(timings) = timings {
+            response.timings = serde_json::from_str(&timings).context("[-] bad stored timings")?;
+        }
+        response.tls = upstream_tls;
+        Ok(StoredExchange {
+            id,
+            session_id,
+            client_addr,
+            tls,
+            client_tls,
+            request,
+            response,
+            started_at: from_millis(started_at),
+            duration_ms: duration_ms.map(|d| d as u64),
+        })
+    })())
+}
+
+/// 从 `start` 开始的版本, 密码套件和 SNI 三列, 没有握手时版本为NULL
+fn read_tls(row: &Row, start: usize) -> rusqlite::Result<Option<TlsInfo>> {
+    let Some(version) = row.get::<_, Option<String>>(start)? else {
+        return Ok(None);
+    };
+    Ok(Some(TlsInfo { version, cipher: row.get::<_, Option<String>>(start + 1)?.unwrap_or_default(), sni: row.get(start + 2)? }))
+}
+
+fn unpack(data: Vec<u8>, compressed: bool) -> Result<Vec<u8>, anyhow::Error> {
+    if !compressed {
+        return Ok(data);
+    }
+    let mut out = Vec::new();
+    ZlibDecoder::new(&data[..]).read_to_end(&mut out).context("[-] corrupt stored body")?;
+    Ok(out)
+}
+
+/// 请求行和头部, 请求目标保存为完整的 URI
+fn request_head(req: &Request) -> Vec<u8> {
+    let mut head = format!("{} {} {}\r\n", req.method.as_str(), req.uri, req.http_version).into_bytes();
+    let mut headers = req.headers.clone();
+    headers.redact_credentials();
+    headers.write_to(&mut head);
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn response_head(resp: &Response) -> Vec<u8> {
+    let version = if resp.http_version.is_empty() { "HTTP/1.1" } else { resp.http_version.as_str() };
+    let mut head = format!("{} {} {}\r\n", version, resp.status, resp.reason).into_bytes();
+    resp.headers.write_to(&mut head);
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn millis(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or_default()
+}
+
+fn from_millis(ms: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timing::Timings;
+
+    fn exchange(url: &str, status: u16, body: &str) -> (Request, Response) {
+        let mut req = Request::from_string(&format!("POST {} HTTP/1.1\r\nX-Req: 1\r\n\r\n", url)).unwrap();
+        req.body = Bytes::from("token=abc");
+        req.received_at = Some(SystemTime::now());
+        let mut resp = Response::new(status).with_header("Content-Type", "text/plain").with_body(body.to_string());
+        resp.completed_at = Some(SystemTime::now());
+        (req, resp)
+    }
+
+    #[test]
+    fn insert_query_and_retention() {
+        let store = Store::open_in_memory(StoreOptions::default()).unwrap();
+        let tls = |sni: &str| TlsInfo { version: "TLSv1_3".to_string(), cipher: "TLS13_AES_128_GCM_SHA256".to_string(), sni: Some(sni.to_string()) };
+        let ctx = InterceptContext { session_id: 7, tls: true, client_tls: Some(tls("api.example.com")), ..Default::default() };
+        let big = "x".repeat(10_000);
+        let (req, mut resp) = exchange("https://api.example.com/v1/users?page=1", 200, &big);
+        resp.timings = Timings { dns: Some(1.5), wait: Some(20.0), ..Default::default() };
+        resp.tls = Some(TlsInfo { sni: None, ..tls("") });
+        let id = store.insert(&ctx, &req, &resp).unwrap();
+        let (req, resp) = exchange("http://www.example.com/login", 500, "boom");
+        store.insert(&InterceptContext::default(), &req, &resp).unwrap();
+        let (req, resp) = exchange("https://api.example.com/v2/items", 404, "");
+        store.insert(&ctx, &req, &resp).unwrap();
+
+        let stored = store.get(id).unwrap().unwrap();
+        assert_eq!((stored.session_id, stored.tls), (7, true));
+        assert_eq!((&stored.client_tls, &stored.response.tls), (&Some(tls("api.example.com")), &Some(TlsInfo { sni: None, ..tls("") })));
+        assert_eq!(stored.request.uri.to_string(), "https://api.example.com/v1/users?page=1");
+        assert_eq!((stored.request.header("x-req"), &stored.request.body[..]), (Some("1"), &b"token=abc"[..]));
+        assert_eq!((stored.response.status, stored.response.text().len()), (200, 10_000));
+        assert_eq!((stored.response.timings.dns, stored.response.timings.wait), (Some(1.5), Some(20.0)));
+
+        let hits = store.query(&Query { host: Some("API.*".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(hits.len(), 2);
+        let hits = store.query(&Query { path_prefix: Some("/v1/".to_string()), status_max: Some(299), ..Default::default() }).unwrap();
+        assert_eq!(hits.iter().map(|e| e.id).collect::<Vec<_>>(), vec![id]);
+        let hits = store.query(&Query { status_min: Some(400), limit: Some(1), ..Default::default() }).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert!(store.query(&Query { until: Some(UNIX_EPOCH), ..Default::default() }).unwrap().is_empty());
+        let filter = Some(Filter::parse("status >= 400 && resp.body contains BOOM").unwrap());
+        let hits = store.query(&Query { filter, limit: Some(5), ..Default::default() }).unwrap();
+        assert_eq!(hits.iter().map(|e| e.request.uri.path.as_str()).collect::<Vec<_>>(), vec!["/login"]);
+        assert_eq!((&hits[0].client_tls, &hits[0].response.tls), (&None, &None));
+
+        // 总大小超限时删除最旧的记录
+        let store = Store { options: StoreOptions { max_size: Some(store.total_size().unwrap() - 1), ..Default::default() }, ..store };
+        assert_eq!(store.enforce_retention().unwrap(), 1);
+        assert!(store.get(id).unwrap().is_none());
+        assert_eq!(store.count().unwrap(), 2);
+    }
+}
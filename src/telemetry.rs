@@ -0,0 +1,360 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::{field, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::header::HeaderMap;
+use crate::prelude::*;
+
+/// 未设置 `RUST_LOG` 时的过滤规则
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+/// 日志输出格式
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum LogFormat {
+    /// 多行, 便于人读
+    #[default] Pretty,
+    /// 单行
+    Compact,
+    /// 每行一个 JSON 对象, 带上所在 span 的字段
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            other => Err(anyhow!("[-] unknown log format: {}", other)),
+        }
+    }
+}
+
+/// 日志配置
+#[derive(Debug,Clone,Default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// `EnvFilter` 语法, 如 `info,https_req_tcp::session=debug`
+    pub filter: Option<String>,
+    /// 写入文件而不是 stdout, 终端界面运行时使用
+    pub file: Option<PathBuf>,
+    /// OTLP/HTTP 收集器地址, 如 `http://127.0.0.1:4318`
+    pub otlp_endpoint: Option<String>,
+}
+
+impl LogConfig {
+    /// 从环境变量读取: `PROXY_LOG_FORMAT`, `RUST_LOG`, `PROXY_LOG_FILE`, `OTEL_EXPORTER_OTLP_ENDPOINT`
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        Ok(LogConfig {
+            format: var("PROXY_LOG_FORMAT").map(|f| f.parse()).transpose()?.unwrap_or_default(),
+            filter: var("RUST_LOG"),
+            file: var("PROXY_LOG_FILE").map(PathBuf::from),
+            otlp_endpoint: var("OTEL_EXPORTER_OTLP_ENDPOINT"),
+        })
+    }
+}
+
+/// 转发请求时如何处理 W3C `traceparent` 头
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum TracePropagation {
+    /// 不读也不改
+    #[default] Off,
+    /// 请求带有 `traceparent` 时把事务 span 接到该链路上, 并把头改写为事务 span
+    Continue,
+    /// 同 `Continue`, 没有 `traceparent` 时新开一条链路并注入
+    Inject,
+}
+
+impl TracePropagation {
+    fn continues(self, headers: &HeaderMap) -> bool {
+        self != TracePropagation::Off && headers.contains("traceparent")
+    }
+
+    pub(crate) fn should_inject(self, headers: &HeaderMap) -> bool {
+        self == TracePropagation::Inject || self.continues(headers)
+    }
+}
+
+impl FromStr for TracePropagation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" | "none" => Ok(TracePropagation::Off),
+            "continue" => Ok(TracePropagation::Continue),
+            "inject" => Ok(TracePropagation::Inject),
+            other => Err(anyhow!("[-] unknown trace propagation: {}", other)),
+        }
+    }
+}
+
+/// 一次代理事务的 span, 字段按 OpenTelemetry HTTP 客户端语义约定命名.
+/// 目标地址和响应状态在处理过程中补上
+pub(crate) fn exchange_span(req: &Request, propagation: TracePropagation) -> Span {
+    let method = req.method.as_str();
+    let span = info_span!(
+        "http.exchange",
+        otel.name = method,
+        otel.kind = "client",
+        otel.status_code = field::Empty,
+        http.request.method = method,
+        http.response.status_code = field::Empty,
+        url.full = %req.uri,
+        server.address = field::Empty,
+        server.port = field::Empty,
+        network.protocol.version = req.http_version.trim_start_matches("HTTP/"),
+        error.type = field::Empty,
+    );
+    if propagation.continues(&req.headers) {
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(&req.headers));
+        if parent.span().span_context().is_valid() {
+            span.set_parent(parent);
+        }
+    }
+    span
+}
+
+/// 把 span 的链路上下文写入 `traceparent`/`tracestate`.
+/// 没有安装 OpenTelemetry 层时上下文无效, 原来的头保持不变
+pub(crate) fn inject_context(span: &Span, headers: &mut HeaderMap) {
+    let cx = span.context();
+    if !cx.span().span_context().is_valid() {
+        return;
+    }
+    headers.remove("tracestate");
+    TraceContextPropagator::new().inject_context(&cx, &mut HeaderInjector(headers));
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_str(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(name, _)| name).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key, value);
+    }
+}
+
+/// 持有 OTLP 导出器, 释放时把缓存的 span 发出去
+pub struct TracingGuard {
+    provider: Option<TracerProvider>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("[-] Failed to flush OTLP spans: {:?}", e);
+            }
+        }
+    }
+}
+
+/// 安装全局 subscriber. 已经安装过时返回错误
+pub fn init_tracing(config: &LogConfig) -> Result<TracingGuard, anyhow::Error> {
+    let filter = EnvFilter::try_new(config.filter.as_deref().unwrap_or(DEFAULT_LOG_FILTER)).context("[-] Invalid log filter")?;
+    let mut layers = Vec::new();
+    match &config.file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("[-] Failed to open log file {}", path.display()))?;
+            layers.push(fmt_layer(config.format, std::sync::Mutex::new(file), false));
+        }
+        None => layers.push(fmt_layer(config.format, std::io::stdout, true)),
+    }
+    let provider = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let provider = otlp_provider(endpoint)?;
+            let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+            layers.push(tracing_opentelemetry::layer().with_tracer(tracer).boxed());
+            opentelemetry::global::set_tracer_provider(provider.clone());
+            Some(provider)
+        }
+        None => None,
+    };
+    Registry::default().with(layers).with(filter).try_init().context("[-] Tracing subscriber already installed")?;
+    Ok(TracingGuard { provider })
+}
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).with_span_list(false).boxed(),
+    }
+}
+
+/// 批量导出到 `<endpoint>/v1/traces`
+fn otlp_provider(endpoint: &str) -> Result<TracerProvider, anyhow::Error> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let url = if endpoint.ends_with("/v1/traces") { endpoint.to_string() } else { format!("{}/v1/traces", endpoint) };
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(url)
+        .build()
+        .context("[-] Failed to build OTLP exporter")?;
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", env!("CARGO_PKG_NAME"))]))
+        .build())
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+
+    use opentelemetry::trace::{SpanKind, Status};
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use tokio::net::{TcpListener, TcpStream};
+    use tracing::instrument::WithSubscriber;
+
+    use super::*;
+    use crate::session::Session;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_events_carry_session_fields() {
+        assert_eq!("JSON".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = Registry::default().with(fmt_layer(LogFormat::Json, move || writer.clone(), false));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("session", session_id = 7u32, client_addr = "127.0.0.1:5000", target = tracing::field::Empty);
+            span.record("target", "example.com:443");
+            span.in_scope(|| tracing::info!(status = 200, "[+] exchange completed"));
+        });
+        let line: serde_json::Value = serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        assert_eq!(line["fields"]["message"], "[+] exchange completed");
+        assert_eq!(line["fields"]["status"], 200);
+        assert_eq!(line["span"]["session_id"], 7);
+        assert_eq!(line["span"]["target"], "example.com:443");
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Collector {
+        fn export(&mut self, batch: Vec<SpanData>) -> Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn exchange_span_continues_traceparent() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        assert_eq!("Continue".parse::<TracePropagation>().unwrap(), TracePropagation::Continue);
+
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8; 1];
+                stream.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+            stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+            let req = format!(
+                "GET http://{}/missing HTTP/1.1\r\nHost: {}\r\ntraceparent: 00-{}-00f067aa0ba902b7-01\r\nConnection: close\r\n\r\n",
+                origin_addr, origin_addr, TRACE_ID
+            );
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).await.unwrap();
+            resp
+        });
+
+        let collector = Collector::default();
+        let provider = TracerProvider::builder().with_simple_exporter(collector.clone()).build();
+        let subscriber = Registry::default().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let (stream, addr) = proxy.accept().await.unwrap();
+        let mut session = Session::new(1, stream).unwrap();
+        session.trace_propagation = TracePropagation::Continue;
+        async {
+            session.session_connect(addr).await.unwrap();
+            session.forward_http().await.unwrap();
+        }
+        .with_subscriber(subscriber)
+        .await;
+
+        assert!(client.await.unwrap().starts_with("HTTP/1.1 404"));
+        let head = upstream.await.unwrap();
+        let spans = collector.0.lock().unwrap();
+        let span = spans.iter().find(|s| s.name == "GET").expect("exchange span exported");
+        assert_eq!(span.span_kind, SpanKind::Client);
+        assert_eq!(span.span_context.trace_id().to_string(), TRACE_ID);
+        assert_eq!(span.parent_span_id.to_string(), "00f067aa0ba902b7");
+        assert!(matches!(span.status, Status::Error { .. }));
+        let attr = |key: &str| span.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string());
+        assert_eq!(attr("http.request.method").as_deref(), Some("GET"));
+        assert_eq!(attr("http.response.status_code").as_deref(), Some("404"));
+        assert_eq!(attr("server.port"), Some(origin_addr.port().to_string()));
+        assert_eq!(attr("error.type").as_deref(), Some("404"));
+        // 上游收到的 traceparent 指向代理的事务 span, 链路不变
+        let expected = format!("traceparent: 00-{}-{}-01", TRACE_ID, span.span_context.span_id());
+        assert!(head.contains(&expected), "{}", head);
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+use crate::copy::Direction;
+use crate::config::load_config;
+use crate::prelude::*;
+
+/// 限速时每次最多写出的字节数, 约为一个 TCP 报文
+pub const PACKET_SIZE: usize = 1460;
+/// 按客户端/主机记录的令牌桶超过这个数量时清理已经回满的
+const MAX_TRACKED: usize = 1024;
+
+/// 令牌桶: 以 `rate` 每秒的速度补充, 最多积累 `capacity` 个
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// 初始是满的
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        TokenBucket { rate, capacity, state: Mutex::new((capacity, Instant::now())) }
+    }
+
+    /// 按字节限速的桶, 积累 100ms 的流量, 至少一个报文
+    pub fn bandwidth(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self::new(rate, (rate / 10.0).max(PACKET_SIZE as f64))
+    }
+
+    fn refill(&self) -> std::sync::MutexGuard<'_, (f64, Instant)> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.0 = (state.0 + now.duration_since(state.1).as_secs_f64() * self.rate).min(self.capacity);
+        state.1 = now;
+        state
+    }
+
+    /// 取 `n` 个令牌, 不够时返回还要等待的时间
+    pub fn try_take(&self, n: f64) -> Result<(), Duration> {
+        let mut state = self.refill();
+        if state.0 >= n {
+            state.0 -= n;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((n - state.0) / self.rate))
+        }
+    }
+
+    /// 取最多 `want` 个(不超过一个报文), 令牌不够一个报文时返回等待时间
+    pub fn take_up_to(&self, want: usize) -> Result<usize, Duration> {
+        let want = want.min(PACKET_SIZE);
+        self.try_take(want as f64).map(|()| want)
+    }
+
+    fn is_full(&self) -> bool {
+        self.refill().0 >= self.capacity
+    }
+}
+
+/// 常见网络条件, 上下行单位字节每秒, 延迟为往返延迟
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(try_from = "String")]
+pub struct NetworkPreset {
+    pub download: u64,
+    pub upload: u64,
+    pub latency: Duration,
+    pub jitter: Duration,
+}
+
+impl NetworkPreset {
+    pub const NAMES: &'static [&'static str] = &["2g", "slow-3g", "3g", "4g", "wifi"];
+
+    pub fn named(name: &str) -> Option<Self> {
+        let (download, upload, latency, jitter) = match name.to_ascii_lowercase().as_str() {
+            "2g" | "edge" => (31_250, 6_250, 800, 200),
+            "slow-3g" => (50_000, 50_000, 400, 100),
+            "3g" => (200_000, 93_750, 150, 30),
+            "4g" | "lte" => (2_500_000, 625_000, 60, 15),
+            "wifi" => (3_750_000, 1_875_000, 10, 3),
+            _ => return None,
+        };
+        Some(NetworkPreset { download, upload, latency: Duration::from_millis(latency), jitter: Duration::from_millis(jitter) })
+    }
+}
+
+impl FromStr for NetworkPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NetworkPreset::named(s).ok_or_else(|| anyhow!("[-] unknown network preset {:?}, expected one of {:?}", s, NetworkPreset::NAMES))
+    }
+}
+
+impl TryFrom<String> for NetworkPreset {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// 限速配置文件: YAML 或 TOML
+///
+/// ```yaml
+/// requests:
+///   per_client: 10    # 每个客户端每秒请求数
+///   per_host: 50      # 每个目标主机每秒请求数
+///   burst: 20
+/// network:
+///   preset: 3g        # 未单独设置的项取预设值
+///   download: 100000  # 字节每秒
+///   latency_ms: 200
+///   scope: session    # session 每个连接单独计算, global 所有连接共享
+/// ```
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct ThrottleConfig {
+    #[serde(default)]
+    pub requests: RequestLimits,
+    #[serde(default)]
+    pub network: NetworkConfig,
+}
+
+/// 请求速率, 超过时回复 429
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct RequestLimits {
+    pub per_client: Option<f64>,
+    pub per_host: Option<f64>,
+    /// 允许的突发请求数, 客户端和主机的限制共用, 默认等于每秒请求数
+    pub burst: Option<f64>,
+}
+
+/// 带宽和延迟, 作用于客户端连接
+#[derive(Debug,Clone,Default,Deserialize)]
+pub struct NetworkConfig {
+    pub preset: Option<NetworkPreset>,
+    /// 客户端发往上游的字节每秒
+    pub upload: Option<u64>,
+    /// 写回客户端的字节每秒
+    pub download: Option<u64>,
+    /// 响应方向空闲后第一段数据的额外延迟
+    pub latency_ms: Option<u64>,
+    /// 延迟在 ±jitter 内随机
+    pub jitter_ms: Option<u64>,
+    #[serde(default)]
+    pub scope: BandwidthScope,
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BandwidthScope {
+    #[default]
+    Session,
+    Global,
+}
+
+impl ThrottleConfig {
+    /// 按扩展名读取 `.yaml`/`.yml` 或 `.toml` 配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let config: ThrottleConfig = load_config(path, "throttle")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 请求速率和突发数必须是大于 0 的有限数
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let requests = &self.requests;
+        for (name, value) in [("per_client", requests.per_client), ("per_host", requests.per_host), ("burst", requests.burst)] {
+            if let Some(value) = value.filter(|v| !(v.is_finite() && *v > 0.0)) {
+                return Err(anyhow!("[-] throttle requests.{} must be a positive number: {}", name, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// 没有任何限制
+    pub fn is_empty(&self) -> bool {
+        self.requests.per_client.is_none() && self.requests.per_host.is_none() && Shaper::from_config(&self.network).is_none()
+    }
+}
+
+/// 超过请求速率, 见 [`Throttle::check_request`]
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct RateLimited {
+    /// client 或 host
+    pub scope: &'static str,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rate limit exceeded, retry after {:.1}s", self.scope, self.retry_after.as_secs_f64())
+    }
+}
+
+/// 请求速率限制和带宽整形
+#[derive(Debug)]
+pub struct Throttle {
+    config: ThrottleConfig,
+    clients: Mutex<HashMap<IpAddr, TokenBucket>>,
+    hosts: Mutex<HashMap<String, TokenBucket>>,
+    /// `scope: global` 时所有连接共享的桶
+    global: Option<Shaper>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        let global = match config.network.scope {
+            BandwidthScope::Global => Shaper::from_config(&config.network),
+            BandwidthScope::Session => None,
+        };
+        Throttle { config, clients: Mutex::default(), hosts: Mutex::default(), global }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        Ok(Throttle::new(ThrottleConfig::load(path)?))
+    }
+
+    pub fn config(&self) -> &ThrottleConfig {
+        &self.config
+    }
+
+    /// 计入一个请求, 先看客户端再看目标主机. 被拒绝的请求不占用主机的额度
+    pub fn check_request(&self, client: Option<IpAddr>, host: &str) -> Result<(), RateLimited> {
+        let limits = &self.config.requests;
+        if let (Some(rate), Some(ip)) = (limits.per_client, client) {
+            take(&self.clients, ip, rate, limits.burst).map_err(|retry_after| RateLimited { scope: "client", retry_after })?;
+        }
+        if let Some(rate) = limits.per_host {
+            take(&self.hosts, host.to_ascii_lowercase(), rate, limits.burst).map_err(|retry_after| RateLimited { scope: "host", retry_after })?;
+        }
+        Ok(())
+    }
+
+    /// 一个客户端连接使用的整形器, 没有配置带宽和延迟时为 None
+    pub fn shaper(&self) -> Option<Shaper> {
+        match self.config.network.scope {
+            BandwidthScope::Global => self.global.clone(),
+            BandwidthScope::Session => Shaper::from_config(&self.config.network),
+        }
+    }
+}
+
+fn take<K: std::hash::Hash + Eq>(buckets: &Mutex<HashMap<K, TokenBucket>>, key: K, rate: f64, burst: Option<f64>) -> Result<(), Duration> {
+    let mut buckets = buckets.lock().unwrap();
+    if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&key) {
+        buckets.retain(|_, bucket| !bucket.is_full());
+    }
+    buckets.entry(key).or_insert_with(|| TokenBucket::new(rate, burst.unwrap_or(rate).max(1.0))).try_take(1.0)
+}
+
+/// 超过请求速率时回复给客户端的响应
+pub fn too_many_requests(limited: &RateLimited) -> Response {
+    let retry_after = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    Response::new(429)
+        .with_header("Retry-After", retry_after.to_string())
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_body(format!("{}\n", limited))
+}
+
+/// 一个连接(或 global 时所有连接)的上下行带宽和延迟
+#[derive(Debug,Clone)]
+pub struct Shaper {
+    upload: Option<Arc<TokenBucket>>,
+    download: Option<Arc<TokenBucket>>,
+    latency: Duration,
+    jitter: Duration,
+}
+
+impl Shaper {
+    /// 显式设置的项优先, 其余取预设值
+    pub fn from_config(config: &NetworkConfig) -> Option<Self> {
+        let preset = config.preset;
+        let upload = config.upload.or(preset.map(|p| p.upload));
+        let download = config.download.or(preset.map(|p| p.download));
+        let latency = config.latency_ms.map(Duration::from_millis).or(preset.map(|p| p.latency)).unwrap_or_default();
+        let jitter = config.jitter_ms.map(Duration::from_millis).or(preset.map(|p| p.jitter)).unwrap_or_default();
+        if upload.is_none() && download.is_none() && latency.is_zero() {
+            return None;
+        }
+        Some(Shaper {
+            upload: upload.map(|rate| Arc::new(TokenBucket::bandwidth(rate))),
+            download: download.map(|rate| Arc::new(TokenBucket::bandwidth(rate))),
+            latency,
+            jitter,
+        })
+    }
+
+    /// 一个方向上的写出节奏, 延迟只加在响应方向
+    pub fn pacer(&self, direction: Direction) -> Pacer {
+        match direction {
+            Direction::Request => Pacer::new(self.upload.clone(), Duration::ZERO, Duration::ZERO),
+            Direction::Response => Pacer::new(self.download.clone(), self.latency, self.jitter),
+        }
+    }
+}
+
+/// 控制一个方向上的数据何时可以写出: 空闲后的第一段数据先等 latency ± jitter,
+/// 之后按令牌桶以报文大小放行
+pub struct Pacer {
+    bucket: Option<Arc<TokenBucket>>,
+    latency: Duration,
+    jitter: Duration,
+    /// 已经放行但还没写出的字节
+    granted: usize,
+    last_active: Option<Instant>,
+    delay: Option<Pin<Box<Sleep>>>,
+    rng: u64,
+}
+
+impl Pacer {
+    fn new(bucket: Option<Arc<TokenBucket>>, latency: Duration, jitter: Duration) -> Self {
+        Pacer { bucket, latency, jitter, granted: 0, last_active: None, delay: None, rng: uuid::Uuid::new_v4().as_u64_pair().0 | 1 }
+    }
+
+    /// 等到可以写出数据, 返回最多可写的字节数(不超过 `want`). 写完后用 [`Pacer::consume`] 扣除
+    pub fn poll_reserve(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        if want == 0 {
+            return Poll::Ready(0);
+        }
+        loop {
+            if let Some(delay) = &mut self.delay {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+            if self.granted > 0 {
+                self.last_active = Some(Instant::now());
+                return Poll::Ready(self.granted.min(want));
+            }
+            let now = Instant::now();
+            if !self.latency.is_zero() && self.last_active.is_none_or(|t| now.saturating_duration_since(t) >= self.latency) {
+                let deadline = now + self.jittered();
+                self.last_active = Some(deadline);
+                self.delay = Some(Box::pin(tokio::time::sleep_until(deadline)));
+                continue;
+            }
+            match &self.bucket {
+                None => self.granted = want,
+                Some(bucket) => match bucket.take_up_to(want) {
+                    Ok(n) => self.granted = n,
+                    Err(wait) => self.delay = Some(Box::pin(sleep(wait))),
+                },
+            }
+        }
+    }
+
+    /// 扣除实际写出的字节
+    pub fn consume(&mut self, n: usize) {
+        self.granted = self.granted.saturating_sub(n);
+    }
+
+    fn jittered(&mut self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.latency;
+        }
+        // xorshift, 不需要密码学强度
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let unit = (self.rng >> 11) as f64 / (1u64 << 53) as f64;
+        let offset = self.jitter.as_secs_f64() * (unit * 2.0 - 1.0);
+        Duration::from_secs_f64((self.latency.as_secs_f64() + offset).max(0.0))
+    }
+}
+
+/// 按 [`Shaper`] 限制客户端连接的读写, 读为请求方向, 写为响应方向
+pub struct Throttled<S> {
+    inner: S,
+    read: Option<Pacer>,
+    write: Option<Pacer>,
+}
+
+impl<S> Throttled<S> {
+    /// `shaper` 为 None 时原样转发
+    pub fn new(inner: S, shaper: Option<&Shaper>) -> Self {
+        Throttled {
+            inner,
+            read: shaper.map(|s| s.pacer(Direction::Request)),
+            write: shaper.map(|s| s.pacer(Direction::Response)),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        let Some(pacer) = &mut me.read else {
+            return Pin::new(&mut me.inner).poll_read(cx, buf);
+        };
+        let n = ready!(pacer.poll_reserve(cx, buf.remaining()));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(n));
+        ready!(Pin::new(&mut me.inner).poll_read(cx, &mut limited))?;
+        let filled = limited.filled().len();
+        buf.advance(filled);
+        pacer.consume(filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        let Some(pacer) = &mut me.write else {
+            return Pin::new(&mut me.inner).poll_write(cx, buf);
+        };
+        let n = ready!(pacer.poll_reserve(cx, buf.len()));
+        let written = ready!(Pin::new(&mut me.inner).poll_write(cx, &buf[..n]))?;
+        pacer.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_and_bandwidth() {
+        let config: ThrottleConfig = serde_yaml::from_str(
+            "requests:\n  per_client: 1\n  per_host: 1\n  burst: 2\nnetwork:\n  preset: 3g\n  download: 100000\n  latency_ms: 50\n  jitter_ms: 0\n",
+        )
+        .unwrap();
+        assert_eq!(config.network.preset, NetworkPreset::named("3g"));
+        assert!(serde_yaml::from_str::<ThrottleConfig>("network:\n  preset: 9g\n").is_err());
+        assert!(config.validate().is_ok());
+        for bad in ["per_client: 0", "per_host: -1", "burst: .nan", "per_client: .inf"] {
+            let config: ThrottleConfig = serde_yaml::from_str(&format!("requests:\n  {}\n", bad)).unwrap();
+            assert!(config.validate().is_err(), "{}", bad);
+        }
+        assert!(ThrottleConfig::default().is_empty());
+
+        let throttle = Throttle::new(config);
+        let ip = Some("10.0.0.1".parse().unwrap());
+        assert!(throttle.check_request(ip, "a.com").is_ok());
+        assert!(throttle.check_request(ip, "a.com").is_ok());
+        let limited = throttle.check_request(ip, "a.com").unwrap_err();
+        assert_eq!(limited.scope, "client");
+        assert!(limited.retry_after > Duration::ZERO && limited.retry_after <= Duration::from_secs(1));
+        assert_eq!(too_many_requests(&limited).headers.get("Retry-After"), Some(&b"1"[..]));
+        let other = Some("10.0.0.2".parse().unwrap());
+        assert_eq!(throttle.check_request(other, "a.com").unwrap_err().scope, "host");
+        assert!(throttle.check_request(other, "b.com").is_ok());
+
+        // 桶里有 10000 字节, 剩余 20000 字节按 100000 字节每秒约 200ms, 加上 50ms 延迟
+        let (a, mut b) = tokio::io::duplex(64 * 1024);
+        let mut shaped = Throttled::new(a, throttle.shaper().as_ref());
+        let started = std::time::Instant::now();
+        shaped.write_all(&[7u8; 30_000]).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(220), "{:?}", started.elapsed());
+        let mut received = vec![0u8; 30_000];
+        b.read_exact(&mut received).await.unwrap();
+        assert!(received.iter().all(|&b| b == 7));
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use serde_json::json;
+
+/// 一个事务各阶段的耗时, 单位毫秒, 口径参照 HAR 的 `timings`.
+/// 没有经历的阶段为 None, 如复用的上游连接没有 DNS/连接/握手
+#[derive(Debug,Clone,Copy,Default,PartialEq,serde::Serialize,serde::Deserialize)]
+#[serde(default)]
+pub struct Timings {
+    /// 读完请求到开始连接上游, 主要是拦截器的处理时间
+    pub blocked: Option<f64>,
+    pub dns: Option<f64>,
+    /// TCP 连接, 不含 TLS 握手
+    pub connect: Option<f64>,
+    pub upstream_tls: Option<f64>,
+    /// 与客户端的 TLS 握手, 只记在该连接的第一个事务上
+    pub client_tls: Option<f64>,
+    /// 向上游发送请求
+    pub send: Option<f64>,
+    /// 请求发完到收到响应头 (TTFB)
+    pub wait: Option<f64>,
+    /// 下载响应体
+    pub receive: Option<f64>,
+    /// 读完请求头到响应写回客户端
+    pub total: Option<f64>,
+}
+
+/// 转为毫秒, 保留微秒精度
+pub fn ms(d: Duration) -> f64 {
+    d.as_micros() as f64 / 1000.0
+}
+
+/// `from` 到 `to` 的毫秒数, 时钟回拨时为 None
+pub fn ms_between(from: SystemTime, to: SystemTime) -> Option<f64> {
+    to.duration_since(from).ok().map(ms)
+}
+
+impl Timings {
+    pub fn is_empty(&self) -> bool {
+        *self == Timings::default()
+    }
+
+    /// HAR 1.2 的 `timings`: `connect` 包含 `ssl`, 不适用的阶段为 -1.
+    /// 客户端握手不在 HAR 定义中, 放在 `_clientSsl`.
+    /// 没有分阶段数据时(如旧记录)整个耗时记为 `wait`
+    pub fn to_har(&self, duration_ms: Option<u64>) -> serde_json::Value {
+        if self.is_empty() {
+            return json!({"send": 0, "wait": duration_ms.unwrap_or_default(), "receive": 0});
+        }
+        let opt = |v: Option<f64>| v.unwrap_or(-1.0);
+        let connect = match (self.connect, self.upstream_tls) {
+            (Some(tcp), tls) => tcp + tls.unwrap_or_default(),
+            (None, _) => -1.0,
+        };
+        json!({
+            "blocked": opt(self.blocked),
+            "dns": opt(self.dns),
+            "connect": connect,
+            "ssl": opt(self.upstream_tls),
+            "send": self.send.unwrap_or_default(),
+            "wait": self.wait.unwrap_or_default(),
+            "receive": self.receive.unwrap_or_default(),
+            "_clientSsl": opt(self.client_tls),
+        })
+    }
+}
+
+/// 日志中的紧凑形式, 只列出有数据的阶段, 如 `dns=0.4ms connect=1.2ms total=12.0ms`
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phases = [
+            ("blocked", self.blocked),
+            ("dns", self.dns),
+            ("connect", self.connect),
+            ("upstream_tls", self.upstream_tls),
+            ("client_tls", self.client_tls),
+            ("send", self.send),
+            ("wait", self.wait),
+            ("receive", self.receive),
+            ("total", self.total),
+        ];
+        let mut first = true;
+        for (name, value) in phases {
+            if let Some(value) = value {
+                write!(f, "{}{}={:.1}ms", if first { "" } else { " " }, name, value)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn har_and_display() {
+        assert_eq!(Timings::default().to_har(Some(12)), json!({"send": 0, "wait": 12, "receive": 0}));
+        let timings = Timings {
+            dns: Some(1.5),
+            connect: Some(2.0),
+            upstream_tls: Some(3.0),
+            send: Some(0.5),
+            wait: Some(10.0),
+            receive: Some(4.0),
+            total: Some(21.0),
+            ..Default::default()
+        };
+        let har = timings.to_har(None);
+        assert_eq!((har["connect"].as_f64(), har["ssl"].as_f64()), (Some(5.0), Some(3.0)));
+        assert_eq!((har["blocked"].as_f64(), har["_clientSsl"].as_f64()), (Some(-1.0), Some(-1.0)));
+        assert_eq!(timings.to_string(), "dns=1.5ms connect=2.0ms upstream_tls=3.0ms send=0.5ms wait=10.0ms receive=4.0ms total=21.0ms");
+        assert_eq!(ms(Duration::from_micros(1500)), 1.5);
+        let json = serde_json::to_string(&timings).unwrap();
+        assert_eq!(serde_json::from_str::<Timings>(&json).unwrap(), timings);
+        assert_eq!(serde_json::from_str::<Timings>("{}").unwrap(), Timings::default());
+    }
+}
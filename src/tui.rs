@@ -0,0 +1,453 @@
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::{execute, terminal};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Text};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::{Frame, Terminal};
+use tokio::sync::broadcast::error::TryRecvError;
+
+use crate::acl::Acl;
+use crate::breakpoint::Breakpoints;
+use crate::capture::{to_har, Flow, FlowLog};
+use crate::filter::Filter;
+use crate::intercept::InterceptContext;
+use crate::prelude::*;
+use crate::replay::{repeat, RequestEdit};
+
+/// 详情中十六进制显示的最大字节数
+const MAX_HEX_BYTES: usize = 4096;
+
+const HELP: &str = "q quit  j/k move  g/G top/follow  tab req/resp  PgUp/PgDn scroll  / filter  r replay  i intercept  c/x release/abort paused  e export";
+
+/// 类似 mitmproxy console 的终端界面, 数据来自 `FlowLog`.
+///
+/// 界面画在 stderr 上, 日志应通过 `LogConfig::file` (`PROXY_LOG_FILE`) 写到文件
+pub struct Tui {
+    flows: FlowLog,
+    breakpoints: Option<Breakpoints>,
+    acl: Option<Arc<Acl>>,
+}
+
+impl Tui {
+    pub fn new(flows: FlowLog) -> Self {
+        Tui { flows, breakpoints: None, acl: None }
+    }
+
+    /// 启用拦截开关, `breakpoints` 需要同时注册到代理上
+    pub fn with_breakpoints(mut self, breakpoints: Breakpoints) -> Self {
+        self.breakpoints = Some(breakpoints);
+        self
+    }
+
+    /// 重放时按代理的 ACL 检查目标
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// 运行直到按下 `q`, 终端在阻塞线程中处理
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        let handle = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            terminal::enable_raw_mode().context("[-] Failed to enable raw mode")?;
+            execute!(io::stderr(), terminal::EnterAlternateScreen)?;
+            let result = Terminal::new(CrosstermBackend::new(io::stderr()))
+                .map_err(anyhow::Error::from)
+                .and_then(|mut term| App::new(self.flows, self.breakpoints, self.acl, Some(handle)).run(&mut term));
+            // 出错时也要恢复终端
+            let _ = execute!(io::stderr(), terminal::LeaveAlternateScreen);
+            let _ = terminal::disable_raw_mode();
+            result
+        })
+        .await?
+    }
+}
+
+enum Mode {
+    Normal,
+    Filter(String),
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Pane {
+    Request,
+    Response,
+}
+
+struct App {
+    log: FlowLog,
+    breakpoints: Option<Breakpoints>,
+    acl: Option<Arc<Acl>>,
+    /// 为空时不能重放
+    runtime: Option<tokio::runtime::Handle>,
+    /// 过滤后的事务
+    view: Vec<Arc<Flow>>,
+    filter: Option<Filter>,
+    selected: usize,
+    /// 新事务到达时自动选中最后一个
+    follow: bool,
+    pane: Pane,
+    scroll: u16,
+    mode: Mode,
+    status: String,
+    status_tx: mpsc::Sender<String>,
+    status_rx: mpsc::Receiver<String>,
+    quit: bool,
+}
+
+impl App {
+    fn new(log: FlowLog, breakpoints: Option<Breakpoints>, acl: Option<Arc<Acl>>, runtime: Option<tokio::runtime::Handle>) -> Self {
+        let (status_tx, status_rx) = mpsc::channel();
+        let mut app = App {
+            log,
+            breakpoints,
+            acl,
+            runtime,
+            view: Vec::new(),
+            filter: None,
+            selected: 0,
+            follow: true,
+            pane: Pane::Response,
+            scroll: 0,
+            mode: Mode::Normal,
+            status: HELP.to_string(),
+            status_tx,
+            status_rx,
+            quit: false,
+        };
+        app.refresh();
+        app
+    }
+
+    fn run<B: Backend>(&mut self, term: &mut Terminal<B>) -> Result<(), anyhow::Error> {
+        let mut rx = self.log.subscribe();
+        while !self.quit {
+            term.draw(|frame| self.draw(frame))?;
+            if event::poll(Duration::from_millis(100))? {
+                if let Event::Key(key) = event::read()? {
+                    self.on_key(key);
+                }
+            }
+            let mut changed = false;
+            loop {
+                match rx.try_recv() {
+                    Ok(_) | Err(TryRecvError::Lagged(_)) => changed = true,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Closed) => {
+                        self.quit = true;
+                        break;
+                    }
+                }
+            }
+            if changed {
+                self.refresh();
+            }
+            while let Ok(status) = self.status_rx.try_recv() {
+                self.status = status;
+            }
+        }
+        Ok(())
+    }
+
+    fn current(&self) -> Option<&Arc<Flow>> {
+        self.view.get(self.selected)
+    }
+
+    /// 按过滤条件重建列表, 尽量保持当前选中的事务
+    fn refresh(&mut self) {
+        let selected_id = self.current().map(|f| f.id);
+        let filter = self.filter.as_ref();
+        self.view = self.log.flows().into_iter().filter(|f| filter.is_none_or(|flt| flt.matches(&f.request, Some(&f.response)))).collect();
+        self.selected = match selected_id.and_then(|id| self.view.iter().position(|f| f.id == id)) {
+            Some(i) if !self.follow => i,
+            _ => self.view.len().saturating_sub(1),
+        };
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index.min(self.view.len().saturating_sub(1));
+        self.follow = self.selected + 1 >= self.view.len();
+        self.scroll = 0;
+    }
+
+    fn on_key(&mut self, key: KeyEvent) {
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        if let Mode::Filter(input) = &mut self.mode {
+            match key.code {
+                KeyCode::Enter => {
+                    let text = input.trim().to_string();
+                    self.mode = Mode::Normal;
+                    self.apply_filter(&text);
+                }
+                KeyCode::Esc => self.mode = Mode::Normal,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return;
+        }
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Down | KeyCode::Char('j') => self.select(self.selected + 1),
+            KeyCode::Up | KeyCode::Char('k') => self.select(self.selected.saturating_sub(1)),
+            KeyCode::Home | KeyCode::Char('g') => self.select(0),
+            KeyCode::End | KeyCode::Char('G') => self.select(usize::MAX),
+            KeyCode::Tab | KeyCode::Enter => {
+                self.pane = if self.pane == Pane::Request { Pane::Response } else { Pane::Request };
+                self.scroll = 0;
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Char('/') => self.mode = Mode::Filter(self.filter.as_ref().map(|f| f.to_string()).unwrap_or_default()),
+            KeyCode::Char('r') => self.replay(),
+            KeyCode::Char('i') => self.toggle_intercept(),
+            KeyCode::Char('c') => self.finish_paused(false),
+            KeyCode::Char('x') => self.finish_paused(true),
+            KeyCode::Char('e') => self.export(),
+            _ => {}
+        }
+    }
+
+    fn apply_filter(&mut self, text: &str) {
+        if text.is_empty() {
+            self.filter = None;
+        } else {
+            match text.parse::<Filter>() {
+                Ok(filter) => self.filter = Some(filter),
+                Err(e) => {
+                    self.status = format!("[-] Bad filter: {}", e);
+                    return;
+                }
+            }
+        }
+        self.follow = true;
+        self.refresh();
+        self.status = format!("{} flows", self.view.len());
+    }
+
+    /// 在后台重放当前事务, 结果作为新的事务出现在列表中
+    fn replay(&mut self) {
+        let (Some(flow), Some(runtime)) = (self.current().cloned(), self.runtime.as_ref()) else {
+            return;
+        };
+        let log = self.log.clone();
+        let acl = self.acl.clone();
+        let status = self.status_tx.clone();
+        self.status = format!("replaying #{}", flow.id);
+        runtime.spawn(async move {
+            let result = repeat(&flow.request, &RequestEdit::default(), Some(flow.response.clone()), acl.as_deref()).await;
+            let message = match &result.response {
+                Some(resp) => {
+                    let ctx = InterceptContext { session_id: flow.session_id, tls: flow.tls, ..Default::default() };
+                    let new = log.record(&ctx, &result.request, resp);
+                    format!("replayed #{} as #{}", flow.id, new.id)
+                }
+                None => format!("[-] Replay failed: {}", result.error.unwrap_or_default()),
+            };
+            let _ = status.send(message);
+        });
+    }
+
+    fn toggle_intercept(&mut self) {
+        let Some(breakpoints) = &self.breakpoints else {
+            self.status = "[-] Interception is not enabled".to_string();
+            return;
+        };
+        let enabled = !breakpoints.intercept_all();
+        breakpoints.set_intercept_all(enabled);
+        self.status = if enabled { "intercepting all requests" } else { "interception off" }.to_string();
+    }
+
+    /// 放行或中止所有暂停的事务
+    fn finish_paused(&mut self, abort: bool) {
+        let Some(breakpoints) = &self.breakpoints else {
+            return;
+        };
+        let paused = breakpoints.paused();
+        for p in &paused {
+            if abort {
+                breakpoints.abort(p.id);
+            } else {
+                breakpoints.release(p.id);
+            }
+        }
+        self.status = format!("{} {} paused flows", if abort { "aborted" } else { "released" }, paused.len());
+    }
+
+    /// 把当前列表导出到工作目录下的 HAR 文件
+    fn export(&mut self) {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let path = format!("flows-{}.har", secs);
+        let har = to_har(self.view.iter().map(|f| f.as_ref()));
+        self.status = match std::fs::write(&path, serde_json::to_vec_pretty(&har).unwrap_or_default()) {
+            Ok(()) => format!("exported {} flows to {}", self.view.len(), path),
+            Err(e) => format!("[-] Failed to export: {}", e),
+        };
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, bottom] = Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+        let [list, detail] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(main);
+
+        let rows = self.view.iter().map(|f| {
+            let style = match f.response.status {
+                500.. => Style::default().fg(Color::Red),
+                400.. => Style::default().fg(Color::Yellow),
+                _ => Style::default(),
+            };
+            Row::new(vec![
+                f.id.to_string(),
+                f.request.method.as_str().to_string(),
+                f.response.status.to_string(),
+                f.request.uri.to_string(),
+                f.response.body.len().to_string(),
+                f.duration_ms.map(|d| format!("{}ms", d)).unwrap_or_default(),
+            ])
+            .style(style)
+        });
+        let widths = [
+            Constraint::Length(5),
+            Constraint::Length(7),
+            Constraint::Length(4),
+            Constraint::Fill(1),
+            Constraint::Length(8),
+            Constraint::Length(7),
+        ];
+        let mut title = format!(" Flows {}/{} ", self.view.len(), self.log.len());
+        if let Some(filter) = &self.filter {
+            title.push_str(&format!("[{}] ", filter));
+        }
+        if self.breakpoints.as_ref().is_some_and(Breakpoints::intercept_all) {
+            let paused = self.breakpoints.as_ref().map_or(0, |b| b.paused().len());
+            title.push_str(&format!("[intercept: {} paused] ", paused));
+        }
+        let table = Table::new(rows, widths)
+            .header(Row::new(vec!["#", "Method", "Code", "URL", "Size", "Time"]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::bordered().title(title))
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = TableState::default().with_selected((!self.view.is_empty()).then_some(self.selected));
+        frame.render_stateful_widget(table, list, &mut state);
+
+        let (title, text) = match (self.current(), self.pane) {
+            (None, _) => (" Detail ".to_string(), Text::raw("")),
+            (Some(flow), Pane::Request) => (format!(" Request #{} [tab] ", flow.id), request_text(&flow.request)),
+            (Some(flow), Pane::Response) => (format!(" Response #{} [tab] ", flow.id), response_text(&flow.response)),
+        };
+        let paragraph = Paragraph::new(text).block(Block::bordered().title(title)).wrap(Wrap { trim: false }).scroll((self.scroll, 0));
+        frame.render_widget(paragraph, detail);
+
+        let line = match &self.mode {
+            Mode::Filter(input) => Line::raw(format!("/{}", input)),
+            Mode::Normal => Line::raw(self.status.as_str()),
+        };
+        frame.render_widget(Paragraph::new(line), bottom);
+    }
+}
+
+fn request_text(req: &Request) -> Text<'static> {
+    let mut lines = vec![Line::styled(
+        format!("{} {} {}", req.method.as_str(), req.uri, req.http_version),
+        Style::default().add_modifier(Modifier::BOLD),
+    )];
+    lines.extend(header_lines(&req.headers));
+    lines.push(Line::raw(""));
+    lines.extend(body_text(&req.body, req.header("content-type")).lines().map(|l| Line::raw(l.to_string())));
+    Text::from(lines)
+}
+
+fn response_text(resp: &Response) -> Text<'static> {
+    let mut lines = vec![Line::styled(
+        format!("{} {} {}", resp.http_version, resp.status, resp.reason),
+        Style::default().add_modifier(Modifier::BOLD),
+    )];
+    if !resp.timings.is_empty() {
+        lines.push(Line::styled(resp.timings.to_string(), Style::default().add_modifier(Modifier::DIM)));
+    }
+    lines.extend(header_lines(&resp.headers));
+    lines.push(Line::raw(""));
+    let body = resp.decoded_body().unwrap_or_else(|_| resp.body.clone());
+    lines.extend(body_text(&body, resp.content_type()).lines().map(|l| Line::raw(l.to_string())));
+    Text::from(lines)
+}
+
+fn header_lines(headers: &HeaderMap) -> impl Iterator<Item = Line<'static>> + '_ {
+    headers.iter().map(|(k, v)| Line::raw(format!("{}: {}", k, String::from_utf8_lossy(v))))
+}
+
+/// 消息体的显示文本: JSON 格式化, 其他文本原样, 二进制显示十六进制
+fn body_text(body: &[u8], content_type: Option<&str>) -> String {
+    if content_type.is_some_and(|t| t.contains("json")) {
+        if let Ok(value) = serde_json::from_slice::<serde_json::Value>(body) {
+            return serde_json::to_string_pretty(&value).unwrap_or_default();
+        }
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => text.to_string(),
+        _ => hexdump(&body[..body.len().min(MAX_HEX_BYTES)]),
+    }
+}
+
+fn hexdump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, row)| {
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = row.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+            format!("{:08x}  {:<47}  {}\n", i * 16, hex.join(" "), ascii)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use ratatui::backend::TestBackend;
+
+    use super::*;
+
+    #[test]
+    fn filter_navigate_and_render() {
+        let log = FlowLog::default();
+        let ctx = InterceptContext::default();
+        let req = Request::from_string("GET http://a.test/ok HTTP/1.1\r\n\r\n").unwrap();
+        log.record(&ctx, &req, &Response::new(200).with_header("Content-Type", "application/octet-stream").with_body(&b"\x00\x01AB"[..]));
+        let req = Request::from_string("POST http://b.test/fail HTTP/1.1\r\n\r\n").unwrap();
+        log.record(&ctx, &req, &Response::new(500).with_header("Content-Type", "application/json").with_body(r#"{"err":1}"#));
+
+        let mut app = App::new(log.clone(), Some(Breakpoints::default()), None, None);
+        assert_eq!(app.current().unwrap().id, 2);
+        let press = |app: &mut App, code| app.on_key(KeyEvent::new(code, KeyModifiers::NONE));
+        press(&mut app, KeyCode::Char('k'));
+        assert_eq!(app.current().unwrap().id, 1);
+        assert!(!app.follow);
+
+        let mut term = Terminal::new(TestBackend::new(120, 20)).unwrap();
+        term.draw(|f| app.draw(f)).unwrap();
+        let screen: String = term.backend().buffer().content().iter().map(|c| c.symbol()).collect();
+        assert!(screen.contains("http://b.test/fail") && screen.contains("00000000  00 01 41 42"));
+
+        press(&mut app, KeyCode::Char('/'));
+        "status >= 500".chars().for_each(|c| press(&mut app, KeyCode::Char(c)));
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.view.len(), 1);
+        term.draw(|f| app.draw(f)).unwrap();
+        let screen: String = term.backend().buffer().content().iter().map(|c| c.symbol()).collect();
+        assert!(screen.contains("\"err\": 1") && !screen.contains("a.test"));
+
+        press(&mut app, KeyCode::Char('i'));
+        assert_eq!(app.breakpoints.as_ref().unwrap().rules().len(), 1);
+        press(&mut app, KeyCode::Char('i'));
+        assert!(app.breakpoints.as_ref().unwrap().rules().is_empty());
+    }
+}
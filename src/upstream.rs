@@ -0,0 +1,158 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use rustls::ClientConfig;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, TcpStream};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tracing::debug;
+
+use crate::metrics::metrics;
+use crate::prelude::*;
+use crate::timing::{ms, Timings};
+
+/// 到源站的连接, 明文或 TLS
+pub enum Upstream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// 单个上游地址的 TCP 连接超时
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 第一次连接上游 TLS 时构建, 之后复用
+static TLS_CONNECTOR: LazyLock<TlsConnector> = LazyLock::new(|| {
+    let mut  root_store = rustls::RootCertStore::from_iter(
+        webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .cloned(),
+    );
+    if let Ok(cert_der) = CertificateDer::from_pem_file("ca.crt") {
+        root_store.add_parsable_certificates(vec![cert_der]);
+    }
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(client_config))
+});
+
+/// 上游 TLS 连接使用的配置: webpki 根证书加上本地 ca.crt
+pub fn tls_connector() -> TlsConnector {
+    TLS_CONNECTOR.clone()
+}
+
+impl Upstream {
+    /// 连接源站, `tls` 为真时完成 TLS 握手
+    pub async fn connect(host: &str, port: u16, tls: bool) -> Result<Self, anyhow::Error> {
+        Self::connect_timed(host, port, tls, &mut Timings::default()).await
+    }
+
+    /// 同 `connect`, 把 DNS 解析, TCP 连接和 TLS 握手的耗时记入 `timings`.
+    /// 失败时已经完成的阶段也会记录
+    pub async fn connect_timed(host: &str, port: u16, tls: bool, timings: &mut Timings) -> Result<Self, anyhow::Error> {
+        let started = Instant::now();
+        let addrs: Vec<SocketAddr> = match lookup_host((host, port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                metrics().upstream_connect_errors.with_label_values(&["Dns"]).inc();
+                return Err(anyhow::Error::new(e).context(format!("[-] Failed to resolve {}", host)));
+            }
+        };
+        timings.dns = Some(ms(started.elapsed()));
+        Self::connect_addrs(host, port, &addrs, tls, timings).await
+    }
+
+    /// 连接已经解析好的地址, 不再解析 `host`; `host` 用于 TLS 的 SNI 和证书校验
+    pub async fn connect_addrs(host: &str, port: u16, addrs: &[SocketAddr], tls: bool, timings: &mut Timings) -> Result<Self, anyhow::Error> {
+        let started = Instant::now();
+        let stream = match connect_any(addrs).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                metrics().upstream_connect_errors.with_label_values(&[&format!("{:?}", e.kind())]).inc();
+                return Err(anyhow::Error::new(e).context(format!("[-] Failed to connect {}:{}", host, port)));
+            }
+        };
+        timings.connect = Some(ms(started.elapsed()));
+        metrics().upstream_connect_duration.observe(started.elapsed().as_secs_f64());
+        debug!(host, port, elapsed_ms = started.elapsed().as_millis() as u64, "[+] Upstream TCP connected");
+        if !tls {
+            return Ok(Upstream::Plain(stream));
+        }
+        let server_name = ServerName::try_from(host.to_string()).context("Invalid server name")?;
+        let started = Instant::now();
+        let tls_stream = match tls_connector().connect(server_name, stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                metrics().tls_failed("upstream", &e);
+                return Err(anyhow::Error::new(e).context(format!("[-] TLS handshake with {}:{} failed", host, port)));
+            }
+        };
+        timings.upstream_tls = Some(ms(started.elapsed()));
+        metrics().tls_handshake_duration.with_label_values(&["upstream"]).observe(started.elapsed().as_secs_f64());
+        debug!(host, elapsed_ms = started.elapsed().as_millis() as u64, "[+] Upstream TLS handshake completed");
+        Ok(Upstream::Tls(Box::new(tls_stream)))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Upstream::Tls(_))
+    }
+
+    /// TLS 连接协商的参数, `host` 是连接时使用的名字, IP 地址不发送 SNI
+    pub fn tls_info(&self, host: &str) -> Option<TlsInfo> {
+        let Upstream::Tls(stream) = self else {
+            return None;
+        };
+        let sni = matches!(ServerName::try_from(host), Ok(ServerName::DnsName(_))).then_some(host);
+        Some(TlsInfo::new(stream.get_ref().1, sni))
+    }
+}
+
+/// 依次尝试解析出的地址, 每个最多等待 `CONNECT_TIMEOUT`, 全部失败时返回最后一个错误
+async fn connect_any(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no addresses resolved");
+    for addr in addrs {
+        match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last = e,
+            Err(_) => last = io::Error::new(io::ErrorKind::TimedOut, format!("connect to {} timed out", addr)),
+        }
+    }
+    Err(last)
+}
+
+impl AsyncRead for Upstream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Upstream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Upstream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Upstream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Upstream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Upstream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Upstream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt;
+
+use anyhow::anyhow;
+
+/// 请求目标的形式 (RFC 9112 §3.2)
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default,serde::Serialize)]
+pub enum TargetForm {
+    /// `/path?query`
+    #[default] Origin,
+    /// `http://host:port/path?query`, 发往代理的普通HTTP请求
+    Absolute,
+    /// `host:port`, 仅用于CONNECT
+    Authority,
+    /// `*`, 仅用于 OPTIONS
+    Asterisk,
+}
+
+/// URI中的 `host[:port]` 部分, IPv6 字面量保存时不带方括号
+#[derive(Debug,Clone,PartialEq,Eq,Default,serde::Serialize)]
+pub struct Authority {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl Authority {
+    /// 解析 `host`, `host:port`, `[v6]`, `[v6]:port`
+    pub fn parse(raw: &str) -> Result<Self, anyhow::Error> {
+        // 去掉可能存在的 userinfo
+        let raw = raw.rsplit_once('@').map(|(_, hp)| hp).unwrap_or(raw);
+        if raw.is_empty() {
+            return Err(anyhow!("empty authority"));
+        }
+        let (host, port) = if let Some(rest) = raw.strip_prefix('[') {
+            let end = rest.find(']').ok_or_else(|| anyhow!("unterminated IPv6 literal: {}", raw))?;
+            let host = &rest[..end];
+            let port = match &rest[end + 1..] {
+                "" => None,
+                tail => Some(tail.strip_prefix(':').ok_or_else(|| anyhow!("bad authority: {}", raw))?),
+            };
+            (host, port)
+        } else {
+            match raw.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (raw, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(anyhow!("empty host in authority: {}", raw));
+        }
+        let port = match port {
+            // `host:` 视为未指定端口
+            Some("") | None => None,
+            Some(p) => Some(p.parse::<u16>().map_err(|_| anyhow!("bad port in authority: {}", raw))?),
+        };
+        Ok(Authority { host: host.to_string(), port })
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.host.contains(':')
+    }
+
+    /// 用于 `Host` 头和日志的形式, IPv6 会重新加上方括号
+    pub fn host_header(&self) -> String {
+        let host = if self.is_ipv6() { format!("[{}]", self.host) } else { self.host.clone() };
+        match self.port {
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        }
+    }
+}
+
+impl fmt::Display for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.host_header())
+    }
+}
+
+/// 解析后的请求目标
+#[derive(Debug,Clone,PartialEq,Eq,Default,serde::Serialize)]
+pub struct Uri {
+    pub form: TargetForm,
+    pub scheme: Option<String>,
+    pub authority: Option<Authority>,
+    pub path: String,
+    pub query: Option<String>,
+}
+
+impl Uri {
+    /// 按请求行中的 request-target 解析
+    pub fn parse(target: &str) -> Result<Self, anyhow::Error> {
+        if target == "*" {
+            return Ok(Uri { form: TargetForm::Asterisk, path: "*".to_string(), ..Default::default() });
+        }
+        if target.starts_with('/') {
+            let (path, query) = split_query(target);
+            return Ok(Uri { form: TargetForm::Origin, path, query, ..Default::default() });
+        }
+        if let Some((scheme, rest)) = target.split_once("://") {
+            if scheme.is_empty() || !scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+                return Err(anyhow!("bad scheme in request target: {}", target));
+            }
+            let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+            let authority = Authority::parse(&rest[..end])?;
+            // fragment 不会发往服务器
+            let rest = rest[end..].split('#').next().unwrap_or("");
+            let (path, query) = split_query(rest);
+            let path = if path.is_empty() { "/".to_string() } else { path };
+            return Ok(Uri {
+                form: TargetForm::Absolute,
+                scheme: Some(scheme.to_ascii_lowercase()),
+                authority: Some(authority),
+                path,
+                query,
+            });
+        }
+        let authority = Authority::parse(target)?;
+        Ok(Uri { form: TargetForm::Authority, authority: Some(authority), ..Default::default() })
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.authority.as_ref().map(|a| a.host.as_str())
+    }
+
+    /// 显式端口, 没有则按 scheme 取默认端口
+    pub fn port_or_default(&self) -> Option<u16> {
+        self.authority.as_ref().and_then(|a| a.port).or_else(|| default_port(self.scheme.as_deref()?))
+    }
+
+    /// 转发给源站时使用的 origin-form: `/path?query`
+    pub fn origin_form(&self) -> String {
+        if self.form == TargetForm::Asterisk {
+            return "*".to_string();
+        }
+        let path = if self.path.is_empty() { "/" } else { self.path.as_str() };
+        match &self.query {
+            Some(q) => format!("{}?{}", path, q),
+            None => path.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.form {
+            TargetForm::Asterisk => f.write_str("*"),
+            TargetForm::Authority => write!(f, "{}", self.authority.clone().unwrap_or_default()),
+            TargetForm::Origin => f.write_str(&self.origin_form()),
+            TargetForm::Absolute => write!(
+                f,
+                "{}://{}{}",
+                self.scheme.as_deref().unwrap_or("http"),
+                self.authority.clone().unwrap_or_default(),
+                self.origin_form()
+            ),
+        }
+    }
+}
+
+/// scheme 对应的默认端口
+pub fn default_port(scheme: &str) -> Option<u16> {
+    match scheme.to_ascii_lowercase().as_str() {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        _ => None,
+    }
+}
+
+/// 通配符匹配, 不区分大小写: `*` 匹配任意长度, `?` 匹配单个字符
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.to_lowercase().chars().collect();
+    let t: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// 模式以 `*` 结尾时, 返回最后这个 `*` 匹配的部分; 不匹配或没有结尾 `*` 时返回None.
+/// 用于把 URL 前缀映射到另一个位置
+pub fn wildcard_tail<'a>(pattern: &str, text: &'a str) -> Option<&'a str> {
+    let base = pattern.strip_suffix('*')?;
+    if !wildcard_match(pattern, text) {
+        return None;
+    }
+    let split = (0..=text.len()).filter(|&i| text.is_char_boundary(i)).find(|&i| wildcard_match(base, &text[..i]))?;
+    Some(&text[split..])
+}
+
+fn split_query(s: &str) -> (String, Option<String>) {
+    match s.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (s.to_string(), None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_absolute_form() {
+        let uri = Uri::parse("http://example.com:8080/a/b?x=1#frag").unwrap();
+        assert_eq!(uri.form, TargetForm::Absolute);
+        assert_eq!(uri.scheme.as_deref(), Some("http"));
+        assert_eq!(uri.host(), Some("example.com"));
+        assert_eq!(uri.port_or_default(), Some(8080));
+        assert_eq!(uri.origin_form(), "/a/b?x=1");
+
+        let uri = Uri::parse("http://example.com").unwrap();
+        assert_eq!(uri.port_or_default(), Some(80));
+        assert_eq!(uri.origin_form(), "/");
+    }
+
+    #[test]
+    fn parse_ipv6_literals() {
+        let uri = Uri::parse("http://[::1]:8080/index.html").unwrap();
+        assert_eq!(uri.host(), Some("::1"));
+        assert_eq!(uri.port_or_default(), Some(8080));
+        assert_eq!(uri.authority.unwrap().host_header(), "[::1]:8080");
+
+        let uri = Uri::parse("[2001:db8::1]:443").unwrap();
+        assert_eq!(uri.form, TargetForm::Authority);
+        assert_eq!(uri.host(), Some("2001:db8::1"));
+        assert_eq!(uri.port_or_default(), Some(443));
+    }
+
+    #[test]
+    fn parse_origin_and_asterisk() {
+        let uri = Uri::parse("/search?q=rust").unwrap();
+        assert_eq!(uri.form, TargetForm::Origin);
+        assert_eq!(uri.authority, None);
+        assert_eq!(uri.query.as_deref(), Some("q=rust"));
+        assert_eq!(Uri::parse("*").unwrap().origin_form(), "*");
+        assert!(Uri::parse("http://:80/").is_err());
+        assert!(Uri::parse("example.com:http").is_err());
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*.example.com", "API.example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(wildcard_match("api-?.test", "api-1.test"));
+        assert!(wildcard_match("https://*/v1/*", "https://a.b/v1/users/1"));
+        assert_eq!(wildcard_tail("https://*/v1/*", "https://a.b/v1/users/1"), Some("users/1"));
+        assert_eq!(wildcard_tail("https://a.b/v1", "https://a.b/v1"), None);
+    }
+}
@@ -0,0 +1,153 @@
+use std::future::Future;
+
+use anyhow::Context;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use crate::http1::{fix_framing, Conn};
+use crate::prelude::*;
+
+/// 管理端口上请求体的上限
+const MAX_ADMIN_BODY: usize = 1024 * 1024;
+
+/// 管理端口处理函数的返回值
+pub enum Reply {
+    /// 完整的响应
+    Full(Response),
+    /// 先写出响应头, 之后逐块写出收到的数据直到发送端关闭, 用于 SSE
+    Stream(Response, mpsc::Receiver<Bytes>),
+}
+
+impl From<Response> for Reply {
+    fn from(resp: Response) -> Self {
+        Reply::Full(resp)
+    }
+}
+
+/// 在 `listener` 上运行简单的HTTP/1.1服务, 每个连接一个任务, 支持 keep-alive
+pub async fn serve<H, F>(listener: TcpListener, handler: H) -> Result<(), anyhow::Error>
+where
+    H: Fn(Request) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Reply> + Send + 'static,
+{
+    loop {
+        let (stream, _) = listener.accept().await.context("[-] Failed to accept admin connection")?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let mut conn = Conn::new(stream);
+            loop {
+                let req = match conn.read_request(MAX_ADMIN_BODY).await {
+                    Ok(Some((req, None))) => req,
+                    Ok(Some((_, Some(_)))) => {
+                        let _ = write_reply(&mut conn.stream, Response::new(413), true).await;
+                        break;
+                    }
+                    Ok(None) => break,
+                    Err(_) => {
+                        let _ = write_reply(&mut conn.stream, Response::new(400), true).await;
+                        break;
+                    }
+                };
+                let close = req.wants_close();
+                match handler(req).await {
+                    Reply::Full(resp) => {
+                        if write_reply(&mut conn.stream, resp, close).await.is_err() || close {
+                            break;
+                        }
+                    }
+                    Reply::Stream(resp, mut rx) => {
+                        let mut head = resp.to_head();
+                        head.truncate(head.len() - 2);
+                        head.extend_from_slice(b"Connection: close\r\n\r\n");
+                        if conn.stream.write_all(&head).await.is_ok() {
+                            while let Some(chunk) = rx.recv().await {
+                                if conn.stream.write_all(&chunk).await.is_err() || conn.stream.flush().await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn write_reply<W: AsyncWrite + Unpin>(out: &mut W, mut resp: Response, close: bool) -> std::io::Result<()> {
+    fix_framing(&mut resp.headers, resp.body.len());
+    let mut data = resp.to_head();
+    if close {
+        data.truncate(data.len() - 2);
+        data.extend_from_slice(b"Connection: close\r\n\r\n");
+    }
+    data.extend_from_slice(&resp.body);
+    out.write_all(&data).await?;
+    out.flush().await
+}
+
+/// JSON 响应
+pub fn json_response(status: u16, value: &impl serde::Serialize) -> Response {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    Response::new(status).with_header("Content-Type", "application/json").with_body(body)
+}
+
+/// `{"error": ...}` 形式的错误响应
+pub fn error_response(status: u16, message: impl std::fmt::Display) -> Response {
+    json_response(status, &serde_json::json!({ "error": message.to_string() }))
+}
+
+/// 逐字节比较全部内容, 不因提前返回泄露匹配长度
+pub fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 请求是否带有 `Authorization: Bearer <token>`
+pub fn bearer_authorized(req: &Request, token: &str) -> bool {
+    req.header("authorization").and_then(|v| v.strip_prefix("Bearer ")).is_some_and(|t| constant_eq(t.as_bytes(), token.as_bytes()))
+}
+
+/// 查询串中的参数, 已做百分号解码
+pub fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.uri.query.as_deref()?.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (percent_decode(k) == name).then(|| percent_decode(v))
+    })
+}
+
+/// 解码 `%XX` 和表示空格的 `+`, 非法序列原样保留
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match bytes.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok()) {
+                Some(b) => {
+                    out.push(b);
+                    i += 2;
+                }
+                None => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn query_params() {
+        let req = Request::from_string("GET /api/flows?filter=status+%3E%3D+500&limit=10&bad=%zz%4 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(query_param(&req, "filter").as_deref(), Some("status >= 500"));
+        assert_eq!(query_param(&req, "limit").as_deref(), Some("10"));
+        assert_eq!(query_param(&req, "bad").as_deref(), Some("%zz%4"));
+        assert_eq!(query_param(&req, "none"), None);
+    }
+}